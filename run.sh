@@ -4,7 +4,7 @@ docker build -t jonathanbeber/rust-grpc-example:v0.1.0 .
 
 docker network create rust-grpc-example
 docker run --name server --rm -d --network=rust-grpc-example jonathanbeber/rust-grpc-example:v0.1.0
-docker run --name client --rm -it --network=rust-grpc-example jonathanbeber/rust-grpc-example:v0.1.0 bash
+docker run --name client --rm -it --network=rust-grpc-example -e STOCK_TARGET=server:9090 jonathanbeber/rust-grpc-example:v0.1.0 bash
 
 docker kill server
 docker network rm rust-grpc-example
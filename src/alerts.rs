@@ -0,0 +1,463 @@
+use std::io::Write;
+use std::{collections, fmt, fs, io, path, sync, thread, time};
+
+use serde::Serialize;
+use serde_json::json;
+use slog::Logger;
+
+use super::{output, proto, Product, Stock, Threshold};
+
+const WEBHOOK_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// `AlertKind` tells how low the stock of a product is. Kinds are ordered
+/// from the least to the most severe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    /// The quantity is below the threshold.
+    Low,
+    /// The product ran out.
+    OutOfStock,
+}
+
+impl fmt::Display for AlertKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AlertKind::Low => write!(f, "low"),
+            AlertKind::OutOfStock => write!(f, "out of stock"),
+        }
+    }
+}
+
+/// `Alert` is raised by the [`Evaluator`](struct.Evaluator.html) when the
+/// quantity of a product drops below its threshold or reaches zero.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alert {
+    /// Time the alert was raised.
+    pub time: time::SystemTime,
+    /// How low the stock is.
+    pub kind: AlertKind,
+    /// Store of the product.
+    pub store: proto::Store,
+    /// Product running low.
+    pub product: Product,
+    /// Quantity left.
+    pub quantity: u32,
+    /// Threshold of the product in the store.
+    pub threshold: u32,
+}
+
+impl Alert {
+    fn new(threshold: &Threshold, kind: AlertKind) -> Self {
+        Self {
+            time: time::SystemTime::now(),
+            kind,
+            store: threshold.store,
+            product: threshold.product.clone(),
+            quantity: threshold.quantity,
+            threshold: threshold.threshold,
+        }
+    }
+
+    /// Returns the JSON document sent by the file and webhook notifiers.
+    pub fn to_json(&self) -> serde_json::Value {
+        let millis = self
+            .time
+            .duration_since(time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as i64)
+            .unwrap_or_default();
+        json!({
+            "time": output::format_time(millis),
+            "kind": self.kind,
+            "store": format!("{:?}", self.store),
+            "name": self.product.name(),
+            "brand": self.product.brand(),
+            "category": self.product.category(),
+            "quantity": self.quantity,
+            "threshold": self.threshold,
+        })
+    }
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "'{}' is {} on {:?}: {} left, threshold {}",
+            self.product.name(),
+            self.kind,
+            self.store,
+            self.quantity,
+            self.threshold
+        )
+    }
+}
+
+/// `Notifier` delivers alerts to whoever restocks the stores.
+pub trait Notifier: Send + Sync {
+    /// Delivers `alert`, returning why it could not.
+    fn notify(&self, alert: &Alert) -> Result<(), String>;
+}
+
+/// `LogNotifier` logs alerts as warnings.
+pub struct LogNotifier {
+    log: Logger,
+}
+
+impl LogNotifier {
+    /// Returns a new `LogNotifier` logging to `log`.
+    pub fn new(log: Logger) -> Self {
+        Self { log }
+    }
+}
+
+impl Notifier for LogNotifier {
+    fn notify(&self, alert: &Alert) -> Result<(), String> {
+        warn!(
+            self.log,
+            "{}", alert;
+            "store" => format!("{:?}", alert.store),
+            "kind" => format!("{:?}", alert.kind)
+        );
+        Ok(())
+    }
+}
+
+/// `FileNotifier` appends alerts as JSON lines to a file.
+pub struct FileNotifier {
+    file: sync::Mutex<fs::File>,
+}
+
+impl FileNotifier {
+    /// Returns a new `FileNotifier` appending to the file on `path`. The
+    /// file is created if it does not exist.
+    pub fn new<P: AsRef<path::Path>>(path: P) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self {
+            file: sync::Mutex::new(file),
+        })
+    }
+}
+
+impl Notifier for FileNotifier {
+    fn notify(&self, alert: &Alert) -> Result<(), String> {
+        let mut file = self
+            .file
+            .lock()
+            .unwrap_or_else(sync::PoisonError::into_inner);
+        writeln!(file, "{}", alert.to_json()).map_err(|e| e.to_string())
+    }
+}
+
+/// `WebhookNotifier` posts alerts as JSON to an HTTP endpoint, e.g.
+/// `http://hooks.example.com/stock`. Responses other than 2xx are
+/// failures. HTTPS is not supported.
+pub struct WebhookNotifier {
+    url: String,
+}
+
+impl WebhookNotifier {
+    /// Returns a new `WebhookNotifier` posting to `url`.
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: String::from(url),
+        }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, alert: &Alert) -> Result<(), String> {
+        let response = ureq::post(&self.url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .set("Content-Type", "application/json")
+            .send_string(&alert.to_json().to_string());
+        if let Some(err) = response.synthetic_error() {
+            return Err(format!("could not post to {}: {}", self.url, err));
+        }
+        if !response.ok() {
+            return Err(format!("{} responded {}", self.url, response.status_line()));
+        }
+        Ok(())
+    }
+}
+
+/// `Evaluator` compares the quantities of the products with their
+/// [thresholds](../trait.Stock.html#method.thresholds) and hands the
+/// alerts raised to its notifiers.
+///
+/// Alerts are raised once, when a product gets lower than it was in the
+/// previous evaluation: from above its threshold to low or out of stock,
+/// or from low to out of stock. A product is alerted again only after it
+/// was restocked up to its threshold.
+pub struct Evaluator<S> {
+    stock: S,
+    notifiers: Vec<Box<dyn Notifier>>,
+    kinds: collections::HashMap<(proto::Store, Product), AlertKind>,
+    log: Logger,
+}
+
+impl<S: Stock> Evaluator<S> {
+    /// Returns a new `Evaluator` of the thresholds of `stock`, without
+    /// notifiers. Notification failures are logged to `log`.
+    pub fn new(stock: S, log: Logger) -> Self {
+        Self {
+            stock,
+            notifiers: vec![],
+            kinds: collections::HashMap::new(),
+            log,
+        }
+    }
+
+    /// Adds a notifier the alerts are handed to.
+    pub fn with_notifier<N: Notifier + 'static>(mut self, notifier: N) -> Self {
+        self.notifiers.push(Box::new(notifier));
+        self
+    }
+
+    /// Evaluates the thresholds, notifies the alerts raised since the
+    /// previous evaluation and returns them.
+    pub fn evaluate(&mut self) -> Vec<Alert> {
+        let mut kinds = collections::HashMap::new();
+        let mut alerts = vec![];
+        for threshold in self.stock.thresholds() {
+            let kind = match threshold.quantity {
+                0 => AlertKind::OutOfStock,
+                quantity if quantity < threshold.threshold => AlertKind::Low,
+                _ => continue,
+            };
+            let key = (threshold.store, threshold.product.clone());
+            // Products that were not low before compare as `None`, lower
+            // than any kind.
+            if self.kinds.get(&key) < Some(&kind) {
+                alerts.push(Alert::new(&threshold, kind));
+            }
+            kinds.insert(key, kind);
+        }
+        self.kinds = kinds;
+
+        for alert in &alerts {
+            for notifier in &self.notifiers {
+                if let Err(e) = notifier.notify(alert) {
+                    error!(
+                        self.log,
+                        "Could not notify alert: {}", e;
+                        "store" => format!("{:?}", alert.store),
+                        "product" => alert.product.name()
+                    );
+                }
+            }
+        }
+        alerts
+    }
+}
+
+impl<S: Stock + Send + 'static> Evaluator<S> {
+    /// Evaluates the thresholds every `interval` on a background thread.
+    pub fn spawn(mut self, interval: time::Duration) -> thread::JoinHandle<()> {
+        thread::spawn(move || loop {
+            self.evaluate();
+            thread::sleep(interval);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, Read};
+    use std::{net, sync::mpsc};
+
+    use super::*;
+    use crate::{Context, StockImpl};
+
+    struct CollectNotifier {
+        alerts: sync::Arc<sync::Mutex<Vec<Alert>>>,
+    }
+
+    impl Notifier for CollectNotifier {
+        fn notify(&self, alert: &Alert) -> Result<(), String> {
+            self.alerts.lock().unwrap().push(alert.clone());
+            Ok(())
+        }
+    }
+
+    fn ctx() -> Context {
+        Context::new("test", crate::trace::Tracer::disabled().start("test", None))
+    }
+
+    fn laptop() -> Product {
+        Product::new("Laptop air", "AbCorp", "computers/laptops")
+    }
+
+    fn alert() -> Alert {
+        Alert {
+            time: time::UNIX_EPOCH + time::Duration::from_millis(1_577_836_800_000),
+            kind: AlertKind::Low,
+            store: proto::Store::BERLIN_DE,
+            product: laptop(),
+            quantity: 2,
+            threshold: 5,
+        }
+    }
+
+    // Serves a single HTTP request answering with `status`, and sends the
+    // request line and body through the returned channel.
+    fn stub_server(status: &'static str) -> (String, mpsc::Receiver<(String, String)>) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                let header = header.to_lowercase();
+                if let Some(value) = header.strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+            let _ = sender.send((
+                String::from(request_line.trim()),
+                String::from_utf8(body).unwrap(),
+            ));
+        });
+        (url, receiver)
+    }
+
+    #[test]
+    fn test_evaluate_alerts_once() {
+        let mut stock = StockImpl::new(crate::tests::log::new());
+        stock.register(laptop(), proto::Store::BERLIN_DE, 8);
+        stock.register(laptop(), proto::Store::VENEZA_IT, 1);
+        stock.set_threshold(proto::Store::BERLIN_DE, laptop(), Some(5));
+        stock.set_threshold(proto::Store::VENEZA_IT, laptop(), Some(2));
+        let alerts = sync::Arc::new(sync::Mutex::new(vec![]));
+        let mut evaluator = Evaluator::new(stock.clone(), crate::tests::log::new()).with_notifier(
+            CollectNotifier {
+                alerts: alerts.clone(),
+            },
+        );
+
+        let raised = evaluator.evaluate();
+        assert_eq!(raised.len(), 1);
+        assert_eq!(raised[0].store, proto::Store::VENEZA_IT);
+        assert_eq!(raised[0].kind, AlertKind::Low);
+        assert_eq!(raised[0].quantity, 1);
+        assert_eq!(raised[0].threshold, 2);
+        assert!(evaluator.evaluate().is_empty());
+
+        stock.set(&ctx(), proto::Store::BERLIN_DE, laptop(), 4);
+        stock.set(&ctx(), proto::Store::VENEZA_IT, laptop(), 0);
+        let raised = evaluator.evaluate();
+        let kinds: Vec<(proto::Store, AlertKind)> = raised
+            .iter()
+            .map(|alert| (alert.store, alert.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (proto::Store::BERLIN_DE, AlertKind::Low),
+                (proto::Store::VENEZA_IT, AlertKind::OutOfStock),
+            ]
+        );
+
+        // Partial restocks are not alerted, full ones allow new alerts.
+        stock.set(&ctx(), proto::Store::VENEZA_IT, laptop(), 1);
+        stock.set(&ctx(), proto::Store::BERLIN_DE, laptop(), 5);
+        assert!(evaluator.evaluate().is_empty());
+        stock.remove(&ctx(), proto::Store::BERLIN_DE, &laptop());
+        let raised = evaluator.evaluate();
+        assert_eq!(raised.len(), 1);
+        assert_eq!(raised[0].store, proto::Store::BERLIN_DE);
+        assert_eq!(raised[0].kind, AlertKind::OutOfStock);
+
+        // Cleared thresholds are no longer evaluated.
+        stock.set_threshold(proto::Store::BERLIN_DE, laptop(), None);
+        stock.set(&ctx(), proto::Store::BERLIN_DE, laptop(), 3);
+        stock.set(&ctx(), proto::Store::BERLIN_DE, laptop(), 0);
+        assert!(evaluator.evaluate().is_empty());
+
+        assert_eq!(alerts.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_alert_to_json() {
+        assert_eq!(
+            alert().to_json(),
+            json!({
+                "time": "2020-01-01T00:00:00.000Z",
+                "kind": "low",
+                "store": "BERLIN_DE",
+                "name": "Laptop air",
+                "brand": "AbCorp",
+                "category": "computers/laptops",
+                "quantity": 2,
+                "threshold": 5,
+            })
+        );
+        assert_eq!(
+            alert().to_string(),
+            "'Laptop air' is low on BERLIN_DE: 2 left, threshold 5"
+        );
+    }
+
+    #[test]
+    fn test_file_notifier() {
+        let path = std::env::temp_dir().join(format!("stock-alerts-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let notifier = FileNotifier::new(&path).unwrap();
+
+        notifier.notify(&alert()).unwrap();
+        notifier.notify(&alert()).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines, vec![alert().to_json(), alert().to_json()]);
+    }
+
+    #[test]
+    fn test_webhook_notifier() {
+        let (url, requests) = stub_server("204 No Content");
+
+        WebhookNotifier::new(&url).notify(&alert()).unwrap();
+
+        let (request_line, body) = requests.recv().unwrap();
+        assert_eq!(request_line, "POST /alerts HTTP/1.1");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            alert().to_json()
+        );
+    }
+
+    #[test]
+    fn test_webhook_notifier_failure() {
+        let (url, _requests) = stub_server("500 Internal Server Error");
+
+        let err = WebhookNotifier::new(&url).notify(&alert()).unwrap_err();
+        assert_eq!(
+            err,
+            format!("{} responded HTTP/1.1 500 Internal Server Error", url)
+        );
+    }
+}
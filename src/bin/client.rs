@@ -1,12 +1,13 @@
-use std::{str, sync};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::{cmp, str, time};
 
-#[macro_use]
-extern crate prettytable;
-use prettytable::{format, Table};
-use structopt::StructOpt;
+use futures::Future;
+use structopt::{clap, StructOpt};
 
 use stock::grpc::client;
-use stock::proto;
+use stock::{client_config, completions, config, import, output, proto, reconcile, shell, trace};
 
 /// Opt is the struct responsible for holding the server initialization options.
 #[derive(Debug, StructOpt)]
@@ -20,17 +21,99 @@ struct Opt {
     #[structopt(subcommand)]
     cmd: Command,
 
-    /// Sets the stock server address.
-    #[structopt(long, default_value = "127.0.0.1", global(true))]
-    host: String,
+    /// Reads the contexts from the given TOML file [default: ~/.config/stock/config.toml].
+    #[structopt(
+        long,
+        env = client_config::CONFIG_ENV,
+        parse(from_os_str),
+        global(true)
+    )]
+    config: Option<PathBuf>,
 
-    /// Sets the stock server port.
-    #[structopt(long, default_value = "9090", global(true))]
-    port: u16,
+    /// Uses the given context of the configuration file instead of the current one.
+    #[structopt(long, env = "STOCK_CONTEXT", global(true))]
+    context: Option<String>,
 
-    /// Defines the store where actions will be performed. Currently VENEZA_IT or BERLIN_DE.
-    #[structopt(long, short, default_value = "BERLIN_DE", global(true))]
-    store: proto::Store,
+    /// Sets the stock server target: HOST:PORT, dns:///HOST:PORT or unix:PATH
+    /// [default: 127.0.0.1:9090].
+    #[structopt(long, short, parse(try_from_str = client::parse_target), global(true))]
+    target: Option<String>,
+
+    /// Defines the store where actions will be performed. Currently VENEZA_IT or BERLIN_DE
+    /// [default: BERLIN_DE].
+    #[structopt(long, short, global(true))]
+    store: Option<proto::Store>,
+
+    /// Sets the deadline of each call attempt, in seconds.
+    #[structopt(
+        long,
+        default_value = "10",
+        parse(try_from_str = client::parse_seconds),
+        global(true)
+    )]
+    timeout: time::Duration,
+
+    /// Sets the seconds to wait for a connection to the server before failing the call as
+    /// unavailable.
+    #[structopt(
+        long,
+        default_value = "5",
+        parse(try_from_str = client::parse_seconds),
+        global(true)
+    )]
+    connect_timeout: time::Duration,
+
+    /// Sets how many times calls failing with UNAVAILABLE or RESOURCE_EXHAUSTED are retried,
+    /// with exponential backoff.
+    #[structopt(long, default_value = "3", global(true))]
+    retries: u32,
+
+    /// Sets the output format: table, json, yaml, csv or tsv [default: table].
+    #[structopt(long, short, global(true))]
+    output: Option<output::Format>,
+
+    /// Omits the title and column headers of the table output and the header row of the csv
+    /// and tsv outputs.
+    #[structopt(long, global(true))]
+    no_headers: bool,
+
+    /// Selects and orders the output columns, e.g. name,quantity,status. Available columns are
+    /// name, brand, category, status, quantity and version.
+    #[structopt(long, use_delimiter = true, global(true))]
+    columns: Vec<output::Column>,
+
+    /// Renders every item with a template instead of the output format, e.g.
+    /// '{{name}}\t{{quantity}}'.
+    #[structopt(long, global(true))]
+    template: Option<output::Template>,
+
+    /// Prints the request trace and request IDs to stderr.
+    #[structopt(long, short, global(true))]
+    verbose: bool,
+}
+
+/// Exit code used by `diff --exit-code` when the store differs from the file.
+const EXIT_DIFFERENT: i32 = 1;
+/// Exit code for failures not covered by the other codes.
+const EXIT_ERROR: i32 = 2;
+/// Exit code used when the server could not be reached.
+const EXIT_UNAVAILABLE: i32 = 3;
+/// Exit code used when the call deadline was exceeded.
+const EXIT_DEADLINE_EXCEEDED: i32 = 4;
+/// Exit code used when the server rejected the call as overloaded.
+const EXIT_RESOURCE_EXHAUSTED: i32 = 5;
+/// Exit code used when a change conflicted with another one.
+const EXIT_CONFLICT: i32 = 6;
+
+/// Returns the exit code matching the failure class of `err`.
+fn exit_code(err: &client::Error) -> i32 {
+    match err {
+        client::Error::Unavailable(_) => EXIT_UNAVAILABLE,
+        client::Error::DeadlineExceeded => EXIT_DEADLINE_EXCEEDED,
+        client::Error::ResourceExhausted(_) => EXIT_RESOURCE_EXHAUSTED,
+        client::Error::Conflict(_) => EXIT_CONFLICT,
+        _ => EXIT_ERROR,
+    }
 }
 
 #[derive(Debug, StructOpt)]
@@ -40,47 +123,1131 @@ enum Command {
         /// Displays unavailable items too.
         #[structopt(long)]
         show_unavailable: bool,
+        /// Lists the products the store had at the given RFC 3339 date or duration ago, e.g.
+        /// 2026-09-01T12:00Z or 2d.
+        #[structopt(long, parse(try_from_str = client::parse_time))]
+        as_of: Option<time::SystemTime>,
+    },
+    /// Sets the quantity of a product of the store, adding the product if it's not there yet.
+    Set {
+        /// Product name.
+        name: String,
+        /// Quantity to set.
+        #[structopt(parse(try_from_str = client::parse_quantity))]
+        quantity: u32,
+        /// Product brand.
+        #[structopt(long, default_value = "")]
+        brand: String,
+        /// Product category.
+        #[structopt(long, default_value = "")]
+        category: String,
+        /// Only sets the quantity if the product is still at the given version, as listed with
+        /// --columns name,quantity,version. Exits with 6 if it changed since.
+        #[structopt(long, parse(try_from_str = client::parse_version))]
+        if_version: Option<u64>,
+        /// Records why the quantity is set in the history, e.g. damaged.
+        #[structopt(long, default_value = "")]
+        reason: String,
+    },
+    /// Moves units of a product from a store to another, atomically.
+    Transfer {
+        /// Store the units leave.
+        #[structopt(long)]
+        from: proto::Store,
+        /// Store the units are added to.
+        #[structopt(long)]
+        to: proto::Store,
+        /// Product name.
+        #[structopt(long)]
+        product: String,
+        /// Number of units.
+        #[structopt(long, parse(try_from_str = client::parse_quantity))]
+        qty: u32,
+        /// Product brand.
+        #[structopt(long, default_value = "")]
+        brand: String,
+        /// Product category.
+        #[structopt(long, default_value = "")]
+        category: String,
+        /// Only removes the units from the source store, until the transfer is received with
+        /// the receive command.
+        #[structopt(long)]
+        in_transit: bool,
+        /// Records why the units are moved in the history, e.g. restock.
+        #[structopt(long, default_value = "")]
+        reason: String,
+    },
+    /// Adds the units of an in-transit transfer to its destination store.
+    Receive {
+        /// Transfer ID, as printed by transfer --in-transit.
+        id: String,
+        /// Records why the units are received in the history.
+        #[structopt(long, default_value = "")]
+        reason: String,
+    },
+    /// Creates or updates the products of the store listed in a csv or json file, with the
+    /// columns of the export, and reports the rows that failed.
+    Import {
+        /// File to import.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Format of the file: csv, json or yaml [default: from the file extension].
+        #[structopt(long)]
+        format: Option<import::FileFormat>,
+        /// Imports no row unless every one is valid.
+        #[structopt(long)]
+        all_or_nothing: bool,
+        /// Records why the products are set in the history, e.g. delivery.
+        #[structopt(long, default_value = "")]
+        reason: String,
+    },
+    /// Writes the full inventory of the store, unavailable products included, to stdout.
+    Export {
+        /// Format of the inventory: csv, json or yaml.
+        #[structopt(long, default_value = "csv")]
+        format: import::FileFormat,
+    },
+    /// Prints the products to add, update and remove for the store to match a csv, json or
+    /// yaml file of its desired inventory.
+    Diff {
+        /// File with the desired inventory.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Format of the file: csv, json or yaml [default: from the file extension].
+        #[structopt(long)]
+        format: Option<import::FileFormat>,
+        /// Exits with 1 if the store differs from the file.
+        #[structopt(long)]
+        exit_code: bool,
+    },
+    /// Converges the store to a csv, json or yaml file of its desired inventory, applying
+    /// every change at once.
+    Apply {
+        /// File with the desired inventory.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Format of the file: csv, json or yaml [default: from the file extension].
+        #[structopt(long)]
+        format: Option<import::FileFormat>,
+        /// Removes the products of the store missing from the file. Removals are only shown
+        /// unless --force is set.
+        #[structopt(long)]
+        prune: bool,
+        /// Applies the removals of --prune.
+        #[structopt(long)]
+        force: bool,
+        /// Shows the changes without applying any.
+        #[structopt(long)]
+        dry_run: bool,
+        /// Records why the changes are applied in the history, e.g. stocktake.
+        #[structopt(long, default_value = "")]
+        reason: String,
+    },
+    /// Lists the changes made to the products of the store, oldest first, with who made them
+    /// and why.
+    History {
+        /// Lists the changes of every store.
+        #[structopt(long, conflicts_with = "store")]
+        all_stores: bool,
+        /// Lists the changes of the product with the given name only.
+        #[structopt(long)]
+        product: Option<String>,
+        /// Lists the changes made since the given RFC 3339 date or duration ago, e.g.
+        /// 2020-09-13T12:00:00Z or 2d.
+        #[structopt(long, parse(try_from_str = client::parse_time))]
+        since: Option<time::SystemTime>,
+        /// Lists the changes made before the given RFC 3339 date or duration ago.
+        #[structopt(long, parse(try_from_str = client::parse_time))]
+        until: Option<time::SystemTime>,
+        /// Lists the most recent changes only, 0 for every one.
+        #[structopt(long, default_value = "50")]
+        limit: u32,
+    },
+    /// Starts an interactive session with command history and completion.
+    Shell,
+    /// Shows the store inventory full screen, refreshing it until q is pressed.
+    Watch {
+        /// Seconds between refreshes.
+        #[structopt(long, default_value = "2", parse(try_from_str = client::parse_seconds))]
+        interval: time::Duration,
     },
+    /// Manages the named contexts of the configuration file.
+    Context(ContextCommand),
+    /// Prints the completion script for the given shell. Bash, zsh and fish scripts complete
+    /// --store with the stores of the configured server.
+    Completions {
+        /// Shell to complete: bash, zsh, fish, powershell or elvish.
+        #[structopt(possible_values = &clap::Shell::variants())]
+        shell: clap::Shell,
+    },
+    /// Prints the stores of the configured server, one per line.
+    #[structopt(name = completions::COMPLETE_STORES_COMMAND, setting = clap::AppSettings::Hidden)]
+    CompleteStores,
 }
 
-fn print_table(result: proto::StockResponse) {
-    let mut table = Table::new();
-    let clean = format::FormatBuilder::new().padding(0, 4).build();
-    table.set_format(clean);
+#[derive(Debug, StructOpt)]
+enum ContextCommand {
+    /// Makes the given context the current one.
+    Use {
+        /// Context name.
+        name: String,
+    },
+    /// Lists the contexts, marking the current one with `*`.
+    List,
+    /// Prints a context as TOML, the current one by default.
+    Show {
+        /// Context name.
+        name: Option<String>,
+    },
+}
 
-    table.add_row(row!["PRODUCT", "BRAND", "CATEGORY", "STATUS", "AVAILABLE"]);
-    for item in result.items.into_vec() {
-        table.add_row(row![
-            item.name,
-            item.brand,
-            item.category,
-            item.availability_description,
-            item.quantity
-        ]);
+/// Refresh interval of the shell `watch` command when none is given.
+const DEFAULT_WATCH_INTERVAL: time::Duration = time::Duration::from_secs(2);
+
+/// Deadline of the calls made to complete `--store` values.
+const COMPLETION_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+/// Target used when neither flags, environment variables nor the context
+/// set one.
+const DEFAULT_TARGET: &str = "127.0.0.1:9090";
+
+/// Returns the path of the configuration file and its content. The default
+/// file is optional, the one given with `--config` is not.
+fn load_config(opt: &Opt) -> Result<(Option<PathBuf>, client_config::ClientConfig), config::Error> {
+    match &opt.config {
+        Some(path) => Ok((
+            Some(path.clone()),
+            client_config::ClientConfig::load(path, true)?,
+        )),
+        None => match client_config::ClientConfig::default_path() {
+            Some(path) => {
+                let config = client_config::ClientConfig::load(&path, false)?;
+                Ok((Some(path), config))
+            }
+            None => Ok((None, client_config::ClientConfig::default())),
+        },
     }
-    table.printstd();
+}
+
+/// Runs the `context` subcommands.
+fn run_context(
+    cmd: ContextCommand,
+    path: Option<PathBuf>,
+    mut config: client_config::ClientConfig,
+) -> Result<(), config::Error> {
+    match cmd {
+        ContextCommand::Use { name } => {
+            let path = path.ok_or_else(|| {
+                config::Error::Invalid(String::from("no configuration file, set --config"))
+            })?;
+            config.use_context(&name)?;
+            config.save_current_context(&path)?;
+            println!("Switched to context {:?}", name);
+        }
+        ContextCommand::List => {
+            for name in config.contexts.keys() {
+                let current = config.current_context.as_ref() == Some(name);
+                println!("{} {}", if current { "*" } else { " " }, name);
+            }
+        }
+        ContextCommand::Show { name } => {
+            let mut context = config.context(name.as_deref())?;
+            if context.token.is_some() {
+                context.token = Some(String::from("REDACTED"));
+            }
+            print!("{}", toml::to_string(&context).unwrap_or_default());
+        }
+    }
+    Ok(())
 }
 
 fn main() {
     // Parses cli options
     let opt = Opt::from_args();
 
-    let env = sync::Arc::new(grpcio::EnvBuilder::new().build());
-    let ch = grpcio::ChannelBuilder::new(env).connect(&format!("{}:{}", &opt.host, &opt.port));
-    let client = proto::StockClient::new(ch);
+    let (path, config) = match load_config(&opt) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(EXIT_ERROR);
+        }
+    };
+
+    let cmd = match opt.cmd {
+        Command::Context(cmd) => {
+            if let Err(err) = run_context(cmd, path, config) {
+                eprintln!("{}", err);
+                std::process::exit(EXIT_ERROR);
+            }
+            return;
+        }
+        Command::Completions { shell } => {
+            let stdout = io::stdout();
+            if let Err(err) =
+                completions::generate(&mut Opt::clap(), "client", shell, true, &mut stdout.lock())
+            {
+                eprintln!("Could not write completions: {}", err);
+                std::process::exit(EXIT_ERROR);
+            }
+            return;
+        }
+        cmd => cmd,
+    };
+
+    // Flags override environment variables, which override the context
+    let context = config
+        .context(opt.context.as_deref())
+        .and_then(|mut context| {
+            context.apply_env(std::env::vars())?;
+            Ok(context)
+        });
+    let context = match context {
+        Ok(context) => context,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(EXIT_ERROR);
+        }
+    };
+    let target = opt
+        .target
+        .or_else(|| context.target.clone())
+        .unwrap_or_else(|| String::from(DEFAULT_TARGET));
+    let store = opt
+        .store
+        .or(context.store)
+        .unwrap_or(proto::Store::BERLIN_DE);
 
-    match opt.cmd {
-        Command::List { show_unavailable } => {
-            match client.list_items(&client::generate_list_request(opt.store, show_unavailable)) {
+    let builder = match context.client_builder(&target) {
+        Ok(builder) => builder,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(EXIT_ERROR);
+        }
+    };
+    // Completion must be quick, falling back to the known stores instead
+    let (timeout, connect_timeout, retries) = match cmd {
+        Command::CompleteStores => (
+            cmp::min(opt.timeout, COMPLETION_TIMEOUT),
+            cmp::min(opt.connect_timeout, COMPLETION_TIMEOUT),
+            0,
+        ),
+        _ => (opt.timeout, opt.connect_timeout, opt.retries),
+    };
+    let client = match builder
+        .timeout(timeout)
+        .connect_timeout(connect_timeout)
+        .retry(client::RetryPolicy {
+            retries,
+            ..client::RetryPolicy::default()
+        })
+        .build()
+    {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(EXIT_ERROR);
+        }
+    };
+
+    let printer = output::Printer {
+        format: opt
+            .output
+            .or(context.output)
+            .unwrap_or(output::Format::Table),
+        headers: !opt.no_headers,
+        columns: if opt.columns.is_empty() {
+            output::Column::DEFAULT.to_vec()
+        } else {
+            opt.columns
+        },
+        template: opt.template,
+    };
+
+    let result = match cmd {
+        Command::List {
+            show_unavailable,
+            as_of,
+        } => {
+            let result = match as_of {
+                Some(as_of) => fetch_as_of(&client, store, show_unavailable, as_of, opt.verbose),
+                None => fetch(&client, store, show_unavailable, opt.verbose),
+            };
+            match result {
                 Ok(result) => {
-                    println!("Products available on {:?}", &opt.store);
-                    print_table(result);
+                    let title = match as_of {
+                        Some(as_of) => format!(
+                            "Products available on {:?} as of {}",
+                            &store,
+                            output::format_time(client::unix_millis(as_of))
+                        ),
+                        None => format!("Products available on {:?}", &store),
+                    };
+                    let stdout = io::stdout();
+                    printer.write_items(&mut stdout.lock(), &title, result.get_items())
+                }
+                Err(err) => {
+                    eprintln!("gRPC call failed: {}", err);
+                    std::process::exit(exit_code(&err));
+                }
+            }
+        }
+        Command::Set {
+            name,
+            quantity,
+            brand,
+            category,
+            if_version,
+            reason,
+        } => {
+            let mut req = proto::SetItemRequest::new();
+            req.set_store(store);
+            req.set_name(name);
+            req.set_brand(brand);
+            req.set_category(category);
+            req.set_quantity(quantity as i32);
+            req.set_expected_version(if_version.unwrap_or_default() as i64);
+            req.set_reason(reason);
+            match set_item(&client, req, opt.verbose) {
+                Ok(resp) => {
+                    let item = resp.get_item();
+                    if resp.get_created() {
+                        println!(
+                            "Added '{}' to {:?} with quantity {} (version {})",
+                            item.get_name(),
+                            store,
+                            item.get_quantity(),
+                            item.get_version()
+                        );
+                    } else {
+                        println!(
+                            "Set '{}' on {:?} from {} to {} (version {})",
+                            item.get_name(),
+                            store,
+                            resp.get_previous_quantity(),
+                            item.get_quantity(),
+                            item.get_version()
+                        );
+                    }
+                    Ok(())
+                }
+                Err(client::Error::Conflict(details)) => {
+                    eprintln!("Conflict: {}", details);
+                    eprintln!("List the product again and retry with its current --if-version");
+                    std::process::exit(EXIT_CONFLICT);
+                }
+                Err(err) => {
+                    eprintln!("gRPC call failed: {}", err);
+                    std::process::exit(exit_code(&err));
+                }
+            }
+        }
+        Command::Transfer {
+            from,
+            to,
+            product,
+            qty,
+            brand,
+            category,
+            in_transit,
+            reason,
+        } => {
+            let mut req = proto::TransferRequest::new();
+            req.set_from(from);
+            req.set_to(to);
+            req.set_name(product);
+            req.set_brand(brand);
+            req.set_category(category);
+            req.set_quantity(qty as i32);
+            req.set_in_transit(in_transit);
+            req.set_reason(reason);
+            let span = trace::SpanContext::generate();
+            if opt.verbose {
+                eprintln!("trace id: {}", span.trace_id());
+            }
+            match client.transfer_stock(&span, req).wait() {
+                Ok(resp) => {
+                    if opt.verbose {
+                        eprintln!("request id: {}", resp.get_request_id());
+                    }
+                    print_transfer(&resp);
+                    Ok(())
+                }
+                Err(err) => {
+                    eprintln!("gRPC call failed: {}", err);
+                    std::process::exit(exit_code(&err));
+                }
+            }
+        }
+        Command::Receive { id, reason } => {
+            let mut req = proto::ReceiveTransferRequest::new();
+            req.set_transfer_id(id);
+            req.set_reason(reason);
+            let span = trace::SpanContext::generate();
+            if opt.verbose {
+                eprintln!("trace id: {}", span.trace_id());
+            }
+            match client.receive_transfer(&span, req).wait() {
+                Ok(resp) => {
+                    if opt.verbose {
+                        eprintln!("request id: {}", resp.get_request_id());
+                    }
+                    print_transfer(&resp);
+                    Ok(())
                 }
                 Err(err) => {
                     eprintln!("gRPC call failed: {}", err);
-                    std::process::exit(2);
+                    std::process::exit(exit_code(&err));
                 }
             }
         }
+        Command::Import {
+            file,
+            format,
+            all_or_nothing,
+            reason,
+        } => match run_import(
+            &client,
+            store,
+            &file,
+            format,
+            all_or_nothing,
+            &reason,
+            opt.verbose,
+        ) {
+            Ok(true) => Ok(()),
+            Ok(false) => std::process::exit(EXIT_ERROR),
+            Err(err) => err.exit(),
+        },
+        Command::Diff {
+            file,
+            format,
+            exit_code,
+        } => match plan(&client, store, &file, format, opt.verbose) {
+            Ok(changes) => {
+                for change in &changes {
+                    println!("{}", change);
+                }
+                let count = |destructive: bool| {
+                    changes
+                        .iter()
+                        .filter(|change| change.is_destructive() == destructive)
+                        .count()
+                };
+                if changes.is_empty() {
+                    println!("{:?} matches {}", store, file.display());
+                } else {
+                    println!(
+                        "{:?} differs from {}: {} to add or update, {} to remove",
+                        store,
+                        file.display(),
+                        count(false),
+                        count(true)
+                    );
+                    if exit_code {
+                        std::process::exit(EXIT_DIFFERENT);
+                    }
+                }
+                Ok(())
+            }
+            Err(err) => err.exit(),
+        },
+        Command::Apply {
+            file,
+            format,
+            prune,
+            force,
+            dry_run,
+            reason,
+        } => {
+            let options = ApplyOptions {
+                prune,
+                force,
+                dry_run,
+                reason,
+            };
+            if let Err(err) = run_apply(&client, store, &file, format, options, opt.verbose) {
+                err.exit();
+            }
+            Ok(())
+        }
+        Command::Export { format } => match fetch(&client, store, true, opt.verbose) {
+            Ok(result) => {
+                let printer = output::Printer {
+                    format: match format {
+                        import::FileFormat::Csv => output::Format::Csv,
+                        import::FileFormat::Json => output::Format::Json,
+                        import::FileFormat::Yaml => output::Format::Yaml,
+                    },
+                    ..output::Printer::default()
+                };
+                let stdout = io::stdout();
+                printer.write_items(&mut stdout.lock(), "", result.get_items())
+            }
+            Err(err) => {
+                eprintln!("gRPC call failed: {}", err);
+                std::process::exit(exit_code(&err));
+            }
+        },
+        Command::History {
+            all_stores,
+            product,
+            since,
+            until,
+            limit,
+        } => {
+            let mut req = proto::HistoryRequest::new();
+            if !all_stores {
+                req.set_stores(vec![store]);
+            }
+            req.set_name(product.unwrap_or_default());
+            req.set_since(since.map_or(0, client::unix_millis));
+            req.set_until(until.map_or(0, client::unix_millis));
+            req.set_limit(cmp::min(limit, i32::MAX as u32) as i32);
+            match history(&client, req, opt.verbose) {
+                Ok(resp) => {
+                    let title = if all_stores {
+                        String::from("History of every store")
+                    } else {
+                        format!("History of {:?}", store)
+                    };
+                    let stdout = io::stdout();
+                    printer.write_history(&mut stdout.lock(), &title, resp.get_entries())
+                }
+                Err(err) => {
+                    eprintln!("gRPC call failed: {}", err);
+                    std::process::exit(exit_code(&err));
+                }
+            }
+        }
+        Command::Shell => run_shell(&client, store, &printer, opt.verbose),
+        Command::Watch { interval } => watch(&client, store, &printer, interval),
+        Command::CompleteStores => {
+            let stores = match client.list_stores(&trace::SpanContext::generate()).wait() {
+                Ok(resp) => resp
+                    .get_stores()
+                    .iter()
+                    .map(|store| format!("{:?}", store))
+                    .collect(),
+                Err(_) => proto::store_names(),
+            };
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            stores
+                .iter()
+                .try_for_each(|store| writeln!(stdout, "{}", store))
+        }
+        Command::Context(_) | Command::Completions { .. } => {
+            unreachable!("local commands run before connecting")
+        }
     };
+    if let Err(err) = result {
+        eprintln!("Could not write output: {}", err);
+        std::process::exit(EXIT_ERROR);
+    }
+}
+
+/// Lists the items of `store`, in a new trace propagated to the server.
+fn fetch(
+    client: &client::StockClient,
+    store: proto::Store,
+    show_unavailable: bool,
+    verbose: bool,
+) -> Result<proto::StockResponse, client::Error> {
+    let span = trace::SpanContext::generate();
+    if verbose {
+        eprintln!("trace id: {}", span.trace_id());
+    }
+    let result = client.list_items(&span, store, show_unavailable).wait()?;
+    if verbose {
+        eprintln!("request id: {}", result.get_request_id());
+    }
+    Ok(result)
+}
+
+/// Lists the items `store` had at `as_of`, in a new trace propagated to the
+/// server.
+fn fetch_as_of(
+    client: &client::StockClient,
+    store: proto::Store,
+    show_unavailable: bool,
+    as_of: time::SystemTime,
+    verbose: bool,
+) -> Result<proto::StockResponse, client::Error> {
+    let span = trace::SpanContext::generate();
+    if verbose {
+        eprintln!("trace id: {}", span.trace_id());
+    }
+    let result = client
+        .list_items_as_of(&span, store, show_unavailable, as_of)
+        .wait()?;
+    if verbose {
+        eprintln!("request id: {}", result.get_request_id());
+    }
+    Ok(result)
+}
+
+/// Sends `req` to set the quantity of a product, in a new trace.
+fn set_item(
+    client: &client::StockClient,
+    req: proto::SetItemRequest,
+    verbose: bool,
+) -> Result<proto::SetItemResponse, client::Error> {
+    let span = trace::SpanContext::generate();
+    if verbose {
+        eprintln!("trace id: {}", span.trace_id());
+    }
+    let result = client.set_item(&span, req).wait()?;
+    if verbose {
+        eprintln!("request id: {}", result.get_request_id());
+    }
+    Ok(result)
+}
+
+/// Prints the outcome of a transfer: the quantities left on the source
+/// store and held by the destination, or the ID to receive it with.
+fn print_transfer(resp: &proto::TransferResponse) {
+    let name = if resp.has_source() {
+        resp.get_source().get_name()
+    } else {
+        resp.get_destination().get_name()
+    };
+    if resp.has_source() {
+        print!(
+            "Moved {} of '{}' from {:?} ({} left)",
+            resp.get_quantity(),
+            name,
+            resp.get_from(),
+            resp.get_source().get_quantity()
+        );
+    } else {
+        print!(
+            "Received {} of '{}' from {:?}",
+            resp.get_quantity(),
+            name,
+            resp.get_from()
+        );
+    }
+    if resp.has_destination() {
+        println!(
+            " to {:?} ({} now)",
+            resp.get_to(),
+            resp.get_destination().get_quantity()
+        );
+    } else {
+        println!(
+            ", in transit to {:?} as {}",
+            resp.get_to(),
+            resp.get_transfer_id()
+        );
+    }
+}
+
+/// Lists the history changes matching `req`, in a new trace.
+fn history(
+    client: &client::StockClient,
+    req: proto::HistoryRequest,
+    verbose: bool,
+) -> Result<proto::HistoryResponse, client::Error> {
+    let span = trace::SpanContext::generate();
+    if verbose {
+        eprintln!("trace id: {}", span.trace_id());
+    }
+    let result = client.get_history(&span, req).wait()?;
+    if verbose {
+        eprintln!("request id: {}", result.get_request_id());
+    }
+    Ok(result)
+}
+
+/// `RunError` is why a command reading an inventory file could not run.
+enum RunError {
+    Invalid(String),
+    Call(client::Error),
+}
+
+impl RunError {
+    fn exit(self) -> ! {
+        match self {
+            RunError::Invalid(err) => {
+                eprintln!("{}", err);
+                std::process::exit(EXIT_ERROR);
+            }
+            RunError::Call(err) => {
+                eprintln!("gRPC call failed: {}", err);
+                std::process::exit(exit_code(&err));
+            }
+        }
+    }
+}
+
+/// Returns the rows of the inventory `file`, in `format` or the one of its
+/// extension.
+fn read_file(
+    file: &std::path::Path,
+    format: Option<import::FileFormat>,
+) -> Result<Vec<Result<import::Row, String>>, RunError> {
+    let format = format
+        .or_else(|| import::FileFormat::from_path(file))
+        .ok_or_else(|| {
+            RunError::Invalid(format!(
+                "Unknown format of {}, set --format",
+                file.display()
+            ))
+        })?;
+    std::fs::File::open(file)
+        .map_err(|e| e.to_string())
+        .and_then(|reader| import::read_rows(reader, format))
+        .map_err(|e| RunError::Invalid(format!("{}: {}", file.display(), e)))
+}
+
+/// Streams `items` to the server in a new trace, recording `reason` in the
+/// history of the changes.
+fn bulk_upsert(
+    client: &client::StockClient,
+    mut items: Vec<proto::UpsertItem>,
+    all_or_nothing: bool,
+    reason: &str,
+    verbose: bool,
+) -> Result<proto::BulkUpsertSummary, RunError> {
+    if let Some(first) = items.first_mut() {
+        first.set_reason(String::from(reason));
+    }
+    let span = trace::SpanContext::generate();
+    if verbose {
+        eprintln!("trace id: {}", span.trace_id());
+    }
+    let summary = client
+        .bulk_upsert(&span, items, all_or_nothing)
+        .wait()
+        .map_err(RunError::Call)?;
+    if verbose {
+        eprintln!("request id: {}", summary.get_request_id());
+    }
+    Ok(summary)
+}
+
+/// Imports the rows of `file` into `store` in a single `BulkUpsert` stream,
+/// printing the rows that failed and a summary. It returns whether every
+/// row was imported.
+fn run_import(
+    client: &client::StockClient,
+    store: proto::Store,
+    file: &std::path::Path,
+    format: Option<import::FileFormat>,
+    all_or_nothing: bool,
+    reason: &str,
+    verbose: bool,
+) -> Result<bool, RunError> {
+    let rows = read_file(file, format)?;
+
+    // Rows are numbered from one, as in the file, while the server reports
+    // failures by their index in the stream
+    let mut failures = vec![];
+    let mut sent = vec![];
+    let mut items = vec![];
+    for (i, row) in rows.iter().enumerate() {
+        match row {
+            Ok(row) => {
+                sent.push(i + 1);
+                items.push(row.upsert(store));
+            }
+            Err(err) => failures.push((i + 1, err.clone())),
+        }
+    }
+
+    let (mut created, mut updated) = (0, 0);
+    // With --all-or-nothing, rows that could not be read already fail it
+    if !all_or_nothing || failures.is_empty() {
+        let summary = bulk_upsert(client, items, all_or_nothing, reason, verbose)?;
+        created = summary.get_created();
+        updated = summary.get_updated();
+        for failure in summary.get_failures() {
+            let row = sent
+                .get(failure.get_index() as usize)
+                .copied()
+                .unwrap_or_default();
+            failures.push((row, String::from(failure.get_reason())));
+        }
+        failures.sort();
+    }
+
+    for (row, reason) in &failures {
+        println!("row {}: failed: {}", row, reason);
+    }
+    if all_or_nothing && !failures.is_empty() {
+        println!(
+            "Imported no rows into {:?}: {} failed",
+            store,
+            failures.len()
+        );
+    } else {
+        println!(
+            "Imported {} rows into {:?}: {} created, {} updated, {} failed",
+            rows.len(),
+            store,
+            created,
+            updated,
+            failures.len()
+        );
+    }
+    Ok(failures.is_empty())
+}
+
+/// Returns the changes turning the inventory of `store` into the one of
+/// `file`, which must be valid as a whole.
+fn plan(
+    client: &client::StockClient,
+    store: proto::Store,
+    file: &std::path::Path,
+    format: Option<import::FileFormat>,
+    verbose: bool,
+) -> Result<Vec<reconcile::Change>, RunError> {
+    let mut desired = vec![];
+    let mut failures = vec![];
+    for (i, row) in read_file(file, format)?.into_iter().enumerate() {
+        match row {
+            Ok(row) => desired.push(row),
+            Err(err) => failures.push(format!("row {}: {}", i + 1, err)),
+        }
+    }
+    if !failures.is_empty() {
+        return Err(RunError::Invalid(format!(
+            "{}: invalid rows\n{}",
+            file.display(),
+            failures.join("\n")
+        )));
+    }
+    let current = fetch(client, store, true, verbose).map_err(RunError::Call)?;
+    Ok(reconcile::diff(current.get_items(), &desired))
+}
+
+/// `ApplyOptions` holds the flags of `client apply`.
+struct ApplyOptions {
+    prune: bool,
+    force: bool,
+    dry_run: bool,
+    reason: String,
+}
+
+/// Converges `store` to the inventory of `file` in a single all-or-nothing
+/// `BulkUpsert` stream, printing the changes first. Removals need both
+/// `--prune` and `--force`.
+fn run_apply(
+    client: &client::StockClient,
+    store: proto::Store,
+    file: &std::path::Path,
+    format: Option<import::FileFormat>,
+    options: ApplyOptions,
+    verbose: bool,
+) -> Result<(), RunError> {
+    let (removals, mut changes): (Vec<_>, Vec<_>) = plan(client, store, file, format, verbose)?
+        .into_iter()
+        .partition(reconcile::Change::is_destructive);
+    let kept = if options.prune {
+        changes.extend(removals);
+        0
+    } else {
+        removals.len()
+    };
+
+    for change in &changes {
+        println!("{}", change);
+    }
+    if kept > 0 {
+        println!(
+            "Keeping {} products missing from {}, set --prune to remove them",
+            kept,
+            file.display()
+        );
+    }
+    if changes.is_empty() {
+        println!("{:?} matches {}", store, file.display());
+        return Ok(());
+    }
+    if options.dry_run {
+        println!("Dry run, no changes applied to {:?}", store);
+        return Ok(());
+    }
+
+    let skipped = if options.force {
+        0
+    } else {
+        let before = changes.len();
+        changes.retain(|change| !change.is_destructive());
+        before - changes.len()
+    };
+    if !changes.is_empty() {
+        let items = changes.iter().map(|change| change.upsert(store)).collect();
+        let summary = bulk_upsert(client, items, true, &options.reason, verbose)?;
+        if !summary.get_failures().is_empty() {
+            let failures: Vec<String> = summary
+                .get_failures()
+                .iter()
+                .map(|failure| {
+                    let index = failure.get_index();
+                    match changes.get(index as usize) {
+                        Some(change) => format!("{}: {}", change, failure.get_reason()),
+                        None => format!("change {}: {}", index, failure.get_reason()),
+                    }
+                })
+                .collect();
+            return Err(RunError::Invalid(format!(
+                "No changes applied to {:?}, the server rejected:\n{}",
+                store,
+                failures.join("\n")
+            )));
+        }
+        println!(
+            "Applied to {:?}: {} added, {} updated, {} removed",
+            store,
+            summary.get_created(),
+            summary.get_updated(),
+            summary.get_removed()
+        );
+    }
+    if skipped > 0 {
+        println!("Skipped {} removals, set --force to apply them", skipped);
+    }
+    Ok(())
+}
+
+/// Runs the interactive shell until `exit` or end of file. Call failures
+/// are printed and the session goes on.
+fn run_shell(
+    client: &client::StockClient,
+    mut store: proto::Store,
+    printer: &output::Printer,
+    verbose: bool,
+) -> io::Result<()> {
+    let catalog = Arc::new(Mutex::new(shell::Catalog::default()));
+    let interface = linefeed::Interface::new("stock")?;
+    interface.set_completer(Arc::new(shell::Completer::new(catalog.clone())));
+    interface.set_prompt(&format!("{:?}> ", store))?;
+    let history = shell::history_path();
+    if let Some(path) = &history {
+        // There is no history on the first session
+        let _ = interface.load_history(path);
+    }
+
+    let refresh = |store: proto::Store| match fetch(client, store, true, verbose) {
+        Ok(result) => {
+            if let Ok(mut catalog) = catalog.lock() {
+                catalog.update(result.get_items());
+            }
+            Some(result)
+        }
+        Err(err) => {
+            eprintln!("gRPC call failed: {}", err);
+            None
+        }
+    };
+    refresh(store);
+
+    while let linefeed::ReadResult::Input(line) = interface.read_line()? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        interface.add_history_unique(line.clone());
+        let cmd: shell::Command = match line.parse() {
+            Ok(cmd) => cmd,
+            Err(err) => {
+                eprintln!("{}", err);
+                continue;
+            }
+        };
+        let stdout = io::stdout();
+        match cmd {
+            shell::Command::List {
+                store: list_store,
+                show_unavailable,
+            } => {
+                let list_store = list_store.unwrap_or(store);
+                if let Ok(result) = fetch(client, list_store, show_unavailable, verbose)
+                    .map_err(|err| eprintln!("gRPC call failed: {}", err))
+                {
+                    let title = format!("Products available on {:?}", list_store);
+                    printer.write_items(&mut stdout.lock(), &title, result.get_items())?;
+                }
+            }
+            shell::Command::Use(new_store) => {
+                store = new_store;
+                interface.set_prompt(&format!("{:?}> ", store))?;
+                refresh(store);
+            }
+            shell::Command::Show(_) | shell::Command::Category(_) => {
+                if let Some(result) = refresh(store) {
+                    let items: Vec<proto::Item> = result
+                        .get_items()
+                        .iter()
+                        .filter(|item| cmd.matches(item))
+                        .cloned()
+                        .collect();
+                    let title = format!("Products on {:?}", store);
+                    printer.write_items(&mut stdout.lock(), &title, &items)?;
+                }
+            }
+            shell::Command::Watch(interval) => watch(
+                client,
+                store,
+                printer,
+                interval.unwrap_or(DEFAULT_WATCH_INTERVAL),
+            )?,
+            shell::Command::Help => println!("{}", shell::HELP),
+            shell::Command::Exit => break,
+        }
+    }
+
+    if let Some(path) = &history {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        interface.save_history(path)?;
+    }
+    Ok(())
+}
+
+/// Shows the inventory of `store` full screen, refreshing it every
+/// `interval` until q, Esc or Ctrl-C is pressed.
+fn watch(
+    client: &client::StockClient,
+    store: proto::Store,
+    printer: &output::Printer,
+    interval: time::Duration,
+) -> io::Result<()> {
+    let screen = mortal::Screen::new(mortal::PrepareConfig::default())?;
+    screen.set_cursor_mode(mortal::CursorMode::Invisible)?;
+    let title = format!("Products available on {:?}", store);
+    loop {
+        let mut frame = vec![];
+        match fetch(client, store, false, false) {
+            Ok(result) => printer.write_items(&mut frame, &title, result.get_items())?,
+            Err(err) => writeln!(frame, "gRPC call failed: {}", err)?,
+        }
+        writeln!(
+            frame,
+            "\nRefreshing every {}s, press q to quit.",
+            interval.as_secs_f64()
+        )?;
+
+        screen.clear_screen();
+        let frame = String::from_utf8_lossy(&frame);
+        for (line, text) in frame.lines().take(screen.size().lines).enumerate() {
+            screen.write_at((line, 0), text);
+        }
+        screen.refresh()?;
+
+        let deadline = time::Instant::now() + interval;
+        while let Some(timeout) = deadline.checked_duration_since(time::Instant::now()) {
+            match screen.read_event(Some(timeout))? {
+                Some(mortal::Event::Key(mortal::Key::Char('q')))
+                | Some(mortal::Event::Key(mortal::Key::Escape))
+                | Some(mortal::Event::Key(mortal::Key::Ctrl('c'))) => {
+                    screen.set_cursor_mode(mortal::CursorMode::Normal)?;
+                    return Ok(());
+                }
+                Some(mortal::Event::Resize(_)) => break,
+                _ => {}
+            }
+        }
+    }
 }
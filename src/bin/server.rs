@@ -1,14 +1,16 @@
 use std::net::SocketAddr;
-use std::sync::{atomic, Arc};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+use std::{thread, time};
 
 #[macro_use]
 extern crate slog;
 
 use futures::Future;
-use slog::Drain;
-use structopt::StructOpt;
+use signal_hook::iterator::Signals;
+use structopt::{clap, StructOpt};
 
-use stock::{grpc, proto, Product, StockImpl};
+use stock::{alerts, completions, config, grpc, logging, proto, trace, Product, StockImpl};
 
 /// Opt is the struct responsible for holding the server initialization options.
 #[derive(Debug, StructOpt)]
@@ -19,32 +21,309 @@ use stock::{grpc, proto, Product, StockImpl};
     about = env!("CARGO_PKG_DESCRIPTION"),
 )]
 struct Opt {
-    /// Sets the listen address.
-    #[structopt(long, default_value = "127.0.0.1:9090")]
-    addr: SocketAddr,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+
+    /// Reads the configuration from the given TOML file. Environment variables and flags
+    /// override its values.
+    #[structopt(long, short, env = "STOCK_CONFIG", parse(from_os_str), global(true))]
+    config: Option<PathBuf>,
+
+    /// Sets an address to listen on: HOST:PORT, [IPV6]:PORT or unix:PATH. It can be repeated
+    /// to listen on several addresses [default: 127.0.0.1:9090].
+    #[structopt(long, number_of_values = 1, global(true))]
+    listen: Vec<config::ListenAddr>,
+
+    /// Sets the seconds to wait for in-flight calls to finish on shutdown before cancelling
+    /// them [default: 30].
+    #[structopt(long, global(true))]
+    shutdown_timeout: Option<u64>,
+
+    /// Sets the seconds to keep serving calls on shutdown after reporting NOT_SERVING to
+    /// health checks, before no longer accepting new calls [default: 5].
+    #[structopt(long, global(true))]
+    shutdown_drain_delay: Option<u64>,
+
+    /// Sets the seconds the responses of write calls sent with an idempotency key are kept,
+    /// to be replayed if the calls are sent again. 0 keeps none [default: 86400].
+    #[structopt(long, global(true))]
+    idempotency_window: Option<u64>,
+
+    /// Sets the maximum number of responses kept for idempotency keys, the oldest ones are
+    /// dropped first. Must be positive [default: 100000].
+    #[structopt(long, global(true))]
+    idempotency_max_entries: Option<usize>,
+
+    /// Exports request spans to an OpenTelemetry collector through OTLP/HTTP, e.g.
+    /// http://127.0.0.1:4318/v1/traces.
+    #[structopt(long, global(true))]
+    otlp_endpoint: Option<String>,
+
+    /// Appends request spans as JSON lines to the given file.
+    #[structopt(long, parse(from_os_str), global(true))]
+    trace_file: Option<PathBuf>,
+
+    /// Sets the number of completion queues, each one polled by its own thread [default: 1].
+    #[structopt(long, global(true))]
+    completion_queues: Option<usize>,
+
+    /// Sets the maximum size in bytes of messages sent by the server.
+    #[structopt(long, global(true))]
+    max_send_message_size: Option<u32>,
+
+    /// Sets the maximum size in bytes of messages received by the server.
+    #[structopt(long, global(true))]
+    max_receive_message_size: Option<u32>,
+
+    /// Sets the seconds between keepalive pings sent to clients.
+    #[structopt(long, global(true))]
+    keepalive_time: Option<u64>,
+
+    /// Sets the seconds to wait for a keepalive ping acknowledgement before closing the
+    /// connection.
+    #[structopt(long, global(true))]
+    keepalive_timeout: Option<u64>,
+
+    /// Sets the maximum number of concurrent streams per connection.
+    #[structopt(long, global(true))]
+    max_concurrent_streams: Option<u32>,
+
+    /// Sets the maximum number of calls served at the same time, rejecting the others with
+    /// RESOURCE_EXHAUSTED. Zero means unlimited [default: 0].
+    #[structopt(long, global(true))]
+    max_in_flight: Option<usize>,
+
+    /// Sets the seconds between evaluations of the low-stock thresholds. Zero disables the
+    /// alerts [default: 60].
+    #[structopt(long, global(true))]
+    alerts_interval: Option<u64>,
+
+    /// Appends low-stock alerts as JSON lines to the given file.
+    #[structopt(long, parse(from_os_str), global(true))]
+    alerts_file: Option<PathBuf>,
+
+    /// Posts low-stock alerts as JSON to the given plain HTTP URL, e.g.
+    /// http://hooks.example.com/stock.
+    #[structopt(long, global(true))]
+    alerts_webhook_url: Option<String>,
+
+    /// Sets the minimum log level: trace, debug, info, warn, error or critical [default:
+    /// info]. It can be raised with SIGUSR1 and lowered with SIGUSR2 at runtime.
+    #[structopt(long, parse(try_from_str = logging::parse_level), global(true))]
+    log_level: Option<slog::Level>,
+
+    /// Sets the log format: text or json [default: text].
+    #[structopt(long, global(true))]
+    log_format: Option<logging::Format>,
+
+    /// Writes logs to the given file instead of the terminal.
+    #[structopt(long, parse(from_os_str), global(true))]
+    log_file: Option<PathBuf>,
+}
+
+/// Exit code used when in-flight calls had to be cancelled on shutdown.
+const EXIT_FORCED_SHUTDOWN: i32 = 3;
+
+/// Events the main thread waits for while running and shutting down.
+enum Event {
+    Signal,
+    Drained,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Inspects the effective configuration, merged from the file, environment variables
+    /// and flags.
+    Config(ConfigCommand),
+    /// Prints the completion script for the given shell.
+    Completions {
+        /// Shell to complete: bash, zsh, fish, powershell or elvish.
+        #[structopt(possible_values = &clap::Shell::variants())]
+        shell: clap::Shell,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum ConfigCommand {
+    /// Validates the configuration and exits.
+    Check,
+    /// Prints the configuration as TOML.
+    Print,
+}
+
+/// Returns the configuration file values overridden by `STOCK_*`
+/// environment variables and then by the command line flags.
+fn load_config(opt: &Opt) -> Result<config::Config, config::Error> {
+    let mut config = config::Config::load(opt.config.as_deref())?;
+    config.apply_env(std::env::vars())?;
+
+    if !opt.listen.is_empty() {
+        config.listen = opt.listen.clone();
+    }
+    if let Some(timeout) = opt.shutdown_timeout {
+        config.shutdown_timeout = timeout;
+    }
+    if let Some(delay) = opt.shutdown_drain_delay {
+        config.shutdown_drain_delay = delay;
+    }
+    if let Some(window) = opt.idempotency_window {
+        config.idempotency_window = window;
+    }
+    if let Some(max_entries) = opt.idempotency_max_entries {
+        config.idempotency_max_entries = max_entries;
+    }
+    if let Some(endpoint) = &opt.otlp_endpoint {
+        config.tracing.otlp_endpoint = Some(endpoint.clone());
+    }
+    if let Some(path) = &opt.trace_file {
+        config.tracing.file = Some(path.clone());
+    }
+    if let Some(queues) = opt.completion_queues {
+        config.grpc.completion_queues = queues;
+    }
+    if let Some(size) = opt.max_send_message_size {
+        config.grpc.max_send_message_size = Some(size);
+    }
+    if let Some(size) = opt.max_receive_message_size {
+        config.grpc.max_receive_message_size = Some(size);
+    }
+    if let Some(secs) = opt.keepalive_time {
+        config.grpc.keepalive_time = Some(secs);
+    }
+    if let Some(secs) = opt.keepalive_timeout {
+        config.grpc.keepalive_timeout = Some(secs);
+    }
+    if let Some(streams) = opt.max_concurrent_streams {
+        config.grpc.max_concurrent_streams = Some(streams);
+    }
+    if let Some(max) = opt.max_in_flight {
+        config.grpc.max_in_flight = max;
+    }
+    if let Some(secs) = opt.alerts_interval {
+        config.alerts.interval = secs;
+    }
+    if let Some(path) = &opt.alerts_file {
+        config.alerts.file = Some(path.clone());
+    }
+    if let Some(url) = &opt.alerts_webhook_url {
+        config.alerts.webhook_url = Some(url.clone());
+    }
+    if let Some(level) = opt.log_level {
+        config.log.level = level;
+    }
+    if let Some(format) = opt.log_format {
+        config.log.format = format;
+    }
+    if let Some(path) = &opt.log_file {
+        config.log.file = Some(path.clone());
+    }
+
+    config.validate()?;
+    Ok(config)
 }
 
 fn main() {
-    // Parses cli options
+    // Parses cli options and configuration
     let opt = Opt::from_args();
+    if let Some(Command::Completions { shell }) = opt.cmd {
+        let stdout = std::io::stdout();
+        if let Err(err) =
+            completions::generate(&mut Opt::clap(), "server", shell, false, &mut stdout.lock())
+        {
+            eprintln!("Could not write completions: {}", err);
+            std::process::exit(2);
+        }
+        return;
+    }
+    let config = match load_config(&opt) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    match opt.cmd {
+        Some(Command::Config(ConfigCommand::Check)) => {
+            println!("Configuration OK");
+            return;
+        }
+        Some(Command::Config(ConfigCommand::Print)) => {
+            print!("{}", config.to_toml());
+            return;
+        }
+        Some(Command::Completions { .. }) | None => {}
+    }
 
     // Sets up logs
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::CompactFormat::new(decorator).build().fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
+    let level = logging::LevelHandle::new(config.log.level);
+    let (drain, log_guard) =
+        match logging::drain(config.log.format, config.log.file.as_deref(), level.clone()) {
+            Ok(drain) => drain,
+            Err(e) => {
+                eprintln!("Could not set up logs: {}", e);
+                std::process::exit(2);
+            }
+        };
+    let listen = config
+        .listen
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
     let log = slog::Logger::root(
         drain,
         o!(
             "version" => Opt::clap().p.meta.version.unwrap_or("0.0.1"),
-            "address" => opt.addr
+            "listen" => listen
         ),
     );
 
+    // Changes the log level at runtime
+    match Signals::new(&[signal_hook::SIGUSR1, signal_hook::SIGUSR2]) {
+        Ok(signals) => {
+            let log = log.clone();
+            thread::spawn(move || {
+                for signal in signals.forever() {
+                    let new_level = if signal == signal_hook::SIGUSR1 {
+                        level.increase()
+                    } else {
+                        level.decrease()
+                    };
+                    info!(log, "Log level changed to {}", new_level.as_str());
+                }
+            });
+        }
+        Err(e) => {
+            error!(log, "Could not set log level signal handler: {:?}", e);
+            std::process::exit(2);
+        }
+    }
+
+    // Sets up tracing
+    let tracer = if let Some(endpoint) = &config.tracing.otlp_endpoint {
+        info!(log, "Exporting spans to {}", endpoint);
+        trace::Tracer::new(trace::OtlpExporter::new(endpoint, env!("CARGO_PKG_NAME")))
+    } else if let Some(path) = &config.tracing.file {
+        match trace::FileExporter::new(path) {
+            Ok(exporter) => {
+                info!(log, "Exporting spans to {}", path.display());
+                trace::Tracer::new(exporter)
+            }
+            Err(e) => {
+                error!(log, "Could not open trace file {}: {:?}", path.display(), e);
+                std::process::exit(2);
+            }
+        }
+    } else {
+        trace::Tracer::disabled()
+    };
+
     // Sets up gracefull shutdown
-    let running = Arc::new(atomic::AtomicBool::new(true));
-    let r = running.clone();
+    let (events, events_receiver) = mpsc::channel();
+    let signals = events.clone();
     if let Err(e) = ctrlc::set_handler(move || {
-        r.store(false, atomic::Ordering::SeqCst);
+        let _ = signals.send(Event::Signal);
     }) {
         error!(log, "Could not set signal handler: {:?}", e);
         std::process::exit(2);
@@ -87,19 +366,98 @@ fn main() {
         proto::Store::VENEZA_IT,
         0,
     );
+    stock.set_threshold(
+        proto::Store::BERLIN_DE,
+        Product::new("Laptop air", "AbCorp", "computers/laptops"),
+        Some(5),
+    );
+    stock.set_threshold(
+        proto::Store::VENEZA_IT,
+        Product::new(
+            "SantosDumond watch Active2",
+            "EvilCorp",
+            "wearables/smartwatches",
+        ),
+        Some(2),
+    );
+    if let Err(e) = config
+        .alerts
+        .check_thresholds(|store, product| stock.contains(store, product))
+    {
+        error!(log, "{}", e);
+        std::process::exit(2);
+    }
+    for threshold in &config.alerts.thresholds {
+        let product = threshold.product();
+        debug!(
+            log,
+            "Setting a threshold of {} on '{:?}' in {:?}",
+            threshold.threshold,
+            &product,
+            threshold.store
+        );
+        stock.set_threshold(threshold.store, product, Some(threshold.threshold));
+    }
+
+    // Evaluates the low-stock thresholds in the background
+    if config.alerts.interval > 0 {
+        let alerts_log = log.new(o!("component" => "alerts"));
+        let mut evaluator = alerts::Evaluator::new(stock.clone(), alerts_log.clone())
+            .with_notifier(alerts::LogNotifier::new(alerts_log));
+        if let Some(path) = &config.alerts.file {
+            match alerts::FileNotifier::new(path) {
+                Ok(notifier) => {
+                    info!(log, "Appending alerts to {}", path.display());
+                    evaluator = evaluator.with_notifier(notifier);
+                }
+                Err(e) => {
+                    error!(
+                        log,
+                        "Could not open alerts file {}: {:?}",
+                        path.display(),
+                        e
+                    );
+                    std::process::exit(2);
+                }
+            }
+        }
+        if let Some(url) = &config.alerts.webhook_url {
+            info!(log, "Posting alerts to {}", url);
+            evaluator = evaluator.with_notifier(alerts::WebhookNotifier::new(url));
+        }
+        evaluator.spawn(time::Duration::from_secs(config.alerts.interval));
+    }
 
     // Geerates and starts gRPC server instance
     trace!(log, "Creating server");
-    let handler = grpc::Handler::new(stock, log.new(o!("component" => "handler")));
-    let stock_service = grpc::Service::new(handler, log.new(o!("component" => "service")));
+    let handler = grpc::Handler::new(stock, log.new(o!("component" => "handler")))
+        .with_idempotency(
+            grpc::idempotency::Idempotency::new(time::Duration::from_secs(
+                config.idempotency_window,
+            ))
+            .with_max_entries(config.idempotency_max_entries),
+        );
+    let stock_service = grpc::Service::new(
+        handler,
+        tracer,
+        grpc::limit::ConcurrencyLimit::new(config.grpc.max_in_flight),
+        grpc::auth::Actors::new(&config.auth.actors),
+        log.new(o!("component" => "service")),
+    );
+
+    let health = grpc::health::Health::new(log.new(o!("component" => "health")));
 
-    let env = Arc::new(grpcio::Environment::new(1));
+    let env = Arc::new(grpcio::Environment::new(config.grpc.completion_queues));
     let service = proto::create_stock_service(stock_service);
-    let mut server = match grpcio::ServerBuilder::new(env)
+    let mut builder = config
+        .grpc
+        .server_builder(env)
         .register_service(service)
-        .bind(opt.addr.ip().to_string(), opt.addr.port())
-        .build()
-    {
+        .register_service(proto::create_health_service(health.clone()));
+    for addr in &config.listen {
+        builder = addr.bind(builder);
+    }
+    let mut server = match builder.build() {
         Ok(server) => server,
         Err(e) => {
             error!(log, "Failed to initialize server: {:?}", e);
@@ -108,14 +466,60 @@ fn main() {
     };
 
     server.start();
-    for &(ref host, port) in server.bind_addrs() {
-        info!(log, "listening on {}:{}", host, port);
+    for (addr, &(_, port)) in config.listen.iter().zip(server.bind_addrs()) {
+        match addr {
+            config::ListenAddr::Tcp(addr) => {
+                info!(log, "listening on {}", SocketAddr::new(addr.ip(), port))
+            }
+            config::ListenAddr::Unix(_) => info!(log, "listening on {}", addr),
+        }
     }
 
-    // Handles signals for graceful shutdown
-    while running.load(atomic::Ordering::SeqCst) {
-        std::thread::sleep(std::time::Duration::from_millis(10));
-    }
-    info!(log, "Got signal! Exiting...");
-    let _ = server.shutdown().wait();
+    // Waits for a signal, then reports NOT_SERVING to health checks and
+    // keeps serving for the drain delay, so clients stop sending calls.
+    // Then it stops accepting calls and waits for the in-flight ones up to
+    // the shutdown timeout. A second signal forces the shutdown.
+    let _ = events_receiver.recv();
+    info!(
+        log,
+        "Got signal! Shutting down...";
+        "drain_delay_secs" => config.shutdown_drain_delay,
+        "timeout_secs" => config.shutdown_timeout
+    );
+    health.set_serving(false);
+    let delay = time::Duration::from_secs(config.shutdown_drain_delay);
+    let code = if let Ok(Event::Signal) = events_receiver.recv_timeout(delay) {
+        warn!(log, "Got second signal, cancelling in-flight calls");
+        server.cancel_all_calls();
+        EXIT_FORCED_SHUTDOWN
+    } else {
+        let shutdown = server.shutdown();
+        thread::spawn(move || {
+            let _ = shutdown.wait();
+            let _ = events.send(Event::Drained);
+        });
+
+        let timeout = time::Duration::from_secs(config.shutdown_timeout);
+        match events_receiver.recv_timeout(timeout) {
+            Ok(Event::Drained) => {
+                info!(log, "All calls finished, exiting");
+                0
+            }
+            Ok(Event::Signal) => {
+                warn!(log, "Got second signal, cancelling in-flight calls");
+                server.cancel_all_calls();
+                EXIT_FORCED_SHUTDOWN
+            }
+            Err(_) => {
+                warn!(log, "Shutdown timeout exceeded, cancelling in-flight calls");
+                server.cancel_all_calls();
+                EXIT_FORCED_SHUTDOWN
+            }
+        }
+    };
+
+    // Flushes pending log records before exiting
+    drop(log);
+    drop(log_guard);
+    std::process::exit(code);
 }
@@ -0,0 +1,441 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::{env, fs, io, path, process};
+
+use serde::{Deserialize, Serialize};
+
+use super::config::{self, Error, ENV_PREFIX};
+use super::grpc::client;
+use super::{output, proto};
+
+/// Environment variable overriding the client configuration file path.
+pub const CONFIG_ENV: &str = "STOCK_CLIENT_CONFIG";
+
+/// `ClientConfig` is the client configuration file, by default
+/// `~/.config/stock/config.toml`. It holds named
+/// [`Context`](struct.Context.html)s, so switching between servers only
+/// takes `client context use NAME`.
+///
+///  # Examples
+///
+/// ```
+/// # use stock::client_config::ClientConfig;
+/// # fn main() {
+/// let config = ClientConfig::from_toml(
+///     r#"
+///     current_context = "local"
+///
+///     [contexts.local]
+///     target = "127.0.0.1:9090"
+///     store = "VENEZA_IT"
+///     "#,
+/// )
+/// .unwrap();
+/// let context = config.context(None).unwrap();
+/// assert_eq!(context.target.as_deref(), Some("127.0.0.1:9090"));
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Context used when none is selected with `--context` or
+    /// `STOCK_CONTEXT`.
+    pub current_context: Option<String>,
+    /// Contexts by name.
+    pub contexts: BTreeMap<String, Context>,
+}
+
+/// `Context` holds the connection and output settings of a server. Unset
+/// values fall back to the client defaults.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Context {
+    /// Server target: HOST:PORT, dns:///HOST:PORT or unix:PATH.
+    pub target: Option<String>,
+    /// Default store.
+    #[serde(with = "serde_store")]
+    pub store: Option<proto::Store>,
+    /// Default output format.
+    pub output: Option<output::Format>,
+    /// Bearer token sent on every call.
+    pub token: Option<String>,
+    /// Whether to connect with TLS.
+    pub tls: bool,
+    /// PEM file with the root certificates used to verify the server,
+    /// the system ones if unset.
+    pub tls_ca: Option<path::PathBuf>,
+    /// PEM file with the client certificate, for mutual TLS.
+    pub tls_cert: Option<path::PathBuf>,
+    /// PEM file with the client private key, for mutual TLS.
+    pub tls_key: Option<path::PathBuf>,
+    /// Name the server certificate is verified against, the target host
+    /// if unset.
+    pub tls_server_name: Option<String>,
+}
+
+impl ClientConfig {
+    /// Returns the default configuration file path,
+    /// `$XDG_CONFIG_HOME/stock/config.toml` or `~/.config/stock/config.toml`.
+    pub fn default_path() -> Option<path::PathBuf> {
+        env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(path::PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| path::Path::new(&home).join(".config")))
+            .map(|dir| dir.join("stock").join("config.toml"))
+    }
+
+    /// Returns the configuration on `path`. A missing file is an empty
+    /// configuration unless `required` is set.
+    pub fn load(path: &path::Path, required: bool) -> Result<Self, Error> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(ref e) if !required && e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default())
+            }
+            Err(e) => return Err(Error::Io(path.to_path_buf(), e)),
+        };
+        Self::from_toml(&content).map_err(|e| match e {
+            Error::Parse(_, e) => Error::Parse(Some(path.to_path_buf()), e),
+            e => e,
+        })
+    }
+
+    /// Returns the configuration in the given TOML document.
+    pub fn from_toml(content: &str) -> Result<Self, Error> {
+        toml::from_str(content).map_err(|e| Error::Parse(None, e))
+    }
+
+    /// Returns the configuration as a TOML document.
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).unwrap_or_default()
+    }
+
+    /// Writes the current context to the configuration file on `path`,
+    /// creating the file and its directory if needed. Only the
+    /// `current_context` line is rewritten, so the rest of the file keeps
+    /// its comments and layout. As the file may hold tokens, it's replaced
+    /// atomically with one only its owner can read.
+    pub fn save_current_context(&self, path: &path::Path) -> Result<(), Error> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(Error::Io(path.to_path_buf(), e)),
+        };
+        let content = set_current_context(&content, self.current_context.as_deref());
+        write_private(path, &content).map_err(|e| Error::Io(path.to_path_buf(), e))
+    }
+
+    /// Returns the context called `name`, or the current one if `name` is
+    /// `None`. Without a current context it returns an empty one.
+    pub fn context(&self, name: Option<&str>) -> Result<Context, Error> {
+        match name.or(self.current_context.as_deref()) {
+            Some(name) => self
+                .contexts
+                .get(name)
+                .cloned()
+                .ok_or_else(|| Error::Invalid(format!("context {:?} does not exist", name))),
+            None => Ok(Context::default()),
+        }
+    }
+
+    /// Makes `name` the current context.
+    pub fn use_context(&mut self, name: &str) -> Result<(), Error> {
+        if !self.contexts.contains_key(name) {
+            return Err(Error::Invalid(format!("context {:?} does not exist", name)));
+        }
+        self.current_context = Some(String::from(name));
+        Ok(())
+    }
+}
+
+impl Context {
+    /// Overrides the context with the `STOCK_*` client variables found in
+    /// `vars`, usually `std::env::vars()`. Unknown variables are ignored.
+    pub fn apply_env<I>(&mut self, vars: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        for (key, value) in vars {
+            let name = match key.strip_prefix(ENV_PREFIX) {
+                Some(name) => name,
+                None => continue,
+            };
+            let invalid = |reason: String| Error::Invalid(format!("{}: {}", key, reason));
+            match name {
+                "TARGET" => self.target = Some(client::parse_target(&value).map_err(invalid)?),
+                "STORE" => self.store = Some(value.parse().map_err(invalid)?),
+                "OUTPUT" => self.output = Some(value.parse().map_err(invalid)?),
+                "TOKEN" => self.token = Some(value),
+                "TLS" => self.tls = config::parse(&key, &value)?,
+                "TLS_CA" => self.tls_ca = Some(path::PathBuf::from(value)),
+                "TLS_CERT" => self.tls_cert = Some(path::PathBuf::from(value)),
+                "TLS_KEY" => self.tls_key = Some(path::PathBuf::from(value)),
+                "TLS_SERVER_NAME" => self.tls_server_name = Some(value),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a [`StockClientBuilder`](../grpc/client/struct.StockClientBuilder.html)
+    /// for `target`, with the TLS settings and token of the context. The
+    /// PEM files are read here.
+    pub fn client_builder(&self, target: &str) -> Result<client::StockClientBuilder, Error> {
+        let read = |path: &path::Path| fs::read(path).map_err(|e| Error::Io(path.to_path_buf(), e));
+
+        let mut builder = client::StockClient::builder(target).tls(self.tls);
+        if let Some(ca) = &self.tls_ca {
+            builder = builder.tls_root_ca(read(ca)?);
+        }
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => builder = builder.tls_identity(read(cert)?, read(key)?),
+            (None, None) => {}
+            _ => {
+                return Err(Error::Invalid(String::from(
+                    "tls_cert and tls_key must be set together",
+                )))
+            }
+        }
+        if let Some(name) = &self.tls_server_name {
+            builder = builder.tls_server_name(name);
+        }
+        if let Some(token) = &self.token {
+            builder = builder.token(token);
+        }
+        Ok(builder)
+    }
+}
+
+/// Returns `content` with its top-level `current_context` line set to
+/// `name`, or removed if `name` is `None`. The line is added at the top if
+/// there is none.
+fn set_current_context(content: &str, name: Option<&str>) -> String {
+    let line = name.map(|name| format!("current_context = {}", toml::Value::from(name)));
+    let mut lines = vec![];
+    let mut replaced = false;
+    let mut top_level = true;
+    for current in content.lines() {
+        let trimmed = current.trim_start();
+        top_level = top_level && !trimmed.starts_with('[');
+        let is_current_context = match trimmed.strip_prefix("current_context") {
+            Some(rest) => rest.trim_start().starts_with('='),
+            None => false,
+        };
+        if top_level && is_current_context && !replaced {
+            replaced = true;
+            lines.extend(line.clone());
+        } else {
+            lines.push(String::from(current));
+        }
+    }
+    if !replaced {
+        lines.splice(0..0, line);
+    }
+    let mut content = lines.join("\n");
+    if !content.is_empty() {
+        content.push('\n');
+    }
+    content
+}
+
+/// Replaces the file on `path` with `content`, readable only by its owner.
+/// The content is written to a temporary file in the same directory first,
+/// so readers never see a partial file.
+fn write_private(path: &path::Path, content: &str) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => path::Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = dir.join(format!(".{}.{}.tmp", name, process::id()));
+    let _ = fs::remove_file(&temp);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let result = options
+        .open(&temp)
+        .and_then(|mut file| {
+            file.write_all(content.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+mod serde_store {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    use crate::proto;
+
+    pub fn serialize<S: Serializer>(store: &Option<proto::Store>, s: S) -> Result<S::Ok, S::Error> {
+        match store {
+            Some(store) => s.serialize_some(&format!("{:?}", store)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<proto::Store>, D::Error> {
+        match Option::<String>::deserialize(d)? {
+            Some(s) => s.parse().map(Some).map_err(de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+current_context = "local"
+
+[contexts.local]
+target = "127.0.0.1:9090"
+
+[contexts.prod]
+target = "dns:///stock.example.com:443"
+store = "VENEZA_IT"
+output = "json"
+token = "secret"
+tls = true
+"#;
+
+    #[test]
+    fn test_context() {
+        let config = ClientConfig::from_toml(CONFIG).unwrap();
+        assert_eq!(
+            config.context(None).unwrap().target.as_deref(),
+            Some("127.0.0.1:9090")
+        );
+
+        let prod = config.context(Some("prod")).unwrap();
+        assert_eq!(prod.store, Some(proto::Store::VENEZA_IT));
+        assert_eq!(prod.output, Some(output::Format::Json));
+        assert!(prod.tls);
+
+        assert!(config.context(Some("staging")).is_err());
+        assert_eq!(
+            ClientConfig::default().context(None).unwrap(),
+            Context::default()
+        );
+    }
+
+    #[test]
+    fn test_from_toml_invalid() {
+        assert!(ClientConfig::from_toml("[contexts.local]\nstore = \"PARIS_FR\"").is_err());
+        assert!(ClientConfig::from_toml("[contexts.local]\nport = 9090").is_err());
+    }
+
+    #[test]
+    fn test_use_context() {
+        let mut config = ClientConfig::from_toml(CONFIG).unwrap();
+        config.use_context("prod").unwrap();
+        assert_eq!(config.current_context.as_deref(), Some("prod"));
+        assert!(config.use_context("staging").is_err());
+
+        let config = ClientConfig::from_toml(&config.to_toml()).unwrap();
+        assert_eq!(config.current_context.as_deref(), Some("prod"));
+        assert_eq!(
+            config.context(None).unwrap().store,
+            Some(proto::Store::VENEZA_IT)
+        );
+    }
+
+    #[test]
+    fn test_set_current_context() {
+        let content = "# Servers\ncurrent_context = \"local\"\n\n[contexts.local] # dev\ntarget = \"127.0.0.1:9090\"\n";
+        assert_eq!(
+            set_current_context(content, Some("prod")),
+            content.replace("\"local\"\n\n", "\"prod\"\n\n")
+        );
+        assert_eq!(
+            set_current_context(content, None),
+            content.replace("current_context = \"local\"\n", "")
+        );
+
+        // A missing line is added at the top, and table keys are left alone
+        let content = "[contexts.local]\ncurrent_context = 1\n";
+        assert_eq!(
+            set_current_context(content, Some("a \"b\"")),
+            format!("current_context = \"a \\\"b\\\"\"\n{}", content)
+        );
+        assert_eq!(
+            set_current_context("", Some("local")),
+            "current_context = \"local\"\n"
+        );
+    }
+
+    #[test]
+    fn test_save_current_context() {
+        let dir = std::env::temp_dir().join(format!("stock-client-config-{}", process::id()));
+        let path = dir.join("config.toml");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, format!("# My servers\n{}", CONFIG)).unwrap();
+
+        let mut config = ClientConfig::load(&path, true).unwrap();
+        config.use_context("prod").unwrap();
+        config.save_current_context(&path).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("# My servers\n"));
+        assert!(content.contains("current_context = \"prod\"\n"));
+        let saved = ClientConfig::load(&path, true).unwrap();
+        assert_eq!(saved, config);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let mut context = Context::default();
+        context
+            .apply_env(vec![
+                (
+                    String::from("STOCK_TARGET"),
+                    String::from("unix:/run/stock.sock"),
+                ),
+                (String::from("STOCK_STORE"), String::from("VENEZA_IT")),
+                (String::from("STOCK_TLS"), String::from("true")),
+                (String::from("STOCK_LOG_LEVEL"), String::from("debug")),
+            ])
+            .unwrap();
+        assert_eq!(context.target.as_deref(), Some("unix:/run/stock.sock"));
+        assert_eq!(context.store, Some(proto::Store::VENEZA_IT));
+        assert!(context.tls);
+
+        for (key, value) in &[
+            ("STOCK_TARGET", "http://127.0.0.1:9090"),
+            ("STOCK_OUTPUT", "xml"),
+            ("STOCK_TLS", "yes"),
+        ] {
+            assert!(Context::default()
+                .apply_env(vec![(String::from(*key), String::from(*value))])
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_client_builder_identity() {
+        let context = Context {
+            tls_cert: Some(path::PathBuf::from("client.pem")),
+            ..Context::default()
+        };
+        assert!(context.client_builder("127.0.0.1:9090").is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use std::io;
+
+use structopt::clap;
+
+/// Hidden client subcommand printing the stores of the configured server,
+/// one per line. The completion scripts run it to complete `--store`.
+pub const COMPLETE_STORES_COMMAND: &str = "complete-stores";
+
+/// Writes the completion script of `app`, installed as `bin`, for `shell`.
+///
+/// With `dynamic_stores` set, the Bash, Zsh and Fish scripts complete the
+/// `--store` values by running `bin complete-stores`, so they come from the
+/// configured server. The other shells don't complete them.
+pub fn generate<W: io::Write>(
+    app: &mut clap::App,
+    bin: &str,
+    shell: clap::Shell,
+    dynamic_stores: bool,
+    w: &mut W,
+) -> io::Result<()> {
+    let mut script = vec![];
+    app.gen_completions_to(bin, shell, &mut script);
+    let script = String::from_utf8_lossy(&script);
+    if dynamic_stores {
+        w.write_all(complete_stores(&script, bin, shell).as_bytes())
+    } else {
+        w.write_all(script.as_bytes())
+    }
+}
+
+fn complete_stores(script: &str, bin: &str, shell: clap::Shell) -> String {
+    let stores = format!("{} {} 2>/dev/null", bin, COMPLETE_STORES_COMMAND);
+    match shell {
+        // Wraps the generated function, which completes files after --store
+        clap::Shell::Bash => format!(
+            r#"{script}
+_{bin}_stores() {{
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    if [[ ${{prev}} == "--store" || ${{prev}} == "-s" ]] ; then
+        COMPREPLY=( $(compgen -W "$({stores})" -- "${{COMP_WORDS[COMP_CWORD]}}") )
+        return 0
+    fi
+    _{bin} "$@"
+}}
+
+complete -F _{bin}_stores -o bashdefault -o default {bin}
+"#,
+            script = script,
+            bin = bin,
+            stores = stores,
+        ),
+        clap::Shell::Zsh => {
+            let mut lines = vec![];
+            for (i, line) in script.lines().enumerate() {
+                let trimmed = line.trim_start();
+                if (trimmed.starts_with("'-s+[") || trimmed.starts_with("'--store=["))
+                    && trimmed.ends_with("]' \\")
+                {
+                    let spec = &line[..line.len() - "' \\".len()];
+                    lines.push(format!("{}: :_{}_stores' \\", spec, bin));
+                } else {
+                    lines.push(String::from(line));
+                }
+                // The first line is the #compdef tag
+                if i == 0 {
+                    lines.push(format!(
+                        "\n_{}_stores() {{\n    compadd -- $({})\n}}",
+                        bin, stores
+                    ));
+                }
+            }
+            lines.join("\n") + "\n"
+        }
+        clap::Shell::Fish => format!(
+            "{}complete -c {} -s s -l store -r -f -a '({})'\n",
+            script, bin, stores
+        ),
+        clap::Shell::PowerShell | clap::Shell::Elvish => String::from(script),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app() -> clap::App<'static, 'static> {
+        clap::App::new("stock-client")
+            .arg(
+                clap::Arg::with_name("store")
+                    .long("store")
+                    .short("s")
+                    .takes_value(true)
+                    .help("Defines the store"),
+            )
+            .subcommand(clap::SubCommand::with_name("list"))
+    }
+
+    fn generate_script(shell: clap::Shell, dynamic_stores: bool) -> String {
+        let mut out = vec![];
+        generate(&mut app(), "client", shell, dynamic_stores, &mut out)
+            .expect("Could not generate completions");
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_generate_static() {
+        let script = generate_script(clap::Shell::Bash, false);
+        assert!(script.contains("complete -F _client"));
+        assert!(!script.contains(COMPLETE_STORES_COMMAND));
+    }
+
+    #[test]
+    fn test_generate_dynamic_stores() {
+        let bash = generate_script(clap::Shell::Bash, true);
+        assert!(bash.contains("$(client complete-stores 2>/dev/null)"));
+        assert!(bash.ends_with("complete -F _client_stores -o bashdefault -o default client\n"));
+
+        let zsh = generate_script(clap::Shell::Zsh, true);
+        assert!(zsh.starts_with("#compdef client\n\n_client_stores() {"));
+        assert!(zsh.contains("'--store=[Defines the store]: :_client_stores' \\"));
+        assert!(zsh.contains("'-s+[Defines the store]: :_client_stores' \\"));
+
+        let fish = generate_script(clap::Shell::Fish, true);
+        assert!(fish.contains("-l store -r -f -a '(client complete-stores 2>/dev/null)'"));
+
+        assert_eq!(
+            generate_script(clap::Shell::PowerShell, true),
+            generate_script(clap::Shell::PowerShell, false)
+        );
+    }
+}
@@ -0,0 +1,984 @@
+use std::convert::TryFrom;
+use std::{error, fmt, fs, io, net, path, str, sync, time};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{logging, proto, Product};
+
+/// Prefix of the environment variables overriding the configuration file.
+pub const ENV_PREFIX: &str = "STOCK_";
+
+// Size of `sockaddr_un.sun_path` on Linux, minus the trailing NUL.
+const MAX_UNIX_PATH_LEN: usize = 107;
+
+/// `Config` is the server configuration. It's built in layers: the
+/// defaults, then the TOML configuration file, then environment variables
+/// and finally command line flags, each one overriding the previous.
+///
+///  # Examples
+///
+/// ```
+/// # use stock::config::Config;
+/// # fn main() {
+/// let mut config = Config::from_toml("listen = [\"0.0.0.0:9090\"]").unwrap();
+/// config
+///     .apply_env(vec![(String::from("STOCK_LOG_LEVEL"), String::from("debug"))])
+///     .unwrap();
+/// assert!(config.validate().is_ok());
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses the server listens on.
+    pub listen: Vec<ListenAddr>,
+    /// Seconds to wait for in-flight calls to finish on shutdown before
+    /// cancelling them.
+    pub shutdown_timeout: u64,
+    /// Seconds the server keeps serving calls on shutdown after reporting
+    /// `NOT_SERVING` to health checks, so clients and load balancers stop
+    /// sending new ones before it stops accepting them.
+    pub shutdown_drain_delay: u64,
+    /// Seconds the responses of write calls sent with an idempotency key
+    /// are kept, to be replayed if the calls are sent again. Zero keeps
+    /// none.
+    pub idempotency_window: u64,
+    /// Maximum number of responses kept for idempotency keys, the oldest
+    /// ones are dropped first. It must be positive, idempotency keys are
+    /// disabled with a zero `idempotency_window` instead.
+    pub idempotency_max_entries: usize,
+    /// Logging options.
+    pub log: LogConfig,
+    /// Tracing options.
+    pub tracing: TracingConfig,
+    /// gRPC server tuning options.
+    pub grpc: GrpcConfig,
+    /// Client authentication options.
+    pub auth: AuthConfig,
+    /// Low-stock alert options.
+    pub alerts: AlertsConfig,
+}
+
+/// `ListenAddr` is an address the server listens on: a TCP socket address,
+/// IPv4 or IPv6, or a Unix domain socket path prefixed by `unix:`.
+///
+///  # Examples
+///
+/// ```
+/// # use stock::config::ListenAddr;
+/// # fn main() {
+/// let tcp: ListenAddr = "[::1]:9090".parse().unwrap();
+/// let unix: ListenAddr = "unix:/run/stock.sock".parse().unwrap();
+/// assert_eq!(unix.to_string(), "unix:/run/stock.sock");
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ListenAddr {
+    /// TCP socket address.
+    Tcp(net::SocketAddr),
+    /// Unix domain socket path.
+    Unix(path::PathBuf),
+}
+
+impl ListenAddr {
+    /// Adds the address to `builder`.
+    pub fn bind(&self, builder: grpcio::ServerBuilder) -> grpcio::ServerBuilder {
+        match self {
+            ListenAddr::Tcp(addr) => builder.bind(addr.ip().to_string(), addr.port()),
+            // grpcio appends `:port` to the host before handing it to gRPC
+            // core as a C string, so the NUL byte keeps the port out of the
+            // socket path.
+            ListenAddr::Unix(path) => builder.bind(format!("unix:{}\0", path.display()), 0),
+        }
+    }
+}
+
+impl str::FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ListenAddr, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(String::from(
+                    "Invalid listen address, empty unix socket path",
+                ));
+            }
+            return Ok(ListenAddr::Unix(path::PathBuf::from(path)));
+        }
+        s.parse().map(ListenAddr::Tcp).map_err(|_| {
+            format!(
+                "Invalid listen address {:?}, expected HOST:PORT, [IPV6]:PORT or unix:PATH",
+                s
+            )
+        })
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<ListenAddr, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ListenAddr> for String {
+    fn from(addr: ListenAddr) -> String {
+        addr.to_string()
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// `LogConfig` holds the `[log]` section of the configuration.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Minimum level logged.
+    #[serde(with = "serde_level")]
+    pub level: slog::Level,
+    /// Format of the log records.
+    pub format: logging::Format,
+    /// File logs are appended to. Logs go to the terminal if unset.
+    pub file: Option<path::PathBuf>,
+}
+
+/// `TracingConfig` holds the `[tracing]` section of the configuration.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    /// OTLP/HTTP traces endpoint of an OpenTelemetry collector, over plain
+    /// HTTP.
+    pub otlp_endpoint: Option<String>,
+    /// File spans are appended to as JSON lines.
+    pub file: Option<path::PathBuf>,
+}
+
+/// `GrpcConfig` holds the `[grpc]` section of the configuration. Unset
+/// options keep the gRPC library defaults.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// Number of completion queues, each one polled by its own thread.
+    pub completion_queues: usize,
+    /// Maximum size in bytes of a message sent by the server.
+    pub max_send_message_size: Option<u32>,
+    /// Maximum size in bytes of a message received by the server.
+    pub max_receive_message_size: Option<u32>,
+    /// Seconds between keepalive pings sent to clients.
+    pub keepalive_time: Option<u64>,
+    /// Seconds to wait for a keepalive ping acknowledgement before closing
+    /// the connection.
+    pub keepalive_timeout: Option<u64>,
+    /// Maximum number of concurrent streams per HTTP/2 connection.
+    pub max_concurrent_streams: Option<u32>,
+    /// Maximum number of calls served at the same time. Calls above it are
+    /// rejected with `RESOURCE_EXHAUSTED`. Zero means unlimited.
+    pub max_in_flight: usize,
+}
+
+/// `AuthConfig` holds the `[auth]` section of the configuration.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Known actors, each one identified by the bearer token its calls
+    /// send. The changes made by a call are recorded in the stock history
+    /// under its actor name; calls without a token are recorded as
+    /// anonymous and calls with an unknown token as unknown.
+    pub actors: Vec<Actor>,
+}
+
+/// `AlertsConfig` holds the `[alerts]` section of the configuration.
+/// Alerts are always logged, and also sent to the file and webhook set.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertsConfig {
+    /// Seconds between evaluations of the low-stock thresholds. Zero
+    /// disables the alerts.
+    pub interval: u64,
+    /// File alerts are appended to as JSON lines.
+    pub file: Option<path::PathBuf>,
+    /// HTTP endpoint alerts are posted to as JSON, over plain HTTP.
+    pub webhook_url: Option<String>,
+    /// Reorder thresholds set on startup, on top of the ones of the seeded
+    /// products.
+    pub thresholds: Vec<AlertThreshold>,
+}
+
+/// `AlertThreshold` is an `[[alerts.thresholds]]` entry: the quantity a
+/// product is low below in a store.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertThreshold {
+    /// Store of the product, e.g. `BERLIN_DE`.
+    #[serde(with = "store_name")]
+    pub store: proto::Store,
+    /// Product name.
+    pub name: String,
+    /// Product brand.
+    #[serde(default)]
+    pub brand: String,
+    /// Product category.
+    #[serde(default)]
+    pub category: String,
+    /// Quantity the product is low below.
+    pub threshold: u32,
+}
+
+impl AlertThreshold {
+    /// Returns the product the threshold is set on.
+    pub fn product(&self) -> Product {
+        Product::new(&self.name, &self.brand, &self.category)
+    }
+}
+
+impl AlertsConfig {
+    /// Checks every threshold is set on a product of its store, as told by
+    /// `registered`. A threshold matching no product, because of a wrong
+    /// brand or category, would report it out of stock.
+    pub fn check_thresholds<F>(&self, registered: F) -> Result<(), Error>
+    where
+        F: Fn(proto::Store, &Product) -> bool,
+    {
+        for threshold in &self.thresholds {
+            let product = threshold.product();
+            if !registered(threshold.store, &product) {
+                return Err(Error::Invalid(format!(
+                    "alerts.thresholds: no product {:?} of brand {:?} and category {:?} in {:?}",
+                    product.name(),
+                    product.brand(),
+                    product.category(),
+                    threshold.store
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Stores are written by name in the configuration file
+mod store_name {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(store: &proto::Store, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format!("{:?}", store))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<proto::Store, D::Error> {
+        String::deserialize(d)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// `Actor` is a `[[auth.actors]]` entry.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Actor {
+    /// Name recorded in the stock history.
+    pub name: String,
+    /// Bearer token sent by the actor.
+    pub token: String,
+}
+
+impl str::FromStr for Actor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Actor, Self::Err> {
+        match s.find(':') {
+            Some(i) => Ok(Actor {
+                name: String::from(&s[..i]),
+                token: String::from(&s[i + 1..]),
+            }),
+            None => Err(format!("Invalid actor {:?}, expected NAME:TOKEN", s)),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![ListenAddr::Tcp(net::SocketAddr::from((
+                [127, 0, 0, 1],
+                9090,
+            )))],
+            shutdown_timeout: 30,
+            shutdown_drain_delay: 5,
+            idempotency_window: 24 * 60 * 60,
+            idempotency_max_entries: crate::grpc::idempotency::DEFAULT_MAX_ENTRIES,
+            log: LogConfig::default(),
+            tracing: TracingConfig::default(),
+            grpc: GrpcConfig::default(),
+            auth: AuthConfig::default(),
+            alerts: AlertsConfig::default(),
+        }
+    }
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        Self {
+            interval: 60,
+            file: None,
+            webhook_url: None,
+            thresholds: vec![],
+        }
+    }
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        Self {
+            completion_queues: 1,
+            max_send_message_size: None,
+            max_receive_message_size: None,
+            keepalive_time: None,
+            keepalive_timeout: None,
+            max_concurrent_streams: None,
+            max_in_flight: 0,
+        }
+    }
+}
+
+impl GrpcConfig {
+    /// Returns a
+    /// [grpcio::ServerBuilder](https://docs.rs/grpcio/0.4.7/grpcio/struct.ServerBuilder.html)
+    /// on `env` with the message size, keepalive and stream options set.
+    pub fn server_builder(&self, env: sync::Arc<grpcio::Environment>) -> grpcio::ServerBuilder {
+        let mut builder = grpcio::ChannelBuilder::new(env.clone());
+        if let Some(size) = self.max_send_message_size {
+            builder = builder.max_send_message_len(size as i32);
+        }
+        if let Some(size) = self.max_receive_message_size {
+            builder = builder.max_receive_message_len(size as i32);
+        }
+        if let Some(secs) = self.keepalive_time {
+            builder = builder.keepalive_time(time::Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.keepalive_timeout {
+            builder = builder.keepalive_timeout(time::Duration::from_secs(secs));
+        }
+        if let Some(streams) = self.max_concurrent_streams {
+            builder = builder.max_concurrent_stream(streams as i32);
+        }
+        grpcio::ServerBuilder::new(env).channel_args(builder.build_args())
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: slog::Level::Info,
+            format: logging::Format::Text,
+            file: None,
+        }
+    }
+}
+
+impl Config {
+    /// Returns the defaults overridden by the configuration file on
+    /// `path`, if given.
+    pub fn load(path: Option<&path::Path>) -> Result<Self, Error> {
+        match path {
+            Some(path) => {
+                let content =
+                    fs::read_to_string(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+                Self::from_toml(&content).map_err(|e| match e {
+                    Error::Parse(_, e) => Error::Parse(Some(path.to_path_buf()), e),
+                    e => e,
+                })
+            }
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Returns the defaults overridden by the given TOML document.
+    pub fn from_toml(content: &str) -> Result<Self, Error> {
+        toml::from_str(content).map_err(|e| Error::Parse(None, e))
+    }
+
+    /// Overrides the configuration with the `STOCK_*` variables found in
+    /// `vars`, usually `std::env::vars()`. Unknown variables are ignored.
+    pub fn apply_env<I>(&mut self, vars: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        for (key, value) in vars {
+            let name = match key.strip_prefix(ENV_PREFIX) {
+                Some(name) => name,
+                None => continue,
+            };
+            let invalid = |reason: String| Error::Invalid(format!("{}: {}", key, reason));
+            match name {
+                "LISTEN" => {
+                    self.listen = value
+                        .split(',')
+                        .map(|addr| parse(&key, addr.trim()))
+                        .collect::<Result<_, _>>()?
+                }
+                "SHUTDOWN_TIMEOUT" => self.shutdown_timeout = parse(&key, &value)?,
+                "SHUTDOWN_DRAIN_DELAY" => self.shutdown_drain_delay = parse(&key, &value)?,
+                "IDEMPOTENCY_WINDOW" => self.idempotency_window = parse(&key, &value)?,
+                "IDEMPOTENCY_MAX_ENTRIES" => self.idempotency_max_entries = parse(&key, &value)?,
+                "LOG_LEVEL" => self.log.level = logging::parse_level(&value).map_err(invalid)?,
+                "LOG_FORMAT" => self.log.format = value.parse().map_err(invalid)?,
+                "LOG_FILE" => self.log.file = Some(path::PathBuf::from(value)),
+                "OTLP_ENDPOINT" => self.tracing.otlp_endpoint = Some(value),
+                "TRACE_FILE" => self.tracing.file = Some(path::PathBuf::from(value)),
+                "GRPC_COMPLETION_QUEUES" => self.grpc.completion_queues = parse(&key, &value)?,
+                "GRPC_MAX_SEND_MESSAGE_SIZE" => {
+                    self.grpc.max_send_message_size = Some(parse(&key, &value)?)
+                }
+                "GRPC_MAX_RECEIVE_MESSAGE_SIZE" => {
+                    self.grpc.max_receive_message_size = Some(parse(&key, &value)?)
+                }
+                "GRPC_KEEPALIVE_TIME" => self.grpc.keepalive_time = Some(parse(&key, &value)?),
+                "GRPC_KEEPALIVE_TIMEOUT" => {
+                    self.grpc.keepalive_timeout = Some(parse(&key, &value)?)
+                }
+                "GRPC_MAX_CONCURRENT_STREAMS" => {
+                    self.grpc.max_concurrent_streams = Some(parse(&key, &value)?)
+                }
+                "GRPC_MAX_IN_FLIGHT" => self.grpc.max_in_flight = parse(&key, &value)?,
+                "AUTH_ACTORS" => {
+                    self.auth.actors = value
+                        .split(',')
+                        .map(|actor| parse(&key, actor.trim()))
+                        .collect::<Result<_, _>>()?
+                }
+                "ALERTS_INTERVAL" => self.alerts.interval = parse(&key, &value)?,
+                "ALERTS_FILE" => self.alerts.file = Some(path::PathBuf::from(value)),
+                "ALERTS_WEBHOOK_URL" => self.alerts.webhook_url = Some(value),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the configuration is consistent, returning the first problem
+    /// found.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.listen.is_empty() {
+            return Err(Error::Invalid(String::from(
+                "listen: at least one address is required",
+            )));
+        }
+        for (i, addr) in self.listen.iter().enumerate() {
+            if self.listen[..i].contains(addr) {
+                return Err(Error::Invalid(format!("listen: {} is repeated", addr)));
+            }
+            if let ListenAddr::Unix(path) = addr {
+                if path.as_os_str().len() > MAX_UNIX_PATH_LEN {
+                    return Err(Error::Invalid(format!(
+                        "listen: unix socket path {} is longer than {} bytes",
+                        path.display(),
+                        MAX_UNIX_PATH_LEN
+                    )));
+                }
+                if let Some(dir) = path.parent() {
+                    if !dir.as_os_str().is_empty() && !dir.is_dir() {
+                        return Err(Error::Invalid(format!(
+                            "listen: directory {} does not exist",
+                            dir.display()
+                        )));
+                    }
+                }
+            }
+        }
+        // The HTTP client is built without TLS, so https:// URLs would only
+        // fail once spans or alerts are sent
+        for (key, url) in &[
+            ("tracing.otlp_endpoint", &self.tracing.otlp_endpoint),
+            ("alerts.webhook_url", &self.alerts.webhook_url),
+        ] {
+            if let Some(url) = url {
+                if !url.starts_with("http://") {
+                    return Err(Error::Invalid(format!(
+                        "{}: expected an http:// URL, https is not supported, got {:?}",
+                        key, url
+                    )));
+                }
+            }
+        }
+        if self.tracing.otlp_endpoint.is_some() && self.tracing.file.is_some() {
+            return Err(Error::Invalid(String::from(
+                "tracing.otlp_endpoint and tracing.file cannot be set together",
+            )));
+        }
+        if self.idempotency_max_entries == 0 {
+            return Err(Error::Invalid(String::from(
+                "idempotency_max_entries: must be positive",
+            )));
+        }
+        if self.grpc.completion_queues == 0 {
+            return Err(Error::Invalid(String::from(
+                "grpc.completion_queues: must be at least 1",
+            )));
+        }
+        for (key, value) in &[
+            (
+                "grpc.max_send_message_size",
+                self.grpc.max_send_message_size,
+            ),
+            (
+                "grpc.max_receive_message_size",
+                self.grpc.max_receive_message_size,
+            ),
+            (
+                "grpc.max_concurrent_streams",
+                self.grpc.max_concurrent_streams,
+            ),
+        ] {
+            if let Some(value) = value {
+                if *value == 0 || *value > i32::MAX as u32 {
+                    return Err(Error::Invalid(format!(
+                        "{}: must be between 1 and {}",
+                        key,
+                        i32::MAX
+                    )));
+                }
+            }
+        }
+        for (i, actor) in self.auth.actors.iter().enumerate() {
+            if actor.name.is_empty() || actor.token.is_empty() {
+                return Err(Error::Invalid(String::from(
+                    "auth.actors: name and token cannot be empty",
+                )));
+            }
+            if self.auth.actors[..i]
+                .iter()
+                .any(|other| other.token == actor.token)
+            {
+                return Err(Error::Invalid(format!(
+                    "auth.actors: {} has the token of another actor",
+                    actor.name
+                )));
+            }
+        }
+        if self.alerts.thresholds.iter().any(|t| t.name.is_empty()) {
+            return Err(Error::Invalid(String::from(
+                "alerts.thresholds: name cannot be empty",
+            )));
+        }
+        for (key, file) in &[
+            ("log.file", &self.log.file),
+            ("tracing.file", &self.tracing.file),
+            ("alerts.file", &self.alerts.file),
+        ] {
+            if let Some(dir) = file.as_ref().and_then(|f| f.parent()) {
+                if !dir.as_os_str().is_empty() && !dir.is_dir() {
+                    return Err(Error::Invalid(format!(
+                        "{}: directory {} does not exist",
+                        key,
+                        dir.display()
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the configuration as a TOML document, with the tokens of the
+    /// actors redacted so it can be shown safely.
+    pub fn to_toml(&self) -> String {
+        let mut config = self.clone();
+        for actor in &mut config.auth.actors {
+            actor.token = String::from("REDACTED");
+        }
+        toml::to_string(&config).unwrap_or_default()
+    }
+}
+
+/// `Error` describes why the configuration could not be loaded.
+#[derive(Debug)]
+pub enum Error {
+    /// The configuration file could not be read.
+    Io(path::PathBuf, io::Error),
+    /// The configuration file is not valid TOML or has unknown keys.
+    Parse(Option<path::PathBuf>, toml::de::Error),
+    /// A value is not valid.
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            Error::Parse(Some(path), e) => write!(f, "invalid {}: {}", path.display(), e),
+            Error::Parse(None, e) => write!(f, "invalid configuration: {}", e),
+            Error::Invalid(reason) => write!(f, "invalid configuration: {}", reason),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+pub(crate) fn parse<T>(key: &str, value: &str) -> Result<T, Error>
+where
+    T: str::FromStr,
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| Error::Invalid(format!("{}: {}", key, e)))
+}
+
+mod serde_level {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(level: &slog::Level, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&level.as_str().to_lowercase())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<slog::Level, D::Error> {
+        let s = String::deserialize(d)?;
+        crate::logging::parse_level(&s).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (String::from(*k), String::from(*v)))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_toml() {
+        let config = Config::from_toml(
+            r#"
+            listen = ["0.0.0.0:8080", "[::1]:8080"]
+
+            [log]
+            level = "debug"
+            format = "json"
+            "#,
+        )
+        .expect("Could not parse configuration");
+
+        assert_eq!(
+            config.listen,
+            vec![
+                "0.0.0.0:8080".parse().unwrap(),
+                "[::1]:8080".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.log.level, slog::Level::Debug);
+        assert_eq!(config.log.format, logging::Format::Json);
+        assert_eq!(config.log.file, None);
+        assert_eq!(config.tracing, TracingConfig::default());
+        assert_eq!(config.auth, AuthConfig::default());
+        assert_eq!(config.alerts, AlertsConfig::default());
+    }
+
+    #[test]
+    fn test_from_toml_actors() {
+        let config = Config::from_toml(
+            r#"
+            [[auth.actors]]
+            name = "alice"
+            token = "s3cr3t"
+            "#,
+        )
+        .expect("Could not parse configuration");
+        assert_eq!(
+            config.auth.actors,
+            vec![Actor {
+                name: String::from("alice"),
+                token: String::from("s3cr3t")
+            }]
+        );
+    }
+
+    #[test]
+    fn test_from_toml_alert_thresholds() {
+        let config = Config::from_toml(
+            r#"
+            [[alerts.thresholds]]
+            store = "veneza_it"
+            name = "Model 3"
+            brand = "Tesla"
+            threshold = 1
+            "#,
+        )
+        .expect("Could not parse configuration");
+        assert_eq!(
+            config.alerts.thresholds,
+            vec![AlertThreshold {
+                store: proto::Store::VENEZA_IT,
+                name: String::from("Model 3"),
+                brand: String::from("Tesla"),
+                category: String::new(),
+                threshold: 1,
+            }]
+        );
+        assert_eq!(config.alerts.interval, 60);
+        let printed = toml::to_string(&config).unwrap();
+        assert!(printed.contains("store = \"VENEZA_IT\""), "{}", printed);
+
+        let err = Config::from_toml(
+            "[[alerts.thresholds]]\nstore = \"PARIS_FR\"\nname = \"a\"\nthreshold = 1",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("Invalid store"), "{}", err);
+    }
+
+    #[test]
+    fn test_check_thresholds() {
+        let laptop = Product::new("Laptop air", "AbCorp", "computers/laptops");
+        let registered =
+            |store, product: &Product| store == proto::Store::BERLIN_DE && *product == laptop;
+        let mut alerts = AlertsConfig::default();
+        assert!(alerts.check_thresholds(registered).is_ok());
+
+        alerts.thresholds = vec![AlertThreshold {
+            store: proto::Store::BERLIN_DE,
+            name: String::from("Laptop air"),
+            brand: String::from("AbCorp"),
+            category: String::from("computers/laptops"),
+            threshold: 5,
+        }];
+        assert!(alerts.check_thresholds(registered).is_ok());
+
+        // Without the category no product matches
+        alerts.thresholds[0].category = String::new();
+        let err = alerts.check_thresholds(registered).unwrap_err();
+        assert!(err.to_string().contains("alerts.thresholds"), "{}", err);
+
+        alerts.thresholds[0].category = String::from("computers/laptops");
+        alerts.thresholds[0].store = proto::Store::VENEZA_IT;
+        assert!(alerts.check_thresholds(registered).is_err());
+    }
+
+    #[test]
+    fn test_from_toml_unknown_key() {
+        let err = Config::from_toml("[log]\nlevle = \"debug\"").unwrap_err();
+        assert!(err.to_string().contains("levle"), "{}", err);
+    }
+
+    #[test]
+    fn test_from_toml_invalid_level() {
+        let err = Config::from_toml("[log]\nlevel = \"loud\"").unwrap_err();
+        assert!(err.to_string().contains("Invalid log level"), "{}", err);
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let mut config = Config::from_toml("[log]\nlevel = \"debug\"").unwrap();
+        config
+            .apply_env(env(&[
+                ("STOCK_LOG_LEVEL", "error"),
+                ("STOCK_LISTEN", "127.0.0.1:8080, unix:/run/stock.sock"),
+                ("STOCK_SHUTDOWN_TIMEOUT", "5"),
+                ("STOCK_SHUTDOWN_DRAIN_DELAY", "0"),
+                ("STOCK_IDEMPOTENCY_WINDOW", "0"),
+                ("STOCK_IDEMPOTENCY_MAX_ENTRIES", "10"),
+                ("HOME", "/root"),
+            ]))
+            .expect("Could not apply env");
+
+        assert_eq!(config.log.level, slog::Level::Error);
+        assert_eq!(config.shutdown_timeout, 5);
+        assert_eq!(config.shutdown_drain_delay, 0);
+        assert_eq!(config.idempotency_window, 0);
+        assert_eq!(config.idempotency_max_entries, 10);
+        assert_eq!(
+            config.listen,
+            vec![
+                ListenAddr::Tcp("127.0.0.1:8080".parse().unwrap()),
+                ListenAddr::Unix(path::PathBuf::from("/run/stock.sock"))
+            ]
+        );
+
+        config
+            .apply_env(env(&[
+                ("STOCK_GRPC_COMPLETION_QUEUES", "4"),
+                ("STOCK_GRPC_MAX_IN_FLIGHT", "100"),
+                ("STOCK_GRPC_KEEPALIVE_TIME", "60"),
+            ]))
+            .expect("Could not apply env");
+        assert_eq!(config.grpc.completion_queues, 4);
+        assert_eq!(config.grpc.max_in_flight, 100);
+        assert_eq!(config.grpc.keepalive_time, Some(60));
+
+        config
+            .apply_env(env(&[
+                ("STOCK_ALERTS_INTERVAL", "5"),
+                ("STOCK_ALERTS_FILE", "/tmp/alerts.json"),
+                ("STOCK_ALERTS_WEBHOOK_URL", "http://127.0.0.1:8000/alerts"),
+            ]))
+            .expect("Could not apply env");
+        assert_eq!(config.alerts.interval, 5);
+        assert_eq!(
+            config.alerts.file,
+            Some(path::PathBuf::from("/tmp/alerts.json"))
+        );
+        assert_eq!(
+            config.alerts.webhook_url,
+            Some(String::from("http://127.0.0.1:8000/alerts"))
+        );
+
+        config
+            .apply_env(env(&[("STOCK_AUTH_ACTORS", "alice:a:1, bob:b")]))
+            .expect("Could not apply env");
+        assert_eq!(
+            config.auth.actors,
+            vec![
+                Actor {
+                    name: String::from("alice"),
+                    token: String::from("a:1")
+                },
+                Actor {
+                    name: String::from("bob"),
+                    token: String::from("b")
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_env_invalid() {
+        let err = Config::default()
+            .apply_env(env(&[("STOCK_LISTEN", "localhost")]))
+            .unwrap_err();
+        assert!(err.to_string().contains("STOCK_LISTEN"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Config::default().validate().is_ok());
+
+        let mut config = Config::default();
+        config.tracing.otlp_endpoint = Some(String::from("127.0.0.1:4318"));
+        assert!(config.validate().is_err());
+
+        config.tracing.otlp_endpoint = Some(String::from("https://127.0.0.1:4318/v1/traces"));
+        assert!(config.validate().is_err());
+
+        config.tracing.otlp_endpoint = Some(String::from("http://127.0.0.1:4318/v1/traces"));
+        assert!(config.validate().is_ok());
+
+        config.tracing.file = Some(path::PathBuf::from("traces.json"));
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.log.file = Some(path::PathBuf::from("/does/not/exist/stock.log"));
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            listen: vec![],
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.listen.push(config.listen[0].clone());
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            listen: vec![ListenAddr::Unix(std::env::temp_dir().join("a".repeat(200)))],
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.grpc.completion_queues = 0;
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            idempotency_max_entries: 0,
+            ..Config::default()
+        };
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("must be positive"), "{}", err);
+
+        let mut config = Config::default();
+        config.grpc.max_receive_message_size = Some(u32::MAX);
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.auth.actors = vec!["alice:a".parse().unwrap(), "bob:".parse().unwrap()];
+        assert!(config.validate().is_err());
+
+        config.auth.actors[1].token = String::from("a");
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.alerts.webhook_url = Some(String::from("hooks.example.com"));
+        assert!(config.validate().is_err());
+
+        config.alerts.webhook_url = Some(String::from("https://hooks.example.com/stock"));
+        let err = config.validate().unwrap_err();
+        assert!(
+            err.to_string().contains("https is not supported"),
+            "{}",
+            err
+        );
+
+        config.alerts.webhook_url = Some(String::from("http://hooks.example.com/stock"));
+        assert!(config.validate().is_ok());
+
+        config.alerts.file = Some(path::PathBuf::from("/does/not/exist/alerts.json"));
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.alerts.thresholds = vec![AlertThreshold {
+            store: proto::Store::BERLIN_DE,
+            name: String::new(),
+            brand: String::new(),
+            category: String::new(),
+            threshold: 1,
+        }];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_listen_addr_from_str() {
+        assert_eq!(
+            "127.0.0.1:9090".parse(),
+            Ok(ListenAddr::Tcp(net::SocketAddr::from((
+                [127, 0, 0, 1],
+                9090
+            ))))
+        );
+        assert_eq!(
+            "[::]:9090".parse::<ListenAddr>().map(|a| a.to_string()),
+            Ok(String::from("[::]:9090"))
+        );
+        assert_eq!(
+            "unix:/run/stock.sock".parse(),
+            Ok(ListenAddr::Unix(path::PathBuf::from("/run/stock.sock")))
+        );
+        assert!("unix:".parse::<ListenAddr>().is_err());
+        assert!("localhost:9090".parse::<ListenAddr>().is_err());
+        assert!("::1:9090".parse::<ListenAddr>().is_err());
+    }
+
+    #[test]
+    fn test_to_toml_roundtrip() {
+        let mut config = Config::default();
+        config
+            .listen
+            .push(ListenAddr::Unix(path::PathBuf::from("/run/stock.sock")));
+        config.log.level = slog::Level::Warning;
+        config.tracing.file = Some(path::PathBuf::from("traces.json"));
+        config.auth.actors = vec!["alice:s3cr3t".parse().unwrap()];
+        config.alerts.webhook_url = Some(String::from("http://127.0.0.1:8000/alerts"));
+
+        let toml = config.to_toml();
+        assert!(!toml.contains("s3cr3t"), "{}", toml);
+        config.auth.actors[0].token = String::from("REDACTED");
+        assert_eq!(Config::from_toml(&toml).unwrap(), config);
+    }
+}
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::config;
+
+/// Actor recorded for the calls sending a token no actor has.
+pub const UNKNOWN_ACTOR: &str = "unknown";
+
+/// `Actors` maps the bearer tokens sent on the `authorization` metadata
+/// of the calls to the actors recorded in the stock history.
+///
+/// Clones share the same map.
+#[derive(Clone, Debug, Default)]
+pub struct Actors {
+    tokens: Arc<HashMap<String, String>>,
+}
+
+impl Actors {
+    /// Returns the `Actors` of the `[[auth.actors]]` configuration entries.
+    pub fn new(actors: &[config::Actor]) -> Self {
+        Self {
+            tokens: Arc::new(
+                actors
+                    .iter()
+                    .map(|actor| (actor.token.clone(), actor.name.clone()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Returns the actor of a call given its `authorization` metadata
+    /// value: [`ANONYMOUS_ACTOR`](../../constant.ANONYMOUS_ACTOR.html)
+    /// without a bearer token, or
+    /// [`UNKNOWN_ACTOR`](constant.UNKNOWN_ACTOR.html) if no actor has it.
+    pub fn resolve(&self, authorization: Option<&str>) -> &str {
+        match authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+            Some(token) => self
+                .tokens
+                .get(token)
+                .map(String::as_str)
+                .unwrap_or(UNKNOWN_ACTOR),
+            None => crate::ANONYMOUS_ACTOR,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let actors = Actors::new(&["alice:s3cr3t".parse().unwrap()]);
+        assert_eq!(actors.resolve(Some("Bearer s3cr3t")), "alice");
+        assert_eq!(actors.resolve(Some("Bearer other")), UNKNOWN_ACTOR);
+        assert_eq!(actors.resolve(Some("s3cr3t")), crate::ANONYMOUS_ACTOR);
+        assert_eq!(actors.resolve(None), crate::ANONYMOUS_ACTOR);
+        assert_eq!(
+            Actors::default().resolve(Some("Bearer s3cr3t")),
+            UNKNOWN_ACTOR
+        );
+    }
+}
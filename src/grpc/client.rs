@@ -1,4 +1,750 @@
-use crate::proto;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::{cmp, collections, error, ffi, fmt, thread, time};
+
+use futures::future::{self, Loop};
+use futures::{stream, Future, IntoFuture, Sink};
+use rand::Rng;
+
+use crate::{proto, trace};
+
+/// Metadata key carrying the bearer token sent by
+/// [`StockClient`](struct.StockClient.html).
+pub const AUTHORIZATION_HEADER: &str = "authorization";
+
+/// `ClientFuture` is the future returned by the
+/// [`StockClient`](struct.StockClient.html) methods.
+pub type ClientFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
+
+/// `Error` describes why a [`StockClient`](struct.StockClient.html) call
+/// failed.
+#[derive(Debug)]
+pub enum Error {
+    /// The client configuration is not valid.
+    Config(String),
+    /// The server could not be reached.
+    Unavailable(String),
+    /// The call deadline was exceeded.
+    DeadlineExceeded,
+    /// The server rejected the call because it's overloaded.
+    ResourceExhausted(String),
+    /// The change conflicted with another one: the product was changed
+    /// since the expected version, or is not in the store anymore.
+    Conflict(String),
+    /// The server failed the call with another status.
+    Status(grpcio::RpcStatus),
+    /// The call failed on the client side.
+    Grpc(grpcio::Error),
+}
+
+impl Error {
+    /// Returns whether the call may succeed if retried: the server was
+    /// unreachable or rejected the call because it was overloaded.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Error::Unavailable(_) | Error::ResourceExhausted(_))
+    }
+}
+
+impl From<grpcio::Error> for Error {
+    fn from(err: grpcio::Error) -> Self {
+        match err {
+            grpcio::Error::RpcFailure(status) => {
+                let details = status.details.clone().unwrap_or_default();
+                match status.status {
+                    grpcio::RpcStatusCode::Unavailable => Error::Unavailable(details),
+                    grpcio::RpcStatusCode::DeadlineExceeded => Error::DeadlineExceeded,
+                    grpcio::RpcStatusCode::ResourceExhausted => Error::ResourceExhausted(details),
+                    grpcio::RpcStatusCode::Aborted | grpcio::RpcStatusCode::FailedPrecondition => {
+                        Error::Conflict(details)
+                    }
+                    _ => Error::Status(status),
+                }
+            }
+            err => Error::Grpc(err),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(reason) => write!(f, "invalid client configuration: {}", reason),
+            Error::Unavailable(details) => write!(f, "server unavailable: {}", details),
+            Error::DeadlineExceeded => write!(f, "deadline exceeded"),
+            Error::ResourceExhausted(details) => write!(f, "server overloaded: {}", details),
+            Error::Conflict(details) => write!(f, "conflict: {}", details),
+            Error::Status(status) => write!(
+                f,
+                "call failed with {:?}: {}",
+                status.status,
+                status.details.as_deref().unwrap_or_default()
+            ),
+            Error::Grpc(err) => write!(f, "call failed: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// `RetryPolicy` retries idempotent calls failing with a transient error,
+/// waiting an exponentially growing backoff with full jitter between the
+/// attempts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt.
+    pub retries: u32,
+    /// Upper bound of the wait before the first retry. It doubles on every
+    /// following retry.
+    pub initial_backoff: time::Duration,
+    /// Upper bound of the wait between any two attempts.
+    pub max_backoff: time::Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            initial_backoff: time::Duration::from_millis(100),
+            max_backoff: time::Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the wait before the retry number `attempt`, starting from 1.
+    /// It's a random duration between zero and the exponential backoff
+    /// ceiling.
+    pub fn backoff(&self, attempt: u32) -> time::Duration {
+        let ceiling = self
+            .initial_backoff
+            .checked_mul(1 << cmp::min(attempt.saturating_sub(1), 16))
+            .map_or(self.max_backoff, |b| cmp::min(b, self.max_backoff));
+        let millis = ceiling.as_millis() as u64;
+        time::Duration::from_millis(rand::thread_rng().gen_range(0, millis + 1))
+    }
+
+    /// Runs the future returned by `f` until it succeeds, fails with an
+    /// error that is not [retryable](enum.Error.html#method.is_retryable) or
+    /// runs out of retries. `f` receives the attempt number, starting from
+    /// 0, and must be idempotent.
+    pub fn run<T, F, R>(&self, f: F) -> ClientFuture<T>
+    where
+        T: Send + 'static,
+        F: FnMut(u32) -> R + Send + 'static,
+        R: IntoFuture<Item = T, Error = Error>,
+        R::Future: Send + 'static,
+    {
+        let policy = self.clone();
+        Box::new(future::loop_fn((f, 0), move |(mut f, attempt)| {
+            let policy = policy.clone();
+            f(attempt)
+                .into_future()
+                .then(move |res| -> ClientFuture<Loop<T, (F, u32)>> {
+                    match res {
+                        Err(ref err) if attempt < policy.retries && err.is_retryable() => Box::new(
+                            delay(policy.backoff(attempt + 1))
+                                .then(move |_| Ok(Loop::Continue((f, attempt + 1)))),
+                        ),
+                        res => Box::new(future::result(res.map(Loop::Break))),
+                    }
+                })
+        }))
+    }
+}
+
+/// Returns a future resolving after `duration`. grpcio has no timers, so
+/// it's driven by a short lived thread.
+fn delay(duration: time::Duration) -> impl Future<Item = (), Error = ()> + Send {
+    let (sender, receiver) = futures::sync::oneshot::channel();
+    thread::spawn(move || {
+        thread::sleep(duration);
+        let _ = sender.send(());
+    });
+    receiver.map_err(|_| ())
+}
+
+/// `StockClientBuilder` configures and builds a
+/// [`StockClient`](struct.StockClient.html).
+///
+///  # Examples
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use stock::grpc::client::StockClient;
+/// # fn main() {
+/// let client = StockClient::builder("unix:/run/stock.sock")
+///     .token("secret")
+///     .timeout(Duration::from_secs(2))
+///     .build()
+///     .unwrap();
+/// # }
+/// ```
+pub struct StockClientBuilder {
+    target: String,
+    env: Option<Arc<grpcio::Environment>>,
+    tls: bool,
+    root_ca: Option<Vec<u8>>,
+    identity: Option<(Vec<u8>, Vec<u8>)>,
+    server_name: Option<String>,
+    token: Option<String>,
+    timeout: time::Duration,
+    connect_timeout: time::Duration,
+    retry: RetryPolicy,
+    cache_ttl: Option<time::Duration>,
+}
+
+impl StockClientBuilder {
+    /// Returns a builder connecting to `target`, either `HOST:PORT` or a
+    /// gRPC target URI, as accepted by [`parse_target`](fn.parse_target.html).
+    pub fn new(target: &str) -> Self {
+        Self {
+            target: String::from(target),
+            env: None,
+            tls: false,
+            root_ca: None,
+            identity: None,
+            server_name: None,
+            token: None,
+            timeout: time::Duration::from_secs(10),
+            connect_timeout: time::Duration::from_secs(5),
+            retry: RetryPolicy::default(),
+            cache_ttl: None,
+        }
+    }
+
+    /// Sets the
+    /// [grpcio::Environment](https://docs.rs/grpcio/0.4.7/grpcio/struct.Environment.html)
+    /// polling the calls, so it can be shared with other clients. A new one
+    /// is created by default.
+    pub fn environment(mut self, env: Arc<grpcio::Environment>) -> Self {
+        self.env = Some(env);
+        self
+    }
+
+    /// Enables TLS, verifying the server with the system root certificates
+    /// unless [`tls_root_ca`](#method.tls_root_ca) is set.
+    pub fn tls(mut self, tls: bool) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Enables TLS, verifying the server with the given PEM encoded root
+    /// certificates.
+    pub fn tls_root_ca(mut self, pem: Vec<u8>) -> Self {
+        self.tls = true;
+        self.root_ca = Some(pem);
+        self
+    }
+
+    /// Enables TLS, authenticating the client with the given PEM encoded
+    /// certificate chain and private key.
+    pub fn tls_identity(mut self, cert: Vec<u8>, key: Vec<u8>) -> Self {
+        self.tls = true;
+        self.identity = Some((cert, key));
+        self
+    }
+
+    /// Overrides the name checked against the server certificate, which is
+    /// the target host by default.
+    pub fn tls_server_name(mut self, name: &str) -> Self {
+        self.server_name = Some(String::from(name));
+        self
+    }
+
+    /// Sets the bearer token sent on the `authorization` metadata of every
+    /// call.
+    pub fn token(mut self, token: &str) -> Self {
+        self.token = Some(String::from(token));
+        self
+    }
+
+    /// Sets the deadline of each call attempt. Defaults to 10 seconds.
+    pub fn timeout(mut self, timeout: time::Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how long to wait for a connection to the server before failing
+    /// calls as unavailable. Defaults to 5 seconds.
+    pub fn connect_timeout(mut self, timeout: time::Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets the [`RetryPolicy`](struct.RetryPolicy.html) of idempotent
+    /// calls.
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Caches responses for `ttl`. Once expired, cached responses are
+    /// revalidated with the server, which only sends the items again if
+    /// they changed. Disabled by default.
+    pub fn cache(mut self, ttl: time::Duration) -> Self {
+        self.cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Returns the [`StockClient`](struct.StockClient.html), or an
+    /// [`Error::Config`](enum.Error.html) if the options are not valid. The
+    /// connection is only established on the first call.
+    pub fn build(self) -> Result<StockClient, Error> {
+        let target = parse_target(&self.target).map_err(Error::Config)?;
+        if let Some(token) = &self.token {
+            if token.is_empty() || !token.bytes().all(|b| b.is_ascii_graphic()) {
+                return Err(Error::Config(String::from(
+                    "token must be non-empty printable ASCII",
+                )));
+            }
+        }
+        if !self.tls && self.server_name.is_some() {
+            return Err(Error::Config(String::from(
+                "a TLS server name requires TLS to be enabled",
+            )));
+        }
+
+        let env = self
+            .env
+            .unwrap_or_else(|| Arc::new(grpcio::EnvBuilder::new().build()));
+        let mut builder = channel_builder(env, self.connect_timeout);
+        let channel = if self.tls {
+            if let Some(name) = &self.server_name {
+                builder = builder.override_ssl_target(name.as_str());
+            }
+            let mut creds = grpcio::ChannelCredentialsBuilder::new();
+            if let Some(pem) = self.root_ca {
+                creds = creds.root_cert(pem);
+            }
+            if let Some((cert, key)) = self.identity {
+                creds = creds.cert(cert, key);
+            }
+            builder.secure_connect(&target, creds.build())
+        } else {
+            builder.connect(&target)
+        };
+
+        Ok(StockClient {
+            client: proto::StockClient::new(channel),
+            token: self.token,
+            timeout: self.timeout,
+            retry: self.retry,
+            cache: self.cache_ttl.map(|ttl| Arc::new(Cache::new(ttl))),
+        })
+    }
+}
+
+/// `StockClient` is a typed client of the stock service, meant to be
+/// shared by the CLI and other services. It's cheap to clone, clones share
+/// the same connection.
+///
+///  # Examples
+///
+/// ```no_run
+/// # use futures::Future;
+/// # use stock::{grpc::client::StockClient, proto, trace};
+/// # fn main() {
+/// let client = StockClient::builder("127.0.0.1:9090").build().unwrap();
+/// let resp = client
+///     .list_items(&trace::SpanContext::generate(), proto::Store::BERLIN_DE, false)
+///     .wait()
+///     .unwrap();
+/// println!("{} items", resp.get_items().len());
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct StockClient {
+    client: proto::StockClient,
+    token: Option<String>,
+    timeout: time::Duration,
+    retry: RetryPolicy,
+    cache: Option<Arc<Cache>>,
+}
+
+impl StockClient {
+    /// Returns a [`StockClientBuilder`](struct.StockClientBuilder.html)
+    /// connecting to `target`.
+    pub fn builder(target: &str) -> StockClientBuilder {
+        StockClientBuilder::new(target)
+    }
+
+    /// Lists the items of `store`, including the unavailable ones if
+    /// `show_unavailable` is set. The call joins the trace of `span`, and
+    /// it's retried according to the client
+    /// [`RetryPolicy`](struct.RetryPolicy.html). If the cache is enabled,
+    /// fresh responses are returned without calling the server.
+    pub fn list_items(
+        &self,
+        span: &trace::SpanContext,
+        store: proto::Store,
+        show_unavailable: bool,
+    ) -> ClientFuture<proto::StockResponse> {
+        let key = (store, show_unavailable);
+        let mut req = generate_list_request(store, show_unavailable);
+        if let Some(cache) = &self.cache {
+            match cache.lookup(key) {
+                Lookup::Fresh(resp) => return Box::new(future::ok(resp)),
+                Lookup::Stale(version) => req.set_if_none_match(version),
+                Lookup::Miss => {}
+            }
+        }
+
+        let cache = self.cache.clone();
+        Box::new(
+            self.call(span, move |client, opt| {
+                client.list_items_async_opt(&req, opt)
+            })
+            .map(move |resp| match cache {
+                Some(cache) => cache.update(key, resp),
+                None => resp,
+            }),
+        )
+    }
+
+    /// Lists the items `store` held at `as_of`, reconstructed by the server
+    /// from its history. Past inventories don't change, so the cache is not
+    /// used.
+    pub fn list_items_as_of(
+        &self,
+        span: &trace::SpanContext,
+        store: proto::Store,
+        show_unavailable: bool,
+        as_of: time::SystemTime,
+    ) -> ClientFuture<proto::StockResponse> {
+        let mut req = generate_list_request(store, show_unavailable);
+        req.set_as_of(unix_millis(as_of));
+        self.call(span, move |client, opt| {
+            client.list_items_async_opt(&req, opt)
+        })
+    }
+
+    /// Lists the stores holding products on the server. The call joins the
+    /// trace of `span`, and it's retried according to the client
+    /// [`RetryPolicy`](struct.RetryPolicy.html).
+    pub fn list_stores(&self, span: &trace::SpanContext) -> ClientFuture<proto::StoresResponse> {
+        let req = proto::StoresRequest::new();
+        self.call(span, move |client, opt| {
+            client.list_stores_async_opt(&req, opt)
+        })
+    }
+
+    /// Creates or updates a product of a store, setting its quantity. The
+    /// call is retried like the listing ones, with the idempotency key of
+    /// `req` or a generated one, so the server applies it once.
+    pub fn set_item(
+        &self,
+        span: &trace::SpanContext,
+        mut req: proto::SetItemRequest,
+    ) -> ClientFuture<proto::SetItemResponse> {
+        if req.get_idempotency_key().is_empty() {
+            req.set_idempotency_key(uuid::Uuid::new_v4().to_string());
+        }
+        self.call(span, move |client, opt| {
+            client.set_item_async_opt(&req, opt)
+        })
+    }
+
+    /// Streams `items` to the server, which creates or updates their
+    /// products in batches, and returns the summary of the stream, with
+    /// the items that failed by index. Setting `all_or_nothing` applies no
+    /// item unless every one is valid. Like [`set_item`](#method.set_item),
+    /// the call is retried by sending every item again, with the
+    /// idempotency key of the first item or a generated one.
+    pub fn bulk_upsert(
+        &self,
+        span: &trace::SpanContext,
+        mut items: Vec<proto::UpsertItem>,
+        all_or_nothing: bool,
+    ) -> ClientFuture<proto::BulkUpsertSummary> {
+        if let Some(first) = items.first_mut() {
+            first.set_all_or_nothing(all_or_nothing);
+            if first.get_idempotency_key().is_empty() {
+                first.set_idempotency_key(uuid::Uuid::new_v4().to_string());
+            }
+        }
+        let items = Arc::new(items);
+        self.call(span, move |client, opt| {
+            let (sender, receiver) = client.bulk_upsert_opt(opt)?;
+            let items = items.clone();
+            let rows =
+                (0..items.len()).map(move |i| (items[i].clone(), grpcio::WriteFlags::default()));
+            Ok(sender
+                .send_all(stream::iter_ok::<_, grpcio::Error>(rows))
+                .and_then(|_| receiver))
+        })
+    }
+
+    /// Applies the operations of `batch` atomically, either every one or
+    /// none. Like [`set_item`](#method.set_item), the call is retried with
+    /// the idempotency key of `batch` or a generated one.
+    pub fn commit(
+        &self,
+        span: &trace::SpanContext,
+        mut batch: proto::Batch,
+    ) -> ClientFuture<proto::BatchResult> {
+        if batch.get_idempotency_key().is_empty() {
+            batch.set_idempotency_key(uuid::Uuid::new_v4().to_string());
+        }
+        self.call(span, move |client, opt| {
+            client.commit_async_opt(&batch, opt)
+        })
+    }
+
+    /// Moves units of a product between two stores atomically, or sends
+    /// them in transit if `req` asks to. Like [`set_item`](#method.set_item),
+    /// the call is retried with the idempotency key of `req` or a generated
+    /// one.
+    pub fn transfer_stock(
+        &self,
+        span: &trace::SpanContext,
+        mut req: proto::TransferRequest,
+    ) -> ClientFuture<proto::TransferResponse> {
+        if req.get_idempotency_key().is_empty() {
+            req.set_idempotency_key(uuid::Uuid::new_v4().to_string());
+        }
+        self.call(span, move |client, opt| {
+            client.transfer_stock_async_opt(&req, opt)
+        })
+    }
+
+    /// Adds the units of an in-transit transfer to its destination store,
+    /// retried like [`transfer_stock`](#method.transfer_stock).
+    pub fn receive_transfer(
+        &self,
+        span: &trace::SpanContext,
+        mut req: proto::ReceiveTransferRequest,
+    ) -> ClientFuture<proto::TransferResponse> {
+        if req.get_idempotency_key().is_empty() {
+            req.set_idempotency_key(uuid::Uuid::new_v4().to_string());
+        }
+        self.call(span, move |client, opt| {
+            client.receive_transfer_async_opt(&req, opt)
+        })
+    }
+
+    /// Lists the changes of the stock history matching `req`, oldest first.
+    pub fn get_history(
+        &self,
+        span: &trace::SpanContext,
+        req: proto::HistoryRequest,
+    ) -> ClientFuture<proto::HistoryResponse> {
+        self.call(span, move |client, opt| {
+            client.get_history_async_opt(&req, opt)
+        })
+    }
+
+    /// Runs the call started by `f` with the client token and timeout,
+    /// retrying it according to the client retry policy.
+    fn call<T, F, R>(&self, span: &trace::SpanContext, f: F) -> ClientFuture<T>
+    where
+        T: Send + 'static,
+        F: Fn(&proto::StockClient, grpcio::CallOption) -> grpcio::Result<R> + Send + 'static,
+        R: Future<Item = T, Error = grpcio::Error> + Send + 'static,
+    {
+        let client = self.client.clone();
+        let traceparent = span.to_traceparent();
+        let token = self.token.clone();
+        let timeout = self.timeout;
+        self.retry.run(move |_| -> ClientFuture<T> {
+            // Metadata can't be sent across threads, so the call options
+            // are built again on every attempt.
+            let opt = match call_option(&traceparent, token.as_deref(), timeout) {
+                Ok(opt) => opt,
+                Err(err) => return Box::new(future::err(err)),
+            };
+            Box::new(f(&client, opt).into_future().flatten().map_err(Error::from))
+        })
+    }
+}
+
+type CacheKey = (proto::Store, bool);
+
+enum Lookup {
+    Fresh(proto::StockResponse),
+    Stale(String),
+    Miss,
+}
+
+struct CacheEntry {
+    resp: proto::StockResponse,
+    fetched: time::Instant,
+}
+
+/// `Cache` holds the last response of every request shape, along with the
+/// time it was fetched or revalidated.
+struct Cache {
+    ttl: time::Duration,
+    entries: Mutex<collections::HashMap<CacheKey, CacheEntry>>,
+}
+
+impl Cache {
+    fn new(ttl: time::Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(collections::HashMap::new()),
+        }
+    }
+
+    fn lookup(&self, key: CacheKey) -> Lookup {
+        let entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(_) => return Lookup::Miss,
+        };
+        match entries.get(&key) {
+            Some(entry) if entry.fetched.elapsed() < self.ttl => Lookup::Fresh(entry.resp.clone()),
+            Some(entry) => Lookup::Stale(String::from(entry.resp.get_version())),
+            None => Lookup::Miss,
+        }
+    }
+
+    /// Stores `resp`, or returns the cached items if the server replied
+    /// they were not modified.
+    fn update(&self, key: CacheKey, resp: proto::StockResponse) -> proto::StockResponse {
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(_) => return resp,
+        };
+        if resp.get_not_modified() {
+            if let Some(entry) = entries.get_mut(&key) {
+                entry.fetched = time::Instant::now();
+                let mut cached = entry.resp.clone();
+                cached.set_request_id(String::from(resp.get_request_id()));
+                return cached;
+            }
+            return resp;
+        }
+        if !resp.get_version().is_empty() {
+            entries.insert(
+                key,
+                CacheEntry {
+                    resp: resp.clone(),
+                    fetched: time::Instant::now(),
+                },
+            );
+        }
+        resp
+    }
+}
+
+fn call_option(
+    traceparent: &str,
+    token: Option<&str>,
+    timeout: time::Duration,
+) -> Result<grpcio::CallOption, Error> {
+    let mut headers = grpcio::MetadataBuilder::with_capacity(2);
+    headers.add_str(trace::TRACEPARENT_HEADER, traceparent)?;
+    if let Some(token) = token {
+        headers.add_str(AUTHORIZATION_HEADER, &format!("Bearer {}", token))?;
+    }
+    Ok(grpcio::CallOption::default()
+        .headers(headers.build())
+        .timeout(timeout))
+}
+
+/// Parses a quantity, from zero up to the largest one the server holds. It's
+/// meant to be used as a `structopt` parser.
+pub fn parse_quantity(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(quantity) if quantity <= i32::MAX as u32 => Ok(quantity),
+        _ => Err(format!(
+            "Invalid quantity {:?}, expected a number from 0 to {}",
+            s,
+            i32::MAX
+        )),
+    }
+}
+
+/// Parses a product version, from zero up to the largest one the server
+/// sends. It's meant to be used as a `structopt` parser.
+pub fn parse_version(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(version) if version <= i64::MAX as u64 => Ok(version),
+        _ => Err(format!(
+            "Invalid version {:?}, expected a number from 0 to {}",
+            s,
+            i64::MAX
+        )),
+    }
+}
+
+/// Parses a number of seconds such as `5` or `0.5` into a `Duration`. It's
+/// meant to be used as a `structopt` parser.
+pub fn parse_seconds(s: &str) -> Result<time::Duration, String> {
+    match s.parse::<f64>() {
+        Ok(secs) if secs > 0.0 && secs.is_finite() && secs < u32::MAX as f64 => {
+            Ok(time::Duration::from_secs_f64(secs))
+        }
+        _ => Err(format!(
+            "Invalid duration {:?}, expected a positive number of seconds",
+            s
+        )),
+    }
+}
+
+/// Returns `time` in Unix milliseconds, as sent on the requests, or zero
+/// if it's before the epoch.
+pub fn unix_millis(time: time::SystemTime) -> i64 {
+    time.duration_since(time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as i64)
+}
+
+/// Parses a point in time given either as an RFC 3339 date, e.g.
+/// `2020-09-13T12:00:00Z`, the same without seconds, e.g.
+/// `2020-09-13T12:00Z`, a UTC day such as `2020-09-13`, or a duration before
+/// now made of a number and a unit: `s`, `m`, `h` or `d`, e.g. `90m`.
+pub fn parse_time(s: &str) -> Result<time::SystemTime, String> {
+    let invalid = || {
+        format!(
+            "Invalid time {:?}, expected an RFC 3339 date or a duration ago such as 90m or 2d",
+            s
+        )
+    };
+    let date = chrono::DateTime::parse_from_rfc3339(s)
+        .or_else(|e| match (s.get(..16), s.get(16..)) {
+            (Some(minutes), Some(offset)) => {
+                chrono::DateTime::parse_from_rfc3339(&format!("{}:00{}", minutes, offset))
+            }
+            _ => Err(e),
+        })
+        .map(|date| date.timestamp_millis())
+        .or_else(|_| {
+            chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .map(|day| day.and_hms(0, 0, 0).timestamp_millis())
+        });
+    if let Ok(millis) = date {
+        let millis = u64::try_from(millis).map_err(|_| invalid())?;
+        return Ok(time::UNIX_EPOCH + time::Duration::from_millis(millis));
+    }
+    let unit = match s.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    let ago = s[..s.len() - 1]
+        .parse::<u32>()
+        .map(|n| time::Duration::from_secs(u64::from(n) * unit))
+        .map_err(|_| invalid())?;
+    time::SystemTime::now().checked_sub(ago).ok_or_else(invalid)
+}
+
+/// Returns a
+/// [`grpcio::ChannelBuilder`](https://docs.rs/grpcio/0.4.7/grpcio/struct.ChannelBuilder.html)
+/// giving up on a connection attempt after `connect_timeout`, so calls to
+/// an unreachable server fail with `UNAVAILABLE` instead of waiting for
+/// their deadline. The wait between reconnection attempts is left to gRPC,
+/// so retried calls reach a server that comes back.
+fn channel_builder(
+    env: Arc<grpcio::Environment>,
+    connect_timeout: time::Duration,
+) -> grpcio::ChannelBuilder {
+    let millis = cmp::min(connect_timeout.as_millis(), i32::MAX as u128) as i32;
+    grpcio::ChannelBuilder::new(env).raw_cfg_int(
+        ffi::CString::new("grpc.min_reconnect_backoff_ms").unwrap(),
+        millis,
+    )
+}
 
 /// Returns a
 /// [`crate::proto::StockRequest`](../../proto/stock/struct.StockRequest.html)
@@ -12,9 +758,48 @@ pub fn generate_list_request(store: proto::Store, show_unavailable: bool) -> pro
     req
 }
 
+/// Checks `target` is a gRPC target the client can connect to, either
+/// `HOST:PORT` or a URI using the `dns`, `unix`, `ipv4` or `ipv6` scheme,
+/// such as `dns:///localhost:9090` or `unix:/run/stock.sock`. It's meant to
+/// be used as a `structopt` parser.
+pub fn parse_target(target: &str) -> Result<String, String> {
+    const SCHEMES: &[&str] = &["dns:", "unix:", "ipv4:", "ipv6:"];
+    if let Some(scheme) = SCHEMES.iter().find(|s| target.starts_with(*s)) {
+        if target.len() == scheme.len() {
+            return Err(format!("Invalid target {:?}, missing address", target));
+        }
+        return Ok(String::from(target));
+    }
+    if target.contains("://") || !target.contains(':') {
+        return Err(format!(
+            "Invalid target {:?}, expected HOST:PORT, dns:///HOST:PORT or unix:PATH",
+            target
+        ));
+    }
+    Ok(String::from(target))
+}
+
+/// Returns a
+/// [`grpcio::CallOption`](https://docs.rs/grpcio/0.4.7/grpcio/struct.CallOption.html)
+/// carrying the given [`SpanContext`](../../trace/struct.SpanContext.html)
+/// as `traceparent` metadata, so the server joins the caller's trace.
+pub fn trace_call_option(span: &trace::SpanContext) -> grpcio::Result<grpcio::CallOption> {
+    let mut headers = grpcio::MetadataBuilder::with_capacity(1);
+    headers.add_str(trace::TRACEPARENT_HEADER, &span.to_traceparent())?;
+    Ok(grpcio::CallOption::default().headers(headers.build()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Mutex;
+
+    fn unavailable() -> Error {
+        Error::from(grpcio::Error::RpcFailure(grpcio::RpcStatus::new(
+            grpcio::RpcStatusCode::Unavailable,
+            Some(String::from("connection refused")),
+        )))
+    }
 
     #[test]
     fn test_generate_list_request() {
@@ -35,4 +820,235 @@ mod tests {
             false
         );
     }
+
+    #[test]
+    fn test_parse_target() {
+        for target in &[
+            "127.0.0.1:9090",
+            "[::1]:9090",
+            "server:9090",
+            "dns:///server:9090",
+            "unix:/run/stock.sock",
+            "ipv6:[::1]:9090",
+        ] {
+            assert_eq!(parse_target(target).as_deref(), Ok(*target));
+        }
+        assert!(parse_target("server").is_err());
+        assert!(parse_target("http://server:9090").is_err());
+        assert!(parse_target("unix:").is_err());
+    }
+
+    #[test]
+    fn test_parse_quantity() {
+        assert_eq!(parse_quantity("0"), Ok(0));
+        assert_eq!(parse_quantity("2147483647"), Ok(2_147_483_647));
+        assert!(parse_quantity("2147483648").is_err());
+        assert!(parse_quantity("-1").is_err());
+        assert_eq!(
+            parse_version("9223372036854775807"),
+            Ok(9_223_372_036_854_775_807)
+        );
+        assert!(parse_version("9223372036854775808").is_err());
+    }
+
+    #[test]
+    fn test_parse_seconds() {
+        assert_eq!(parse_seconds("5"), Ok(time::Duration::from_secs(5)));
+        assert_eq!(parse_seconds("0.25"), Ok(time::Duration::from_millis(250)));
+        assert!(parse_seconds("0").is_err());
+        assert!(parse_seconds("-1").is_err());
+        assert!(parse_seconds("inf").is_err());
+        assert!(parse_seconds("5s").is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(
+            parse_time("2020-09-13T12:26:40.123Z"),
+            Ok(time::UNIX_EPOCH + time::Duration::from_millis(1_600_000_000_123))
+        );
+        assert_eq!(
+            parse_time("2020-09-13T14:26:40+02:00"),
+            Ok(time::UNIX_EPOCH + time::Duration::from_secs(1_600_000_000))
+        );
+        assert_eq!(
+            parse_time("2020-09-13T12:26Z"),
+            Ok(time::UNIX_EPOCH + time::Duration::from_secs(1_600_000_000 - 40))
+        );
+        assert_eq!(
+            parse_time("2020-09-13"),
+            Ok(time::UNIX_EPOCH + time::Duration::from_secs(1_599_955_200))
+        );
+        let ago = time::SystemTime::now()
+            .duration_since(parse_time("90m").unwrap())
+            .unwrap();
+        assert!(ago >= time::Duration::from_secs(90 * 60));
+        assert!(ago < time::Duration::from_secs(91 * 60));
+        for s in &["", "d", "-1d", "2w", "1.5h", "yesterday", "2020-09-13T12Z"] {
+            assert!(parse_time(s).is_err(), "{:?} was accepted", s);
+        }
+    }
+
+    #[test]
+    fn test_error_from_grpc() {
+        assert!(matches!(unavailable(), Error::Unavailable(ref d) if d == "connection refused"));
+        assert!(unavailable().is_retryable());
+
+        let err = Error::from(grpcio::Error::RpcFailure(grpcio::RpcStatus::new(
+            grpcio::RpcStatusCode::DeadlineExceeded,
+            None,
+        )));
+        assert!(matches!(err, Error::DeadlineExceeded));
+        assert!(!err.is_retryable());
+
+        let err = Error::from(grpcio::Error::RpcFailure(grpcio::RpcStatus::new(
+            grpcio::RpcStatusCode::NotFound,
+            None,
+        )));
+        assert!(matches!(err, Error::Status(_)));
+        assert!(!err.is_retryable());
+
+        let err = Error::from(grpcio::Error::RpcFailure(grpcio::RpcStatus::new(
+            grpcio::RpcStatusCode::Aborted,
+            Some(String::from("changed")),
+        )));
+        assert!(matches!(err, Error::Conflict(ref d) if d == "changed"));
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn test_retry_policy_backoff() {
+        let policy = RetryPolicy {
+            retries: 10,
+            initial_backoff: time::Duration::from_millis(10),
+            max_backoff: time::Duration::from_millis(50),
+        };
+        for attempt in 1..=10 {
+            assert!(policy.backoff(attempt) <= time::Duration::from_millis(50));
+        }
+        assert!(policy.backoff(1) <= time::Duration::from_millis(10));
+        assert!(policy.backoff(u32::MAX) <= time::Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_retry_policy_run() {
+        let policy = RetryPolicy {
+            retries: 2,
+            initial_backoff: time::Duration::from_millis(1),
+            max_backoff: time::Duration::from_millis(1),
+        };
+
+        let attempts = Arc::new(Mutex::new(0));
+        let counter = attempts.clone();
+        let res = policy
+            .run(move |_| -> Result<(), Error> {
+                *counter.lock().unwrap() += 1;
+                Err(unavailable())
+            })
+            .wait();
+        assert!(matches!(res, Err(Error::Unavailable(_))));
+        assert_eq!(*attempts.lock().unwrap(), 3);
+
+        let res = policy
+            .run(|attempt| {
+                if attempt < 2 {
+                    Err(unavailable())
+                } else {
+                    Ok(attempt)
+                }
+            })
+            .wait();
+        assert_eq!(res.ok(), Some(2));
+
+        let attempts = Arc::new(Mutex::new(0));
+        let counter = attempts.clone();
+        let res = policy
+            .run(move |_| -> Result<(), Error> {
+                *counter.lock().unwrap() += 1;
+                Err(Error::DeadlineExceeded)
+            })
+            .wait();
+        assert!(res.is_err());
+        assert_eq!(*attempts.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_stock_client_builder_invalid() {
+        assert!(matches!(
+            StockClient::builder("server").build(),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            StockClient::builder("server:9090")
+                .token("not valid")
+                .build(),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            StockClient::builder("server:9090")
+                .tls_server_name("stock.internal")
+                .build(),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_cache() {
+        let key = (proto::Store::BERLIN_DE, false);
+        let cache = Cache::new(time::Duration::from_millis(50));
+        assert!(matches!(cache.lookup(key), Lookup::Miss));
+
+        let mut resp = proto::StockResponse::new();
+        resp.set_version(String::from("v1"));
+        resp.set_items(protobuf::RepeatedField::from_vec(vec![proto::Item::new()]));
+        resp.set_request_id(String::from("request-1"));
+        cache.update(key, resp.clone());
+        assert!(matches!(cache.lookup(key), Lookup::Fresh(ref r) if *r == resp));
+        assert!(matches!(
+            cache.lookup((proto::Store::BERLIN_DE, true)),
+            Lookup::Miss
+        ));
+
+        thread::sleep(time::Duration::from_millis(60));
+        assert!(matches!(cache.lookup(key), Lookup::Stale(ref v) if v == "v1"));
+
+        let mut not_modified = proto::StockResponse::new();
+        not_modified.set_version(String::from("v1"));
+        not_modified.set_not_modified(true);
+        not_modified.set_request_id(String::from("request-2"));
+        let revalidated = cache.update(key, not_modified);
+        assert_eq!(revalidated.get_items().len(), 1);
+        assert_eq!(revalidated.get_request_id(), "request-2");
+        assert!(matches!(cache.lookup(key), Lookup::Fresh(_)));
+    }
+
+    #[test]
+    fn test_call_option() {
+        let span = trace::SpanContext::generate();
+        let opt = call_option(
+            &span.to_traceparent(),
+            Some("secret"),
+            time::Duration::from_secs(1),
+        )
+        .expect("Could not build call option");
+        assert_eq!(opt.get_timeout(), Some(time::Duration::from_secs(1)));
+
+        let headers = opt.get_headers().expect("Could not recover headers");
+        assert_eq!(headers.len(), 2);
+        let (key, value) = headers.get(1).expect("Could not recover authorization");
+        assert_eq!(key, AUTHORIZATION_HEADER);
+        assert_eq!(value, b"Bearer secret");
+    }
+
+    #[test]
+    fn test_trace_call_option() {
+        let span = trace::SpanContext::generate();
+        let opt = trace_call_option(&span).expect("Could not build call option");
+        let headers = opt.get_headers().expect("Could not recover headers");
+
+        assert_eq!(headers.len(), 1);
+        let (key, value) = headers.get(0).expect("Could not recover traceparent");
+        assert_eq!(key, trace::TRACEPARENT_HEADER);
+        assert_eq!(value, span.to_traceparent().as_bytes());
+    }
 }
@@ -0,0 +1,107 @@
+use std::sync::{atomic, Arc};
+
+use futures::Future;
+use slog::Logger;
+
+use crate::proto;
+
+/// Name the stock service is registered with, as used by health checks.
+pub const STOCK_SERVICE_NAME: &str = "generated.Stock";
+
+/// `Health` implements the standard
+/// [gRPC health checking protocol](https://github.com/grpc/grpc/blob/master/doc/health-checking.md)
+/// for the server and the stock service.
+///
+/// Clones share the same status, so the server can be flipped to
+/// `NOT_SERVING` while shutting down after the service is registered.
+#[derive(Clone)]
+pub struct Health {
+    serving: Arc<atomic::AtomicBool>,
+    log: Logger,
+}
+
+impl Health {
+    /// Returns a `Health` instance reporting `SERVING` given a
+    /// [slog::Logger](https://docs.rs/slog/2.5.2/slog/struct.Logger.html)
+    /// .
+    pub fn new(log: Logger) -> Self {
+        Self {
+            serving: Arc::new(atomic::AtomicBool::new(true)),
+            log,
+        }
+    }
+
+    /// Sets whether the server is accepting requests.
+    pub fn set_serving(&self, serving: bool) {
+        self.serving.store(serving, atomic::Ordering::SeqCst);
+    }
+
+    /// Returns the status of `service`, where an empty name stands for the
+    /// whole server. Returns `None` for unknown services.
+    pub fn status(&self, service: &str) -> Option<proto::ServingStatus> {
+        match service {
+            "" | STOCK_SERVICE_NAME => {
+                if self.serving.load(atomic::Ordering::SeqCst) {
+                    Some(proto::ServingStatus::SERVING)
+                } else {
+                    Some(proto::ServingStatus::NOT_SERVING)
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl proto::HealthService for Health {
+    fn check(
+        &mut self,
+        ctx: grpcio::RpcContext,
+        req: proto::HealthCheckRequest,
+        sink: grpcio::UnarySink<proto::HealthCheckResponse>,
+    ) {
+        let log = self.log.clone();
+        let f = match self.status(req.get_service()) {
+            Some(status) => {
+                trace!(
+                    self.log,
+                    "Health check for {:?}: {:?}",
+                    req.get_service(),
+                    status
+                );
+                let mut resp = proto::HealthCheckResponse::new();
+                resp.set_status(status);
+                sink.success(resp)
+            }
+            None => sink.fail(grpcio::RpcStatus::new(
+                grpcio::RpcStatusCode::NotFound,
+                Some(format!("unknown service {:?}", req.get_service())),
+            )),
+        };
+        ctx.spawn(
+            f.map_err(move |err| error!(log, "Error while replying health check: {:?}", err)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_status() {
+        let health = Health::new(crate::tests::log::new());
+        assert_eq!(health.status(""), Some(proto::ServingStatus::SERVING));
+        assert_eq!(
+            health.status(STOCK_SERVICE_NAME),
+            Some(proto::ServingStatus::SERVING)
+        );
+        assert_eq!(health.status("unknown.Service"), None);
+
+        health.clone().set_serving(false);
+        assert_eq!(health.status(""), Some(proto::ServingStatus::NOT_SERVING));
+        assert_eq!(
+            health.status(STOCK_SERVICE_NAME),
+            Some(proto::ServingStatus::NOT_SERVING)
+        );
+    }
+}
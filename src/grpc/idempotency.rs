@@ -0,0 +1,274 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time;
+
+/// Metadata key carrying the idempotency key of a write call, used when the
+/// request doesn't set one.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Maximum length of an idempotency key.
+pub const MAX_KEY_LEN: usize = 128;
+
+/// Default maximum number of responses kept.
+pub const DEFAULT_MAX_ENTRIES: usize = 100_000;
+
+// Actor, method and key of a call
+type Key = (String, &'static str, String);
+
+enum State {
+    InProgress,
+    Done { fingerprint: u64, response: Vec<u8> },
+}
+
+#[derive(Default)]
+struct Entries {
+    states: HashMap<Key, State>,
+    // Completed keys in completion order, so the oldest expire first
+    expiries: VecDeque<(time::Instant, Key)>,
+}
+
+impl Entries {
+    fn evict(&mut self, max_entries: usize) {
+        while self.expiries.len() > max_entries {
+            if let Some((_, key)) = self.expiries.pop_front() {
+                self.states.remove(&key);
+            }
+        }
+    }
+
+    fn purge(&mut self, now: time::Instant) {
+        while let Some((expiry, _)) = self.expiries.front() {
+            if *expiry > now {
+                break;
+            }
+            if let Some((_, key)) = self.expiries.pop_front() {
+                self.states.remove(&key);
+            }
+        }
+    }
+}
+
+/// `Idempotency` keeps the responses of the write calls sent with an
+/// idempotency key for a window of time, so a call sent again with the
+/// same key gets the original response instead of being applied twice.
+/// Keys are scoped by actor and method. Past a maximum number of responses
+/// the oldest ones are dropped early.
+///
+/// Clones share the same responses.
+#[derive(Clone)]
+pub struct Idempotency {
+    window: time::Duration,
+    max_entries: usize,
+    entries: Arc<Mutex<Entries>>,
+}
+
+/// `Begin` is the outcome of [`Idempotency::begin`](struct.Idempotency.html#method.begin).
+pub enum Begin {
+    /// The call must run, and its response be recorded with the
+    /// [`Pending`](struct.Pending.html) guard.
+    Run(Pending),
+    /// A call with the same key already completed.
+    Replay {
+        /// Fingerprint of the request of the completed call.
+        fingerprint: u64,
+        /// Encoded response of the completed call.
+        response: Vec<u8>,
+    },
+    /// A call with the same key is still running.
+    InProgress,
+}
+
+impl Idempotency {
+    /// Returns an `Idempotency` keeping the responses for `window`. A zero
+    /// window keeps none, so every call runs.
+    pub fn new(window: time::Duration) -> Self {
+        Self {
+            window,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            entries: Arc::new(Mutex::new(Entries::default())),
+        }
+    }
+
+    /// Keeps at most `max_entries` responses, dropping the oldest ones
+    /// first. By default it keeps
+    /// [`DEFAULT_MAX_ENTRIES`](constant.DEFAULT_MAX_ENTRIES.html).
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Starts a call of `method` by `actor` sent with `key`. Calls without
+    /// a key always run.
+    pub fn begin(&self, actor: &str, method: &'static str, key: &str) -> Begin {
+        if key.is_empty() || self.window == time::Duration::from_secs(0) {
+            return Begin::Run(Pending { call: None });
+        }
+        let key = (String::from(actor), method, String::from(key));
+        let mut entries = self.lock();
+        entries.purge(time::Instant::now());
+        match entries.states.get(&key) {
+            Some(State::InProgress) => Begin::InProgress,
+            Some(State::Done {
+                fingerprint,
+                response,
+            }) => Begin::Replay {
+                fingerprint: *fingerprint,
+                response: response.clone(),
+            },
+            None => {
+                entries.states.insert(key.clone(), State::InProgress);
+                Begin::Run(Pending {
+                    call: Some((self.clone(), key)),
+                })
+            }
+        }
+    }
+
+    // Entries are only inserted and removed whole, so a panic while holding
+    // the lock can't leave them inconsistent.
+    fn lock(&self) -> MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for Idempotency {
+    fn default() -> Self {
+        Self::new(time::Duration::from_secs(0))
+    }
+}
+
+/// `Pending` marks a call sent with an idempotency key as in progress. If
+/// it's dropped without being completed, because the call failed, the key
+/// can be used again.
+pub struct Pending {
+    call: Option<(Idempotency, Key)>,
+}
+
+impl Pending {
+    /// Records the encoded `response` of the call, and the `fingerprint`
+    /// of its request, for the idempotency window.
+    pub fn complete(mut self, fingerprint: u64, response: Vec<u8>) {
+        if let Some((idempotency, key)) = self.call.take() {
+            let mut entries = idempotency.lock();
+            let expiry = time::Instant::now() + idempotency.window;
+            entries.expiries.push_back((expiry, key.clone()));
+            entries.states.insert(
+                key,
+                State::Done {
+                    fingerprint,
+                    response,
+                },
+            );
+            entries.evict(idempotency.max_entries);
+        }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if let Some((idempotency, key)) = self.call.take() {
+            idempotency.lock().states.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(begin: Begin) -> Pending {
+        match begin {
+            Begin::Run(pending) => pending,
+            _ => panic!("call did not run"),
+        }
+    }
+
+    #[test]
+    fn test_idempotency() {
+        let idempotency = Idempotency::new(time::Duration::from_secs(60));
+        let pending = run(idempotency.begin("alice", "SetItem", "key-1"));
+        assert!(matches!(
+            idempotency.begin("alice", "SetItem", "key-1"),
+            Begin::InProgress
+        ));
+        run(idempotency.begin("bob", "SetItem", "key-1"));
+        run(idempotency.begin("alice", "BulkUpsert", "key-1"));
+
+        pending.complete(7, vec![1, 2]);
+        match idempotency.begin("alice", "SetItem", "key-1") {
+            Begin::Replay {
+                fingerprint,
+                response,
+            } => assert_eq!((fingerprint, response), (7, vec![1, 2])),
+            _ => panic!("call was not replayed"),
+        }
+
+        // Failed calls can be sent again
+        drop(run(idempotency.begin("alice", "SetItem", "key-2")));
+        run(idempotency.begin("alice", "SetItem", "key-2"));
+
+        // Calls without a key always run
+        run(idempotency.begin("alice", "SetItem", "")).complete(0, vec![]);
+        run(idempotency.begin("alice", "SetItem", ""));
+    }
+
+    #[test]
+    fn test_idempotency_window() {
+        let idempotency = Idempotency::new(time::Duration::from_millis(10));
+        run(idempotency.begin("alice", "SetItem", "key-1")).complete(7, vec![]);
+        assert!(matches!(
+            idempotency.begin("alice", "SetItem", "key-1"),
+            Begin::Replay { .. }
+        ));
+        std::thread::sleep(time::Duration::from_millis(20));
+        run(idempotency.begin("alice", "SetItem", "key-1"));
+        assert!(idempotency.lock().expiries.is_empty());
+
+        let disabled = Idempotency::default();
+        run(disabled.begin("alice", "SetItem", "key-1")).complete(7, vec![]);
+        run(disabled.begin("alice", "SetItem", "key-1"));
+    }
+
+    #[test]
+    fn test_idempotency_max_entries() {
+        let idempotency = Idempotency::new(time::Duration::from_secs(60)).with_max_entries(2);
+        for key in &["key-1", "key-2", "key-3"] {
+            run(idempotency.begin("alice", "SetItem", key)).complete(7, vec![]);
+        }
+        let pending = run(idempotency.begin("alice", "SetItem", "key-1"));
+        for key in &["key-2", "key-3"] {
+            assert!(matches!(
+                idempotency.begin("alice", "SetItem", key),
+                Begin::Replay { .. }
+            ));
+        }
+        pending.complete(8, vec![3]);
+
+        // key-2 was the oldest response, the newer ones are still replayed
+        match idempotency.begin("alice", "SetItem", "key-1") {
+            Begin::Replay {
+                fingerprint,
+                response,
+            } => assert_eq!((fingerprint, response), (8, vec![3])),
+            _ => panic!("call was not replayed"),
+        }
+        assert!(matches!(
+            idempotency.begin("alice", "SetItem", "key-3"),
+            Begin::Replay { .. }
+        ));
+        let _pending = run(idempotency.begin("alice", "SetItem", "key-2"));
+        assert_eq!(idempotency.lock().states.len(), 3);
+
+        // Calls in progress don't count towards the maximum
+        let in_progress: Vec<Pending> = (0..5)
+            .map(|i| run(idempotency.begin("alice", "SetItem", &format!("other-{}", i))))
+            .collect();
+        for key in &["key-1", "key-3"] {
+            assert!(matches!(
+                idempotency.begin("alice", "SetItem", key),
+                Begin::Replay { .. }
+            ));
+        }
+        drop(in_progress);
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::{atomic, Arc};
+
+/// `ConcurrencyLimit` caps the number of calls served at the same time.
+///
+/// Clones share the same counter, so a single limit applies to every
+/// completion queue the service is cloned into.
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimit {
+    in_flight: Arc<atomic::AtomicUsize>,
+    max: usize,
+}
+
+impl ConcurrencyLimit {
+    /// Returns a `ConcurrencyLimit` allowing up to `max` calls at the same
+    /// time. Zero means unlimited.
+    pub fn new(max: usize) -> Self {
+        Self {
+            in_flight: Arc::new(atomic::AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Returns a [`Permit`](struct.Permit.html) if there is room for one
+    /// more call, or `None` if the limit is reached. The call counts as in
+    /// flight until the permit is dropped.
+    pub fn acquire(&self) -> Option<Permit> {
+        let in_flight = self.in_flight.fetch_add(1, atomic::Ordering::SeqCst);
+        if self.max != 0 && in_flight >= self.max {
+            self.in_flight.fetch_sub(1, atomic::Ordering::SeqCst);
+            return None;
+        }
+        Some(Permit {
+            in_flight: self.in_flight.clone(),
+        })
+    }
+
+    /// Returns the number of calls currently in flight.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(atomic::Ordering::SeqCst)
+    }
+}
+
+/// `Permit` marks a call as in flight until it's dropped.
+#[derive(Debug)]
+pub struct Permit {
+    in_flight: Arc<atomic::AtomicUsize>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrency_limit() {
+        let limit = ConcurrencyLimit::new(2);
+        let first = limit.acquire().expect("Could not acquire first permit");
+        let second = limit
+            .clone()
+            .acquire()
+            .expect("Could not acquire second permit");
+        assert!(limit.acquire().is_none());
+        assert_eq!(limit.in_flight(), 2);
+
+        drop(first);
+        assert_eq!(limit.in_flight(), 1);
+        assert!(limit.acquire().is_some());
+
+        drop(second);
+        assert_eq!(limit.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_concurrency_limit_unlimited() {
+        let limit = ConcurrencyLimit::new(0);
+        let permits: Vec<_> = (0..100).filter_map(|_| limit.acquire()).collect();
+        assert_eq!(permits.len(), 100);
+    }
+}
@@ -1,10 +1,56 @@
-use futures::Future;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::{mem, str, time};
+
+use futures::{future, Future, Stream};
 use slog::Logger;
 
-use super::{proto, Stock};
+use super::{
+    proto, trace, AsOfError, Change, CommitError, Context, HistoryEntry, HistoryFilter, Operation,
+    Product, Stock, Transfer, TransferError, Update, VersionError,
+};
 
-/// `client` hold the logic for the client generate gRPC requests.
+/// `auth` maps the bearer tokens of the calls to actors.
+pub mod auth;
+/// `client` holds the typed [`StockClient`](client/struct.StockClient.html)
+/// used to call the stock service.
 pub mod client;
+/// `health` implements the gRPC health checking service.
+pub mod health;
+/// `idempotency` keeps the responses of the write calls sent with an
+/// idempotency key.
+pub mod idempotency;
+/// `limit` bounds the number of calls served at the same time.
+pub mod limit;
+
+/// Metadata key used to correlate a call across services. If the caller
+/// does not send it, the server generates one.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Number of valid items of a `BulkUpsert` stream applied together.
+const UPSERT_BATCH_SIZE: usize = 500;
+
+fn header<'a>(headers: &'a grpcio::Metadata, key: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| *k == key)
+        .and_then(|(_, value)| str::from_utf8(value).ok())
+}
+
+fn request_id(headers: &grpcio::Metadata) -> String {
+    match header(headers, REQUEST_ID_HEADER) {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.bytes().all(|b| b.is_ascii_graphic()) =>
+        {
+            String::from(id)
+        }
+        _ => uuid::Uuid::new_v4().to_string(),
+    }
+}
 
 /// `Handler` is the gRPC interface responsible for receiving requests and
 ///  executing a [`Stock`](../trait.Stock.html) implementation.
@@ -17,6 +63,7 @@ where
     S: Stock + Send + Clone + 'static,
 {
     stock: S,
+    idempotency: idempotency::Idempotency,
     log: Logger,
 }
 
@@ -29,19 +76,759 @@ where
     /// [slog::Logger](https://docs.rs/slog/2.5.2/slog/struct.Logger.html)
     /// .
     pub fn new(stock: S, log: Logger) -> Self {
-        Self { stock, log }
+        Self {
+            stock,
+            idempotency: idempotency::Idempotency::default(),
+            log,
+        }
     }
 
-    fn handle(&self, req: proto::StockRequest) -> proto::StockResponse {
-        trace!(self.log, "Starting to handle request");
+    /// Replays the responses kept by `idempotency` to the write calls sent
+    /// again with the same idempotency key. By default no response is
+    /// kept.
+    pub fn with_idempotency(mut self, idempotency: idempotency::Idempotency) -> Self {
+        self.idempotency = idempotency;
+        self
+    }
+
+    fn handle(&self, ctx: &Context, req: proto::StockRequest) -> proto::StockResponse {
+        let ctx = ctx.child("Handler::handle");
+        let log = ctx.logger(&self.log);
+        trace!(log, "Starting to handle request");
         let mut resp = proto::StockResponse::new();
-        resp.set_items(protobuf::RepeatedField::from_vec(
-            self.stock
-                .get(req.get_store(), req.get_display_unavailable_items()),
-        ));
-        trace!(self.log, "Finishing request");
+        resp.set_request_id(String::from(ctx.request_id()));
+        if let Some(version) = self.stock.version(req.get_store()) {
+            if !req.get_if_none_match().is_empty() && req.get_if_none_match() == version {
+                debug!(log, "Store {:?} not modified", req.get_store(); "version" => &version);
+                resp.set_version(version);
+                resp.set_not_modified(true);
+                return resp;
+            }
+            resp.set_version(version);
+        }
+        resp.set_items(protobuf::RepeatedField::from_vec(self.stock.get(
+            &ctx,
+            req.get_store(),
+            req.get_display_unavailable_items(),
+        )));
+        trace!(log, "Finishing request");
+        resp
+    }
+
+    /// Answers a `ListItems` call with `as_of` set from the stock history.
+    fn handle_as_of(
+        &self,
+        ctx: &Context,
+        req: proto::StockRequest,
+    ) -> Result<proto::StockResponse, grpcio::RpcStatus> {
+        let ctx = ctx.child("Handler::handle_as_of");
+        let as_of = from_unix_millis(req.get_as_of()).ok_or_else(|| {
+            grpcio::RpcStatus::new(
+                grpcio::RpcStatusCode::InvalidArgument,
+                Some(String::from("as_of must be a positive Unix time")),
+            )
+        })?;
+        let items = self
+            .stock
+            .get_as_of(
+                &ctx,
+                req.get_store(),
+                req.get_display_unavailable_items(),
+                as_of,
+            )
+            .map_err(|err| {
+                let code = match err {
+                    AsOfError::Unsupported => grpcio::RpcStatusCode::Unimplemented,
+                    AsOfError::Forgotten(_) => grpcio::RpcStatusCode::OutOfRange,
+                };
+                grpcio::RpcStatus::new(code, Some(err.to_string()))
+            })?;
+        let mut resp = proto::StockResponse::new();
+        resp.set_request_id(String::from(ctx.request_id()));
+        resp.set_items(protobuf::RepeatedField::from_vec(items));
+        Ok(resp)
+    }
+
+    fn handle_stores(&self, ctx: &Context) -> proto::StoresResponse {
+        let ctx = ctx.child("Handler::handle_stores");
+        let mut resp = proto::StoresResponse::new();
+        resp.set_request_id(String::from(ctx.request_id()));
+        resp.set_stores(self.stock.stores());
         resp
     }
+
+    fn handle_set(
+        &self,
+        ctx: &Context,
+        mut req: proto::SetItemRequest,
+    ) -> Result<proto::SetItemResponse, grpcio::RpcStatus> {
+        let ctx = ctx
+            .child("Handler::handle_set")
+            .with_reason(req.get_reason());
+        let invalid =
+            |reason| grpcio::RpcStatus::new(grpcio::RpcStatusCode::InvalidArgument, Some(reason));
+        let quantity = validate(req.get_name(), req.get_quantity()).map_err(invalid)?;
+        let expected_version = validate_version(req.get_expected_version()).map_err(invalid)?;
+        let key = req.take_idempotency_key();
+        validate_key(&key)?;
+        let mut hasher = DefaultHasher::new();
+        fingerprint(&mut hasher, &req);
+        let pending = match self.idempotency.begin(ctx.actor(), "SetItem", &key) {
+            idempotency::Begin::Run(pending) => pending,
+            idempotency::Begin::Replay {
+                fingerprint,
+                response,
+            } => {
+                let log = ctx.logger(&self.log);
+                debug!(log, "Replaying SetItem"; "idempotency_key" => &key);
+                return replay(&key, hasher.finish(), fingerprint, &response);
+            }
+            idempotency::Begin::InProgress => return Err(in_progress(&key)),
+        };
+        let product = Product::new(req.get_name(), req.get_brand(), req.get_category());
+        let update = Update {
+            store: req.get_store(),
+            product: product.clone(),
+            quantity: Some(quantity),
+            expected_version,
+        };
+        let (previous, version) = match self.stock.update_many(&ctx, vec![update]) {
+            Ok(mut results) => results.remove(0),
+            Err(mut conflicts) => {
+                let (_, err) = conflicts.remove(0);
+                return Err(conflict(req.get_store(), &product, err));
+            }
+        };
+
+        let mut item = product.item(quantity);
+        item.set_version(version as i64);
+        let mut resp = proto::SetItemResponse::new();
+        resp.set_request_id(String::from(ctx.request_id()));
+        resp.set_item(item);
+        resp.set_created(previous.is_none());
+        resp.set_previous_quantity(previous.unwrap_or_default() as i32);
+        pending.complete(hasher.finish(), encode(&resp));
+        Ok(resp)
+    }
+
+    /// Adds `item`, the next one of a `BulkUpsert` stream, to `upserts`.
+    /// Invalid items are recorded as failures, and the valid ones are
+    /// applied in batches unless the stream is all-or-nothing. Streams
+    /// sent again with an idempotency key apply none.
+    fn handle_upsert(&self, ctx: &Context, upserts: &mut Upserts, mut item: proto::UpsertItem) {
+        let index = upserts.summary.get_received();
+        if index == 0 {
+            upserts.all_or_nothing = item.get_all_or_nothing();
+            upserts.reason = String::from(item.get_reason());
+            if upserts.key.is_empty() {
+                upserts.key = String::from(item.get_idempotency_key());
+            }
+            let begin = validate_key(&upserts.key).map(|_| {
+                self.idempotency
+                    .begin(ctx.actor(), "BulkUpsert", &upserts.key)
+            });
+            match begin {
+                Ok(idempotency::Begin::Run(pending)) => upserts.idempotent = Some(pending),
+                Ok(idempotency::Begin::Replay {
+                    fingerprint,
+                    response,
+                }) => upserts.replay = Some((fingerprint, response)),
+                Ok(idempotency::Begin::InProgress) => {
+                    upserts.error = Some(in_progress(&upserts.key))
+                }
+                Err(status) => upserts.error = Some(status),
+            }
+        }
+        upserts.summary.set_received(index + 1);
+        if !upserts.key.is_empty() {
+            item.clear_idempotency_key();
+            fingerprint(&mut upserts.fingerprint, &item);
+        }
+        if upserts.replay.is_some() || upserts.error.is_some() {
+            return;
+        }
+        // The quantity of removals is ignored
+        let quantity = if item.get_remove() {
+            validate(item.get_name(), 0).map(|_| None)
+        } else {
+            validate(item.get_name(), item.get_quantity()).map(Some)
+        };
+        let update = quantity.and_then(|quantity| {
+            Ok(Update {
+                store: item.get_store(),
+                product: Product::new(item.get_name(), item.get_brand(), item.get_category()),
+                quantity,
+                expected_version: validate_version(item.get_expected_version())?,
+            })
+        });
+        match update {
+            Ok(update) => upserts.pending.push((index, update)),
+            Err(reason) => {
+                let mut failure = proto::UpsertFailure::new();
+                failure.set_index(index);
+                failure.set_reason(reason);
+                upserts.summary.mut_failures().push(failure);
+            }
+        }
+        if !upserts.all_or_nothing && upserts.pending.len() >= UPSERT_BATCH_SIZE {
+            self.apply_upserts(ctx, upserts);
+        }
+    }
+
+    /// Applies the items left in `upserts` at the end of the stream and
+    /// returns its summary. An all-or-nothing stream with failures,
+    /// version conflicts included, applies none, and a stream sent again
+    /// with an idempotency key gets the original summary.
+    fn finish_upserts(
+        &self,
+        ctx: &Context,
+        mut upserts: Upserts,
+    ) -> Result<proto::BulkUpsertSummary, grpcio::RpcStatus> {
+        let log = ctx.logger(&self.log);
+        if let Some(status) = upserts.error.take() {
+            return Err(status);
+        }
+        if let Some((fingerprint, response)) = upserts.replay.take() {
+            debug!(log, "Replaying BulkUpsert"; "idempotency_key" => &upserts.key);
+            return replay(
+                &upserts.key,
+                upserts.fingerprint.finish(),
+                fingerprint,
+                &response,
+            );
+        }
+        if upserts.all_or_nothing && !upserts.summary.get_failures().is_empty() {
+            debug!(log, "Discarding {} upserts", upserts.pending.len();
+                "failures" => upserts.summary.get_failures().len(),
+            );
+        } else {
+            let applied = self.apply_upserts(ctx, &mut upserts);
+            upserts.summary.set_applied(applied);
+        }
+        // Version conflicts are only found once their batch is applied
+        upserts
+            .summary
+            .mut_failures()
+            .sort_by_key(|failure| failure.index);
+        upserts
+            .summary
+            .set_request_id(String::from(ctx.request_id()));
+        if let Some(pending) = upserts.idempotent.take() {
+            pending.complete(upserts.fingerprint.finish(), encode(&upserts.summary));
+        }
+        Ok(upserts.summary)
+    }
+
+    /// Applies the pending items of `upserts` and returns whether they
+    /// were. Items whose version conflicts are recorded as failures: an
+    /// all-or-nothing stream then applies none, while the other streams
+    /// apply the remaining items.
+    fn apply_upserts(&self, ctx: &Context, upserts: &mut Upserts) -> bool {
+        let ctx = ctx
+            .child("Handler::apply_upserts")
+            .with_reason(&upserts.reason);
+        let mut rows = mem::take(&mut upserts.pending);
+        let results = loop {
+            let updates = rows.iter().map(|(_, update)| update.clone()).collect();
+            let conflicts = match self.stock.update_many(&ctx, updates) {
+                Ok(results) => break results,
+                Err(conflicts) => conflicts,
+            };
+            for (i, err) in &conflicts {
+                let mut failure = proto::UpsertFailure::new();
+                failure.set_index(rows[*i].0);
+                failure.set_reason(err.to_string());
+                upserts.summary.mut_failures().push(failure);
+            }
+            if upserts.all_or_nothing {
+                return false;
+            }
+            // Conflicts are returned in order, so later indexes are still valid
+            for (i, _) in conflicts.iter().rev() {
+                rows.remove(*i);
+            }
+        };
+        for ((_, update), (previous, _)) in rows.into_iter().zip(results) {
+            match (update.quantity, previous) {
+                (None, Some(_)) => upserts.summary.removed += 1,
+                // Removing a missing product is a no-op
+                (None, None) => {}
+                (Some(_), Some(_)) => upserts.summary.updated += 1,
+                (Some(_), None) => upserts.summary.created += 1,
+            }
+        }
+        true
+    }
+
+    /// Commits the operations of `batch` atomically, failing with
+    /// `ABORTED` if a product is not at its expected version and with
+    /// `FAILED_PRECONDITION` if another operation can't be applied, in
+    /// which case none is.
+    fn handle_commit(
+        &self,
+        ctx: &Context,
+        mut batch: proto::Batch,
+    ) -> Result<proto::BatchResult, grpcio::RpcStatus> {
+        let ctx = ctx
+            .child("Handler::handle_commit")
+            .with_reason(batch.get_reason());
+        let changes = batch
+            .get_operations()
+            .iter()
+            .enumerate()
+            .map(|(i, operation)| {
+                change(operation).map_err(|reason| {
+                    grpcio::RpcStatus::new(
+                        grpcio::RpcStatusCode::InvalidArgument,
+                        Some(format!("operation {}: {}", i, reason)),
+                    )
+                })
+            })
+            .collect::<Result<Vec<Change>, grpcio::RpcStatus>>()?;
+        let key = batch.take_idempotency_key();
+        validate_key(&key)?;
+        let mut hasher = DefaultHasher::new();
+        fingerprint(&mut hasher, &batch);
+        let pending = match self.idempotency.begin(ctx.actor(), "Commit", &key) {
+            idempotency::Begin::Run(pending) => pending,
+            idempotency::Begin::Replay {
+                fingerprint,
+                response,
+            } => {
+                let log = ctx.logger(&self.log);
+                debug!(log, "Replaying Commit"; "idempotency_key" => &key);
+                return replay(&key, hasher.finish(), fingerprint, &response);
+            }
+            idempotency::Begin::InProgress => return Err(in_progress(&key)),
+        };
+
+        let committed = self
+            .stock
+            .commit(&ctx, changes.clone())
+            .map_err(|errors| commit_failed(&changes, errors))?;
+        let mut resp = proto::BatchResult::new();
+        resp.set_request_id(String::from(ctx.request_id()));
+        for (change, committed) in changes.iter().zip(committed) {
+            let quantity = committed
+                .quantity
+                .or(committed.previous)
+                .unwrap_or_default();
+            let mut item = change.product.item(quantity);
+            item.set_version(committed.version as i64);
+            let mut result = proto::OperationResult::new();
+            result.set_item(item);
+            result.set_previous_quantity(committed.previous.unwrap_or_default() as i32);
+            result.set_created(committed.previous.is_none());
+            result.set_removed(committed.quantity.is_none());
+            resp.mut_results().push(result);
+        }
+        pending.complete(hasher.finish(), encode(&resp));
+        Ok(resp)
+    }
+
+    /// Moves stock between two stores, or sends it in transit when
+    /// `in_transit` is set.
+    fn handle_transfer(
+        &self,
+        ctx: &Context,
+        mut req: proto::TransferRequest,
+    ) -> Result<proto::TransferResponse, grpcio::RpcStatus> {
+        let ctx = ctx
+            .child("Handler::handle_transfer")
+            .with_reason(req.get_reason());
+        let invalid =
+            |reason| grpcio::RpcStatus::new(grpcio::RpcStatusCode::InvalidArgument, Some(reason));
+        let quantity = validate(req.get_name(), req.get_quantity()).map_err(invalid)?;
+        if quantity == 0 {
+            return Err(invalid(String::from("quantity must be positive")));
+        }
+        if req.get_from() == req.get_to() {
+            return Err(invalid(String::from(
+                "source and destination stores must differ",
+            )));
+        }
+        let key = req.take_idempotency_key();
+        validate_key(&key)?;
+        let mut hasher = DefaultHasher::new();
+        fingerprint(&mut hasher, &req);
+        let pending = match self.idempotency.begin(ctx.actor(), "TransferStock", &key) {
+            idempotency::Begin::Run(pending) => pending,
+            idempotency::Begin::Replay {
+                fingerprint,
+                response,
+            } => {
+                let log = ctx.logger(&self.log);
+                debug!(log, "Replaying TransferStock"; "idempotency_key" => &key);
+                return replay(&key, hasher.finish(), fingerprint, &response);
+            }
+            idempotency::Begin::InProgress => return Err(in_progress(&key)),
+        };
+
+        let transfer = Transfer {
+            from: req.get_from(),
+            to: req.get_to(),
+            product: Product::new(req.get_name(), req.get_brand(), req.get_category()),
+            quantity,
+        };
+        let transferred = self
+            .stock
+            .transfer(&ctx, transfer, req.get_in_transit())
+            .map_err(transfer_failed)?;
+        let mut resp = proto::TransferResponse::new();
+        resp.set_request_id(String::from(ctx.request_id()));
+        resp.set_transfer_id(transferred.id.unwrap_or_default());
+        resp.set_from(req.get_from());
+        resp.set_to(req.get_to());
+        resp.set_quantity(quantity as i32);
+        resp.set_source(transferred.source);
+        if let Some(destination) = transferred.destination {
+            resp.set_destination(destination);
+        }
+        pending.complete(hasher.finish(), encode(&resp));
+        Ok(resp)
+    }
+
+    /// Adds the units of an in-transit transfer to its destination store.
+    fn handle_receive(
+        &self,
+        ctx: &Context,
+        mut req: proto::ReceiveTransferRequest,
+    ) -> Result<proto::TransferResponse, grpcio::RpcStatus> {
+        let ctx = ctx
+            .child("Handler::handle_receive")
+            .with_reason(req.get_reason());
+        let key = req.take_idempotency_key();
+        validate_key(&key)?;
+        let mut hasher = DefaultHasher::new();
+        fingerprint(&mut hasher, &req);
+        let pending = match self.idempotency.begin(ctx.actor(), "ReceiveTransfer", &key) {
+            idempotency::Begin::Run(pending) => pending,
+            idempotency::Begin::Replay {
+                fingerprint,
+                response,
+            } => {
+                let log = ctx.logger(&self.log);
+                debug!(log, "Replaying ReceiveTransfer"; "idempotency_key" => &key);
+                return replay(&key, hasher.finish(), fingerprint, &response);
+            }
+            idempotency::Begin::InProgress => return Err(in_progress(&key)),
+        };
+
+        let (transfer, destination) = self
+            .stock
+            .receive_transfer(&ctx, req.get_transfer_id())
+            .map_err(transfer_failed)?;
+        let mut resp = proto::TransferResponse::new();
+        resp.set_request_id(String::from(ctx.request_id()));
+        resp.set_transfer_id(String::from(req.get_transfer_id()));
+        resp.set_from(transfer.from);
+        resp.set_to(transfer.to);
+        resp.set_quantity(transfer.quantity as i32);
+        resp.set_destination(destination);
+        pending.complete(hasher.finish(), encode(&resp));
+        Ok(resp)
+    }
+
+    fn handle_history(
+        &self,
+        ctx: &Context,
+        req: proto::HistoryRequest,
+    ) -> Result<proto::HistoryResponse, grpcio::RpcStatus> {
+        let ctx = ctx.child("Handler::handle_history");
+        let invalid = |reason: &str| {
+            grpcio::RpcStatus::new(
+                grpcio::RpcStatusCode::InvalidArgument,
+                Some(String::from(reason)),
+            )
+        };
+        if req.get_since() < 0 || req.get_until() < 0 || req.get_limit() < 0 {
+            return Err(invalid("since, until and limit must not be negative"));
+        }
+        if req.get_until() != 0 && req.get_since() >= req.get_until() {
+            return Err(invalid("since must be before until"));
+        }
+        let filter = HistoryFilter {
+            stores: req.get_stores().to_vec(),
+            name: Some(String::from(req.get_name())).filter(|name| !name.is_empty()),
+            since: from_unix_millis(req.get_since()),
+            until: from_unix_millis(req.get_until()),
+            limit: Some(req.get_limit() as usize).filter(|limit| *limit != 0),
+        };
+        let entries = self.stock.history(&filter);
+        let log = ctx.logger(&self.log);
+        debug!(log, "History returned {} entries", entries.len());
+
+        let mut resp = proto::HistoryResponse::new();
+        resp.set_request_id(String::from(ctx.request_id()));
+        resp.set_entries(entries.iter().map(history_entry).collect());
+        Ok(resp)
+    }
+}
+
+/// Returns the time `millis` Unix milliseconds stand for, `None` unless
+/// it's positive.
+fn from_unix_millis(millis: i64) -> Option<time::SystemTime> {
+    if millis <= 0 {
+        return None;
+    }
+    time::UNIX_EPOCH.checked_add(time::Duration::from_millis(millis as u64))
+}
+
+fn history_entry(entry: &HistoryEntry) -> proto::HistoryEntry {
+    let mut resp = proto::HistoryEntry::new();
+    let millis = entry
+        .time
+        .duration_since(time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as i64)
+        .unwrap_or_default();
+    resp.set_timestamp(millis);
+    resp.set_store(entry.store);
+    resp.set_name(String::from(entry.product.name()));
+    resp.set_brand(String::from(entry.product.brand()));
+    resp.set_category(String::from(entry.product.category()));
+    resp.set_previous_quantity(entry.previous.unwrap_or_default() as i32);
+    resp.set_quantity(entry.quantity.unwrap_or_default() as i32);
+    resp.set_created(entry.previous.is_none());
+    resp.set_removed(entry.quantity.is_none());
+    resp.set_actor(entry.actor.clone());
+    resp.set_reason(entry.reason.clone());
+    resp
+}
+
+/// `Upserts` holds the state of a `BulkUpsert` stream: the valid items not
+/// applied yet and the summary sent back once the stream ends.
+#[derive(Default)]
+struct Upserts {
+    all_or_nothing: bool,
+    reason: String,
+    key: String,
+    fingerprint: DefaultHasher,
+    idempotent: Option<idempotency::Pending>,
+    replay: Option<(u64, Vec<u8>)>,
+    error: Option<grpcio::RpcStatus>,
+    // Valid items not applied yet, with their index in the stream
+    pending: Vec<(i32, Update)>,
+    summary: proto::BulkUpsertSummary,
+}
+
+/// Checks an idempotency key is printable ASCII and not too long. Empty
+/// keys are valid, the call is just not idempotent.
+fn validate_key(key: &str) -> Result<(), grpcio::RpcStatus> {
+    if key.len() > idempotency::MAX_KEY_LEN || !key.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(grpcio::RpcStatus::new(
+            grpcio::RpcStatusCode::InvalidArgument,
+            Some(format!(
+                "idempotency key must be printable ASCII of up to {} bytes",
+                idempotency::MAX_KEY_LEN
+            )),
+        ));
+    }
+    Ok(())
+}
+
+/// Adds the encoding of a request message, idempotency key excluded, to
+/// the fingerprint telling whether a call sent again is the same.
+fn fingerprint<M: protobuf::Message>(hasher: &mut DefaultHasher, msg: &M) {
+    msg.write_to_bytes().unwrap_or_default().hash(hasher);
+}
+
+fn encode<M: protobuf::Message>(msg: &M) -> Vec<u8> {
+    msg.write_to_bytes().unwrap_or_default()
+}
+
+/// Returns the original response of a call sent again with `key`, unless
+/// the key was used by a different request.
+fn replay<M: protobuf::Message>(
+    key: &str,
+    fingerprint: u64,
+    original: u64,
+    response: &[u8],
+) -> Result<M, grpcio::RpcStatus> {
+    if fingerprint != original {
+        return Err(grpcio::RpcStatus::new(
+            grpcio::RpcStatusCode::InvalidArgument,
+            Some(format!(
+                "idempotency key {:?} was used by a different request",
+                key
+            )),
+        ));
+    }
+    protobuf::parse_from_bytes(response).map_err(|err| {
+        grpcio::RpcStatus::new(grpcio::RpcStatusCode::Internal, Some(err.to_string()))
+    })
+}
+
+// Unavailable rather than aborted, so clients retry it with backoff
+// instead of taking it for a version conflict
+fn in_progress(key: &str) -> grpcio::RpcStatus {
+    grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Unavailable,
+        Some(format!(
+            "a call with idempotency key {:?} is in progress",
+            key
+        )),
+    )
+}
+
+/// Returns the quantity of a product to be set, or why it can't be.
+fn validate(name: &str, quantity: i32) -> Result<u32, String> {
+    if name.trim().is_empty() {
+        return Err(String::from("name must not be empty"));
+    }
+    if quantity < 0 {
+        return Err(String::from("quantity must not be negative"));
+    }
+    Ok(quantity as u32)
+}
+
+/// Returns the change an operation of a `Commit` batch makes, or why it's
+/// invalid.
+fn change(op: &proto::Operation) -> Result<Change, String> {
+    let quantity = op.get_quantity();
+    let expected_version = validate_version(op.get_expected_version())?;
+    let operation = match op.get_kind() {
+        proto::OperationKind::SET => Operation::Set(validate(op.get_name(), quantity)?),
+        proto::OperationKind::ADJUST => {
+            validate(op.get_name(), 0)?;
+            Operation::Adjust(i64::from(quantity))
+        }
+        proto::OperationKind::CREATE => {
+            if expected_version.is_some() {
+                return Err(String::from(
+                    "expected version must not be set on CREATE operations",
+                ));
+            }
+            Operation::Create(validate(op.get_name(), quantity)?)
+        }
+        // The quantity of removals is ignored
+        proto::OperationKind::DELETE => {
+            validate(op.get_name(), 0)?;
+            Operation::Delete
+        }
+    };
+    Ok(Change {
+        store: op.get_store(),
+        product: Product::new(op.get_name(), op.get_brand(), op.get_category()),
+        operation,
+        expected_version,
+    })
+}
+
+/// Returns the status of a `Commit` call whose `changes` can't be applied,
+/// describing every one that failed. Version mismatches make it `ABORTED`,
+/// so the caller can read the products again and retry.
+fn commit_failed(changes: &[Change], errors: Vec<(usize, CommitError)>) -> grpcio::RpcStatus {
+    let code = if errors
+        .iter()
+        .any(|(_, err)| *err == CommitError::Unsupported)
+    {
+        grpcio::RpcStatusCode::Unimplemented
+    } else if errors
+        .iter()
+        .any(|(_, err)| matches!(err, CommitError::Mismatch(_)))
+    {
+        grpcio::RpcStatusCode::Aborted
+    } else {
+        grpcio::RpcStatusCode::FailedPrecondition
+    };
+    let reasons: Vec<String> = errors
+        .iter()
+        .map(|(i, err)| match changes.get(*i) {
+            Some(change) => format!(
+                "operation {} on '{}' in {:?}: {}",
+                i,
+                change.product.name(),
+                change.store,
+                err
+            ),
+            None => format!("operation {}: {}", i, err),
+        })
+        .collect();
+    grpcio::RpcStatus::new(code, Some(reasons.join("; ")))
+}
+
+/// Returns the status of a transfer that can't be made or received.
+fn transfer_failed(err: TransferError) -> grpcio::RpcStatus {
+    let code = match err {
+        TransferError::Unsupported => grpcio::RpcStatusCode::Unimplemented,
+        TransferError::Unknown(_) => grpcio::RpcStatusCode::NotFound,
+        TransferError::Empty | TransferError::SameStore => grpcio::RpcStatusCode::InvalidArgument,
+        TransferError::Missing | TransferError::Insufficient(_) | TransferError::OutOfRange => {
+            grpcio::RpcStatusCode::FailedPrecondition
+        }
+    };
+    grpcio::RpcStatus::new(code, Some(format!("cannot transfer: {}", err)))
+}
+
+/// Returns the version of a product a change expects, if any.
+fn validate_version(version: i64) -> Result<Option<u64>, String> {
+    match version {
+        0 => Ok(None),
+        version if version < 0 => Err(String::from("expected version must not be negative")),
+        version => Ok(Some(version as u64)),
+    }
+}
+
+/// Returns the status of a change of `product` in `store` that can't be
+/// applied: `ABORTED` if the product was changed since the expected
+/// version, so the caller can read it again and retry, and
+/// `FAILED_PRECONDITION` if it's not in the store anymore.
+fn conflict(store: proto::Store, product: &Product, err: VersionError) -> grpcio::RpcStatus {
+    let code = match err {
+        VersionError::Unsupported => grpcio::RpcStatusCode::Unimplemented,
+        VersionError::Missing => grpcio::RpcStatusCode::FailedPrecondition,
+        VersionError::Mismatch(_) => grpcio::RpcStatusCode::Aborted,
+    };
+    grpcio::RpcStatus::new(
+        code,
+        Some(format!(
+            "cannot change '{}' on {:?}: {}",
+            product.name(),
+            store,
+            err
+        )),
+    )
+}
+
+/// `Reply` is implemented by the sinks of the calls answered with a single
+/// message, so [`Service::serve`](struct.Service.html) handles them alike.
+trait Reply<Resp> {
+    fn reply(
+        self,
+        result: Result<Resp, grpcio::RpcStatus>,
+    ) -> Box<dyn Future<Item = (), Error = grpcio::Error> + Send>;
+}
+
+impl<Resp: 'static> Reply<Resp> for grpcio::UnarySink<Resp> {
+    fn reply(
+        self,
+        result: Result<Resp, grpcio::RpcStatus>,
+    ) -> Box<dyn Future<Item = (), Error = grpcio::Error> + Send> {
+        match result {
+            Ok(resp) => Box::new(self.success(resp)),
+            Err(status) => Box::new(self.fail(status)),
+        }
+    }
+}
+
+impl<Resp: 'static> Reply<Resp> for grpcio::ClientStreamingSink<Resp> {
+    fn reply(
+        self,
+        result: Result<Resp, grpcio::RpcStatus>,
+    ) -> Box<dyn Future<Item = (), Error = grpcio::Error> + Send> {
+        match result {
+            Ok(resp) => Box::new(self.success(resp)),
+            Err(status) => Box::new(self.fail(status)),
+        }
+    }
+}
+
+/// Returns the status a call fails with when its request stream does.
+fn stream_status(err: grpcio::Error) -> grpcio::RpcStatus {
+    match err {
+        grpcio::Error::RpcFailure(status) => status,
+        err => grpcio::RpcStatus::new(grpcio::RpcStatusCode::Unknown, Some(err.to_string())),
+    }
 }
 
 /// Service implements the
@@ -56,6 +843,9 @@ where
     S: Stock + Send + Clone + 'static,
 {
     handler: Handler<S>,
+    tracer: trace::Tracer,
+    limit: limit::ConcurrencyLimit,
+    actors: auth::Actors,
     log: Logger,
 }
 
@@ -69,15 +859,151 @@ where
         req: proto::StockRequest,
         sink: grpcio::UnarySink<proto::StockResponse>,
     ) {
-        trace!(self.log, "gRPC request received");
-        let resp = self.handler.handle(req);
+        let store = req.get_store();
+        self.serve(ctx, sink, "ListItems", Some(store), move |handler, call| {
+            let resp = if req.get_as_of() != 0 {
+                handler.handle_as_of(&call, req)
+            } else {
+                Ok(handler.handle(&call, req))
+            };
+            future::result(resp.map(|resp| {
+                let items = resp.get_items().len();
+                (resp, items)
+            }))
+        });
+    }
 
-        let log = Logger::new(&self.log, o!("component" => "gRPCSink"));
-        let f = sink
-            .success(resp)
-            .map_err(move |err| error!(log, "Error while replying StockRequest: {:?}", err));
-        ctx.spawn(f);
-        trace!(self.log, "Finished gRPC request");
+    fn list_stores(
+        &mut self,
+        ctx: grpcio::RpcContext,
+        _req: proto::StoresRequest,
+        sink: grpcio::UnarySink<proto::StoresResponse>,
+    ) {
+        self.serve(ctx, sink, "ListStores", None, |handler, call| {
+            let resp = handler.handle_stores(&call);
+            let stores = resp.get_stores().len();
+            future::ok((resp, stores))
+        });
+    }
+
+    fn set_item(
+        &mut self,
+        ctx: grpcio::RpcContext,
+        mut req: proto::SetItemRequest,
+        sink: grpcio::UnarySink<proto::SetItemResponse>,
+    ) {
+        if req.get_idempotency_key().is_empty() {
+            if let Some(key) = header(ctx.request_headers(), idempotency::IDEMPOTENCY_KEY_HEADER) {
+                req.set_idempotency_key(String::from(key));
+            }
+        }
+        let store = req.get_store();
+        self.serve(ctx, sink, "SetItem", Some(store), move |handler, call| {
+            future::result(handler.handle_set(&call, req).map(|resp| (resp, 1)))
+        });
+    }
+
+    fn bulk_upsert(
+        &mut self,
+        ctx: grpcio::RpcContext,
+        stream: grpcio::RequestStream<proto::UpsertItem>,
+        sink: grpcio::ClientStreamingSink<proto::BulkUpsertSummary>,
+    ) {
+        let upserts = Upserts {
+            key: header(ctx.request_headers(), idempotency::IDEMPOTENCY_KEY_HEADER)
+                .map(String::from)
+                .unwrap_or_default(),
+            ..Upserts::default()
+        };
+        self.serve(ctx, sink, "BulkUpsert", None, |handler, call| {
+            let (upserter, handler) = (handler.clone(), handler.clone());
+            stream
+                .map_err(stream_status)
+                .fold(
+                    (call, upserts),
+                    move |(call, mut upserts), item| -> Result<_, grpcio::RpcStatus> {
+                        upserter.handle_upsert(&call, &mut upserts, item);
+                        Ok((call, upserts))
+                    },
+                )
+                .and_then(move |(call, upserts)| {
+                    let summary = handler.finish_upserts(&call, upserts)?;
+                    let items = summary.get_received() as usize;
+                    Ok((summary, items))
+                })
+        });
+    }
+
+    fn commit(
+        &mut self,
+        ctx: grpcio::RpcContext,
+        mut batch: proto::Batch,
+        sink: grpcio::UnarySink<proto::BatchResult>,
+    ) {
+        if batch.get_idempotency_key().is_empty() {
+            if let Some(key) = header(ctx.request_headers(), idempotency::IDEMPOTENCY_KEY_HEADER) {
+                batch.set_idempotency_key(String::from(key));
+            }
+        }
+        self.serve(ctx, sink, "Commit", None, move |handler, call| {
+            future::result(handler.handle_commit(&call, batch).map(|resp| {
+                let items = resp.get_results().len();
+                (resp, items)
+            }))
+        });
+    }
+
+    fn transfer_stock(
+        &mut self,
+        ctx: grpcio::RpcContext,
+        mut req: proto::TransferRequest,
+        sink: grpcio::UnarySink<proto::TransferResponse>,
+    ) {
+        if req.get_idempotency_key().is_empty() {
+            if let Some(key) = header(ctx.request_headers(), idempotency::IDEMPOTENCY_KEY_HEADER) {
+                req.set_idempotency_key(String::from(key));
+            }
+        }
+        let store = req.get_from();
+        self.serve(
+            ctx,
+            sink,
+            "TransferStock",
+            Some(store),
+            move |handler, call| {
+                future::result(handler.handle_transfer(&call, req).map(|resp| (resp, 1)))
+            },
+        );
+    }
+
+    fn receive_transfer(
+        &mut self,
+        ctx: grpcio::RpcContext,
+        mut req: proto::ReceiveTransferRequest,
+        sink: grpcio::UnarySink<proto::TransferResponse>,
+    ) {
+        if req.get_idempotency_key().is_empty() {
+            if let Some(key) = header(ctx.request_headers(), idempotency::IDEMPOTENCY_KEY_HEADER) {
+                req.set_idempotency_key(String::from(key));
+            }
+        }
+        self.serve(ctx, sink, "ReceiveTransfer", None, move |handler, call| {
+            future::result(handler.handle_receive(&call, req).map(|resp| (resp, 1)))
+        });
+    }
+
+    fn get_history(
+        &mut self,
+        ctx: grpcio::RpcContext,
+        req: proto::HistoryRequest,
+        sink: grpcio::UnarySink<proto::HistoryResponse>,
+    ) {
+        self.serve(ctx, sink, "GetHistory", None, move |handler, call| {
+            future::result(handler.handle_history(&call, req).map(|resp| {
+                let entries = resp.get_entries().len();
+                (resp, entries)
+            }))
+        });
     }
 }
 
@@ -86,11 +1012,119 @@ where
     S: Stock + Send + Clone + 'static,
 {
     /// Returns a instance of `Service` given a
-    /// [`Handler`](struct.Handler.html) and a
+    /// [`Handler`](struct.Handler.html), a
+    /// [`Tracer`](../trace/struct.Tracer.html) used to start a span for
+    /// every request, the
+    /// [`ConcurrencyLimit`](limit/struct.ConcurrencyLimit.html) shared by
+    /// every call, the [`Actors`](auth/struct.Actors.html) the calls are
+    /// made by and a
     /// [slog::Logger](https://docs.rs/slog/2.5.2/slog/struct.Logger.html)
     /// .
-    pub fn new(handler: Handler<S>, log: Logger) -> Self {
-        Self { handler, log }
+    pub fn new(
+        handler: Handler<S>,
+        tracer: trace::Tracer,
+        limit: limit::ConcurrencyLimit,
+        actors: auth::Actors,
+        log: Logger,
+    ) -> Self {
+        Self {
+            handler,
+            tracer,
+            limit,
+            actors,
+            log,
+        }
+    }
+
+    /// Serves a call of `method` answered with a single message: it applies
+    /// the concurrency limit, starts the call span, runs the future returned
+    /// by `handle` and writes the access log with the number of items it
+    /// returned along with the response. If the future fails, the call fails
+    /// with its status.
+    fn serve<Resp, K, F, R>(
+        &self,
+        ctx: grpcio::RpcContext,
+        sink: K,
+        method: &'static str,
+        store: Option<proto::Store>,
+        handle: F,
+    ) where
+        Resp: Send + 'static,
+        K: Reply<Resp> + Send + 'static,
+        F: FnOnce(&Handler<S>, Context) -> R,
+        R: Future<Item = (Resp, usize), Error = grpcio::RpcStatus> + Send + 'static,
+    {
+        let start = time::Instant::now();
+        let permit = match self.limit.acquire() {
+            Some(permit) => permit,
+            None => {
+                warn!(self.log, "Rejecting call, too many in flight";
+                    "peer" => ctx.peer(),
+                    "in_flight" => self.limit.in_flight(),
+                );
+                let log = self.log.clone();
+                let f = sink
+                    .reply(Err(grpcio::RpcStatus::new(
+                        grpcio::RpcStatusCode::ResourceExhausted,
+                        Some(String::from("too many requests in flight")),
+                    )))
+                    .map_err(move |err| error!(log, "Error while rejecting {}: {:?}", method, err));
+                ctx.spawn(f);
+                return;
+            }
+        };
+        let headers = ctx.request_headers();
+        let parent = header(headers, trace::TRACEPARENT_HEADER)
+            .and_then(trace::SpanContext::from_traceparent);
+        let mut span = self
+            .tracer
+            .start(&format!("generated.Stock/{}", method), parent);
+        span.set_attribute("rpc.system", "grpc");
+        span.set_attribute("rpc.method", method);
+        let store = store.map(|store| format!("{:?}", store));
+        if let Some(store) = &store {
+            span.set_attribute("stock.store", store.clone());
+        }
+        let actor = self
+            .actors
+            .resolve(header(headers, client::AUTHORIZATION_HEADER));
+        let call = Context::new(&request_id(headers), span).with_actor(actor);
+        let log = call.logger(&self.log);
+
+        trace!(log, "gRPC request received"; "trace_id" => call.span().context().trace_id());
+        let access_log = log.new(o!(
+            "component" => "access",
+            "peer" => ctx.peer(),
+            "method" => String::from_utf8_lossy(ctx.method()).into_owned(),
+            "store" => store.unwrap_or_default(),
+            "actor" => String::from(call.actor()),
+        ));
+        let sink_log = Logger::new(&log, o!("component" => "gRPCSink"));
+        let f = handle(&self.handler, call).then(move |result| {
+            let items = result.as_ref().map(|(_, items)| *items).unwrap_or_default();
+            let code = match &result {
+                Ok(_) => String::from("OK"),
+                Err(status) => format!("{:?}", status.status),
+            };
+            sink.reply(result.map(|(resp, _)| resp)).then(move |res| {
+                drop(permit);
+                let status = match res {
+                    Ok(()) => code,
+                    Err(err) => {
+                        error!(sink_log, "Error while replying {}: {:?}", method, err);
+                        format!("{:?}", err)
+                    }
+                };
+                info!(access_log, "access";
+                    "items" => items,
+                    "status" => status,
+                    "duration_ms" => start.elapsed().as_secs_f64() * 1000.0,
+                );
+                Ok(())
+            })
+        });
+        ctx.spawn(f);
+        trace!(log, "Finished gRPC request");
     }
 }
 
@@ -105,9 +1139,38 @@ mod tests {
     }
 
     impl Stock for StockMock {
-        fn get(&self, _: proto::Store, _: bool) -> Vec<proto::Item> {
+        fn get(&self, _: &Context, _: proto::Store, _: bool) -> Vec<proto::Item> {
             self.resp.clone()
         }
+
+        fn set(&self, _: &Context, _: proto::Store, _: Product, _: u32) -> Option<u32> {
+            self.resp.first().map(|item| item.quantity as u32)
+        }
+
+        fn remove(&self, _: &Context, _: proto::Store, _: &Product) -> Option<u32> {
+            None
+        }
+    }
+
+    #[derive(Clone)]
+    struct VersionedStockMock;
+
+    impl Stock for VersionedStockMock {
+        fn get(&self, _: &Context, _: proto::Store, _: bool) -> Vec<proto::Item> {
+            vec![proto::Item::new()]
+        }
+
+        fn set(&self, _: &Context, _: proto::Store, _: Product, _: u32) -> Option<u32> {
+            None
+        }
+
+        fn remove(&self, _: &Context, _: proto::Store, _: &Product) -> Option<u32> {
+            None
+        }
+
+        fn version(&self, _: proto::Store) -> Option<String> {
+            Some(String::from("v1"))
+        }
     }
 
     #[test]
@@ -141,7 +1204,617 @@ mod tests {
 
         let mut response = proto::StockResponse::new();
         response.set_items(protobuf::RepeatedField::from_vec(expected_items));
+        response.set_request_id(String::from("request-1"));
+
+        let ctx = Context::new("request-1", trace::Tracer::disabled().start("test", None));
+        assert_eq!(handler.handle(&ctx, request), response);
+    }
+
+    #[test]
+    fn test_handler_not_modified() {
+        let handler = grpc::Handler::new(VersionedStockMock, crate::tests::log::new());
+        let ctx = Context::new("request-1", trace::Tracer::disabled().start("test", None));
+
+        let mut request = proto::StockRequest::new();
+        let resp = handler.handle(&ctx, request.clone());
+        assert_eq!(resp.get_version(), "v1");
+        assert_eq!(resp.get_items().len(), 1);
+        assert!(!resp.get_not_modified());
+
+        request.set_if_none_match(String::from("v0"));
+        let resp = handler.handle(&ctx, request.clone());
+        assert_eq!(resp.get_items().len(), 1);
+        assert!(!resp.get_not_modified());
+
+        request.set_if_none_match(String::from("v1"));
+        let resp = handler.handle(&ctx, request);
+        assert_eq!(resp.get_version(), "v1");
+        assert!(resp.get_items().is_empty());
+        assert!(resp.get_not_modified());
+        assert_eq!(resp.get_request_id(), "request-1");
+    }
+
+    #[test]
+    fn test_handler_as_of() {
+        let mut stock = StockImpl::new(crate::tests::log::new());
+        let laptop = Product::new("Laptop air", "AbCorp", "computers/laptops");
+        stock.register(laptop.clone(), proto::Store::VENEZA_IT, 8);
+        let handler = grpc::Handler::new(stock.clone(), crate::tests::log::new());
+        let ctx = Context::new("request-1", trace::Tracer::disabled().start("test", None));
+
+        let before = time::SystemTime::now();
+        std::thread::sleep(time::Duration::from_millis(2));
+        stock.set(&ctx, proto::Store::VENEZA_IT, laptop, 0);
+
+        let mut request = proto::StockRequest::new();
+        request.set_store(proto::Store::VENEZA_IT);
+        request.set_as_of(before.duration_since(time::UNIX_EPOCH).unwrap().as_millis() as i64);
+        let resp = handler.handle_as_of(&ctx, request.clone()).unwrap();
+        assert_eq!(resp.get_items().len(), 1);
+        assert_eq!(resp.get_items()[0].quantity, 8);
+        assert_eq!(resp.get_items()[0].version, 1);
+        assert!(resp.get_version().is_empty());
+        assert_eq!(resp.get_request_id(), "request-1");
+        assert!(handler.handle(&ctx, request.clone()).get_items().is_empty());
+
+        request.set_as_of(-1);
+        let status = handler.handle_as_of(&ctx, request.clone()).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::InvalidArgument);
+
+        request.set_as_of(1);
+        let handler = grpc::Handler::new(VersionedStockMock, crate::tests::log::new());
+        let status = handler.handle_as_of(&ctx, request).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::Unimplemented);
+    }
+
+    #[test]
+    fn test_handler_stores() {
+        let handler = grpc::Handler::new(StockMock { resp: vec![] }, crate::tests::log::new());
+        let ctx = Context::new("request-1", trace::Tracer::disabled().start("test", None));
+
+        let resp = handler.handle_stores(&ctx);
+        assert_eq!(
+            resp.get_stores(),
+            &[proto::Store::BERLIN_DE, proto::Store::VENEZA_IT]
+        );
+        assert_eq!(resp.get_request_id(), "request-1");
+    }
+
+    #[test]
+    fn test_handler_set() {
+        let mut item = proto::Item::new();
+        item.quantity = 3;
+        let handler = grpc::Handler::new(StockMock { resp: vec![item] }, crate::tests::log::new());
+        let ctx = Context::new("request-1", trace::Tracer::disabled().start("test", None));
+
+        let mut request = proto::SetItemRequest::new();
+        request.set_store(proto::Store::VENEZA_IT);
+        request.set_name(String::from("Laptop air"));
+        request.set_brand(String::from("AbCorp"));
+        request.set_category(String::from("computers/laptops"));
+        request.set_quantity(0);
+        let resp = handler.handle_set(&ctx, request.clone()).unwrap();
+        assert_eq!(resp.get_item().get_name(), "Laptop air");
+        assert_eq!(
+            resp.get_item().get_availability_description(),
+            proto::AvailabilityDescription::UNAVAILABLE
+        );
+        assert!(!resp.get_created());
+        assert_eq!(resp.get_previous_quantity(), 3);
+        assert_eq!(resp.get_request_id(), "request-1");
+
+        request.set_quantity(-1);
+        let status = handler.handle_set(&ctx, request.clone()).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::InvalidArgument);
+
+        request.set_quantity(1);
+        request.set_name(String::from(" "));
+        assert!(handler.handle_set(&ctx, request.clone()).is_err());
+
+        // The mock keeps no versions
+        request.set_name(String::from("Laptop air"));
+        request.set_expected_version(1);
+        let status = handler.handle_set(&ctx, request).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::Unimplemented);
+    }
+
+    #[test]
+    fn test_handler_set_expected_version() {
+        let mut stock = StockImpl::new(crate::tests::log::new());
+        stock.register(
+            Product::new("Laptop air", "", ""),
+            proto::Store::VENEZA_IT,
+            8,
+        );
+        let handler = grpc::Handler::new(stock, crate::tests::log::new());
+        let ctx = Context::new("request-1", trace::Tracer::disabled().start("test", None));
+
+        let mut request = proto::SetItemRequest::new();
+        request.set_store(proto::Store::VENEZA_IT);
+        request.set_name(String::from("Laptop air"));
+        request.set_quantity(6);
+        request.set_expected_version(1);
+        let resp = handler.handle_set(&ctx, request.clone()).unwrap();
+        assert_eq!(resp.get_item().get_version(), 2);
+        assert_eq!(resp.get_previous_quantity(), 8);
+
+        let status = handler.handle_set(&ctx, request.clone()).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::Aborted);
+        assert_eq!(
+            status.details.as_deref(),
+            Some("cannot change 'Laptop air' on VENEZA_IT: the product is at version 2 with quantity 6")
+        );
+
+        request.set_expected_version(-1);
+        let status = handler.handle_set(&ctx, request.clone()).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::InvalidArgument);
+
+        request.set_name(String::from("Model 3"));
+        request.set_expected_version(1);
+        let status = handler.handle_set(&ctx, request.clone()).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::FailedPrecondition);
+
+        request.set_expected_version(0);
+        let resp = handler.handle_set(&ctx, request).unwrap();
+        assert!(resp.get_created());
+        assert_eq!(resp.get_item().get_version(), 1);
+    }
+
+    fn upsert_item(name: &str, quantity: i32) -> proto::UpsertItem {
+        let mut item = proto::UpsertItem::new();
+        item.set_store(proto::Store::VENEZA_IT);
+        item.set_name(String::from(name));
+        item.set_quantity(quantity);
+        item
+    }
+
+    #[test]
+    fn test_handler_upserts() {
+        let stock = StockImpl::new(crate::tests::log::new());
+        let handler = grpc::Handler::new(stock.clone(), crate::tests::log::new());
+        let ctx = Context::new("request-1", trace::Tracer::disabled().start("test", None));
+
+        let mut upserts = Upserts::default();
+        for (name, quantity) in &[
+            ("Laptop air", 8),
+            ("", 1),
+            ("Laptop air", 2),
+            ("Model 3", -1),
+        ] {
+            handler.handle_upsert(&ctx, &mut upserts, upsert_item(name, *quantity));
+        }
+        let summary = handler.finish_upserts(&ctx, upserts).unwrap();
+        assert_eq!(summary.get_received(), 4);
+        assert_eq!(summary.get_created(), 1);
+        assert_eq!(summary.get_updated(), 1);
+        assert!(summary.get_applied());
+        let failures: Vec<i32> = summary.get_failures().iter().map(|f| f.index).collect();
+        assert_eq!(failures, vec![1, 3]);
+        assert_eq!(summary.get_request_id(), "request-1");
+
+        let items = stock.get(&ctx, proto::Store::VENEZA_IT, true);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].quantity, 2);
+        let mut upserts = Upserts::default();
+        let mut removal = upsert_item("Laptop air", -1);
+        removal.set_remove(true);
+        handler.handle_upsert(&ctx, &mut upserts, removal.clone());
+        handler.handle_upsert(&ctx, &mut upserts, removal);
+        let summary = handler.finish_upserts(&ctx, upserts).unwrap();
+        assert!(summary.get_failures().is_empty());
+        assert_eq!(summary.get_removed(), 1);
+        assert!(stock.get(&ctx, proto::Store::VENEZA_IT, true).is_empty());
+    }
+
+    #[test]
+    fn test_handler_upserts_all_or_nothing() {
+        let stock = StockImpl::new(crate::tests::log::new());
+        let handler = grpc::Handler::new(stock.clone(), crate::tests::log::new());
+        let ctx = Context::new("request-1", trace::Tracer::disabled().start("test", None));
+
+        let mut first = upsert_item("Laptop air", 8);
+        first.set_all_or_nothing(true);
+        let mut upserts = Upserts::default();
+        for i in 0..UPSERT_BATCH_SIZE {
+            handler.handle_upsert(&ctx, &mut upserts, upsert_item(&i.to_string(), 1));
+        }
+        assert!(upserts.pending.is_empty());
+        assert_eq!(
+            stock.get(&ctx, proto::Store::VENEZA_IT, true).len(),
+            UPSERT_BATCH_SIZE
+        );
+
+        let mut upserts = Upserts::default();
+        handler.handle_upsert(&ctx, &mut upserts, first.clone());
+        for i in 0..UPSERT_BATCH_SIZE {
+            handler.handle_upsert(&ctx, &mut upserts, upsert_item(&i.to_string(), 2));
+        }
+        handler.handle_upsert(&ctx, &mut upserts, upsert_item("Model 3", -1));
+        let summary = handler.finish_upserts(&ctx, upserts).unwrap();
+        assert!(!summary.get_applied());
+        assert_eq!(summary.get_created() + summary.get_updated(), 0);
+        assert_eq!(summary.get_failures().len(), 1);
+        let items = stock.get(&ctx, proto::Store::VENEZA_IT, true);
+        assert!(items.iter().all(|item| item.quantity == 1));
+
+        let mut upserts = Upserts::default();
+        handler.handle_upsert(&ctx, &mut upserts, first);
+        let summary = handler.finish_upserts(&ctx, upserts).unwrap();
+        assert!(summary.get_applied());
+        assert_eq!(summary.get_created(), 1);
+    }
+
+    #[test]
+    fn test_handler_upserts_expected_version() {
+        let mut stock = StockImpl::new(crate::tests::log::new());
+        stock.register(
+            Product::new("Laptop air", "", ""),
+            proto::Store::VENEZA_IT,
+            8,
+        );
+        let handler = grpc::Handler::new(stock.clone(), crate::tests::log::new());
+        let ctx = Context::new("request-1", trace::Tracer::disabled().start("test", None));
+
+        let versioned = |name: &str, quantity: i32, version: i64| {
+            let mut item = upsert_item(name, quantity);
+            item.set_expected_version(version);
+            item
+        };
+        let stream = vec![
+            versioned("Laptop air", 6, 1),
+            versioned("Laptop air", 5, 1),
+            versioned("Model 3", 1, 1),
+            upsert_item("Model S", 1),
+        ];
+
+        // Conflicting items fail, the others are applied
+        let mut upserts = Upserts::default();
+        for item in stream.clone() {
+            handler.handle_upsert(&ctx, &mut upserts, item);
+        }
+        let summary = handler.finish_upserts(&ctx, upserts).unwrap();
+        assert!(summary.get_applied());
+        assert_eq!((summary.get_created(), summary.get_updated()), (1, 2));
+        let failures: Vec<i32> = summary.get_failures().iter().map(|f| f.index).collect();
+        assert_eq!(failures, vec![2]);
+
+        // All-or-nothing streams apply none
+        let mut upserts = Upserts::default();
+        let mut first = versioned("Model S", 2, 1);
+        first.set_all_or_nothing(true);
+        handler.handle_upsert(&ctx, &mut upserts, first);
+        for item in stream {
+            handler.handle_upsert(&ctx, &mut upserts, item);
+        }
+        let summary = handler.finish_upserts(&ctx, upserts).unwrap();
+        assert!(!summary.get_applied());
+        let failures: Vec<i32> = summary.get_failures().iter().map(|f| f.index).collect();
+        assert_eq!(failures, vec![1, 2, 3]);
+        let items = stock.get(&ctx, proto::Store::VENEZA_IT, true);
+        assert!(items
+            .iter()
+            .all(|item| item.name != "Model S" || item.quantity == 1));
+    }
+
+    #[test]
+    fn test_handler_set_idempotent() {
+        let stock = StockImpl::new(crate::tests::log::new());
+        let handler = grpc::Handler::new(stock.clone(), crate::tests::log::new())
+            .with_idempotency(idempotency::Idempotency::new(time::Duration::from_secs(60)));
+        let ctx = Context::new("request-1", trace::Tracer::disabled().start("test", None));
+
+        let mut request = proto::SetItemRequest::new();
+        request.set_store(proto::Store::VENEZA_IT);
+        request.set_name(String::from("Laptop air"));
+        request.set_quantity(8);
+        request.set_idempotency_key(String::from("key-1"));
+        let resp = handler.handle_set(&ctx, request.clone()).unwrap();
+        assert!(resp.get_created());
+
+        // The product changes in between, the retry still gets the original
+        // response and applies nothing
+        let laptop = Product::new("Laptop air", "", "");
+        stock.set(&ctx, proto::Store::VENEZA_IT, laptop.clone(), 2);
+        let retry = Context::new("request-2", trace::Tracer::disabled().start("test", None));
+        assert_eq!(handler.handle_set(&retry, request.clone()).unwrap(), resp);
+        assert_eq!(
+            stock.get(&ctx, proto::Store::VENEZA_IT, true)[0].quantity,
+            2
+        );
+
+        request.set_quantity(9);
+        let status = handler.handle_set(&ctx, request.clone()).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::InvalidArgument);
+
+        // Keys are scoped by actor
+        let bob = Context::new("request-3", trace::Tracer::disabled().start("test", None))
+            .with_actor("bob");
+        assert!(!handler
+            .handle_set(&bob, request.clone())
+            .unwrap()
+            .get_created());
+        assert_eq!(
+            stock.get(&ctx, proto::Store::VENEZA_IT, true)[0].quantity,
+            9
+        );
+
+        request.set_idempotency_key(String::from("key 2"));
+        let status = handler.handle_set(&ctx, request).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::InvalidArgument);
+    }
+
+    #[test]
+    fn test_handler_upserts_idempotent() {
+        let stock = StockImpl::new(crate::tests::log::new());
+        let handler = grpc::Handler::new(stock.clone(), crate::tests::log::new())
+            .with_idempotency(idempotency::Idempotency::new(time::Duration::from_secs(60)));
+        let ctx = Context::new("request-1", trace::Tracer::disabled().start("test", None));
+
+        let mut first = upsert_item("Laptop air", 8);
+        first.set_idempotency_key(String::from("key-1"));
+        let stream = || vec![first.clone(), upsert_item("Model 3", 1)];
+        let mut upserts = Upserts::default();
+        for item in stream() {
+            handler.handle_upsert(&ctx, &mut upserts, item);
+        }
+        // A retry sent while the stream is still running is rejected
+        let mut retry = Upserts::default();
+        handler.handle_upsert(&ctx, &mut retry, first.clone());
+        let status = handler.finish_upserts(&ctx, retry).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::Unavailable);
+
+        let summary = handler.finish_upserts(&ctx, upserts).unwrap();
+        assert_eq!(summary.get_created(), 2);
+
+        let laptop = Product::new("Laptop air", "", "");
+        stock.set(&ctx, proto::Store::VENEZA_IT, laptop, 3);
+        let mut retry = Upserts {
+            key: String::from("key-1"),
+            ..Upserts::default()
+        };
+        let mut items = stream();
+        items[0].clear_idempotency_key();
+        for item in items {
+            handler.handle_upsert(&ctx, &mut retry, item);
+        }
+        assert_eq!(handler.finish_upserts(&ctx, retry).unwrap(), summary);
+        let items = stock.get(&ctx, proto::Store::VENEZA_IT, true);
+        assert_eq!(
+            items
+                .iter()
+                .find(|item| item.name == "Laptop air")
+                .map(|item| item.quantity),
+            Some(3)
+        );
+
+        let mut retry = Upserts::default();
+        handler.handle_upsert(&ctx, &mut retry, first);
+        let status = handler.finish_upserts(&ctx, retry).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::InvalidArgument);
+    }
+
+    fn operation(kind: proto::OperationKind, name: &str, quantity: i32) -> proto::Operation {
+        let mut operation = proto::Operation::new();
+        operation.set_kind(kind);
+        operation.set_store(proto::Store::VENEZA_IT);
+        operation.set_name(String::from(name));
+        operation.set_quantity(quantity);
+        operation
+    }
+
+    #[test]
+    fn test_handler_commit() {
+        let mut stock = StockImpl::new(crate::tests::log::new());
+        stock.register(
+            Product::new("Laptop air", "", ""),
+            proto::Store::VENEZA_IT,
+            8,
+        );
+        let handler = grpc::Handler::new(stock.clone(), crate::tests::log::new());
+        let ctx = Context::new("request-1", trace::Tracer::disabled().start("test", None));
+
+        let mut batch = proto::Batch::new();
+        batch.set_reason(String::from("damaged"));
+        batch.set_operations(protobuf::RepeatedField::from_vec(vec![
+            operation(proto::OperationKind::ADJUST, "Laptop air", -1),
+            operation(proto::OperationKind::CREATE, "Laptop air (damaged)", 1),
+        ]));
+        let resp = handler.handle_commit(&ctx, batch.clone()).unwrap();
+        assert_eq!(resp.get_request_id(), "request-1");
+        let results = resp.get_results();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].get_item().get_quantity(), 7);
+        assert_eq!(results[0].get_item().get_version(), 2);
+        assert_eq!(results[0].get_previous_quantity(), 8);
+        assert!(results[1].get_created());
+        let history = stock.history(&HistoryFilter::default());
+        assert!(history.iter().all(|entry| entry.reason == "damaged"));
+
+        // Creating the product again fails the whole batch
+        let status = handler.handle_commit(&ctx, batch).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::FailedPrecondition);
+        assert_eq!(
+            status.details.as_deref(),
+            Some(
+                "operation 1 on 'Laptop air (damaged)' in VENEZA_IT: the product is already in \
+                 the store, at version 1 with quantity 1"
+            )
+        );
+        assert_eq!(stock.get(&ctx, proto::Store::VENEZA_IT, true).len(), 2);
+
+        let mut delete = operation(proto::OperationKind::DELETE, "Laptop air", 0);
+        delete.set_expected_version(1);
+        let mut batch = proto::Batch::new();
+        batch.mut_operations().push(delete);
+        let status = handler.handle_commit(&ctx, batch).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::Aborted);
+
+        let mut create = operation(proto::OperationKind::CREATE, "Model 3", 1);
+        create.set_expected_version(1);
+        let mut batch = proto::Batch::new();
+        batch.set_operations(protobuf::RepeatedField::from_vec(vec![
+            operation(proto::OperationKind::SET, "Model 3", -1),
+            create,
+        ]));
+        let status = handler.handle_commit(&ctx, batch).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::InvalidArgument);
+        assert_eq!(
+            status.details.as_deref(),
+            Some("operation 0: quantity must not be negative")
+        );
+
+        let handler = grpc::Handler::new(StockMock { resp: vec![] }, crate::tests::log::new());
+        let status = handler
+            .handle_commit(&ctx, proto::Batch::new())
+            .unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::Unimplemented);
+    }
+
+    #[test]
+    fn test_handler_transfer() {
+        let mut stock = StockImpl::new(crate::tests::log::new());
+        stock.register(
+            Product::new("Laptop air", "", ""),
+            proto::Store::BERLIN_DE,
+            8,
+        );
+        let handler = grpc::Handler::new(stock.clone(), crate::tests::log::new());
+        let ctx = Context::new("request-1", trace::Tracer::disabled().start("test", None));
+
+        let mut request = proto::TransferRequest::new();
+        request.set_from(proto::Store::BERLIN_DE);
+        request.set_to(proto::Store::VENEZA_IT);
+        request.set_name(String::from("Laptop air"));
+        request.set_quantity(3);
+        request.set_reason(String::from("restock"));
+        let resp = handler.handle_transfer(&ctx, request.clone()).unwrap();
+        assert!(resp.get_transfer_id().is_empty());
+        assert_eq!(resp.get_source().get_quantity(), 5);
+        assert_eq!(resp.get_destination().get_quantity(), 3);
+        assert_eq!(resp.get_request_id(), "request-1");
+        let history = stock.history(&HistoryFilter::default());
+        assert_eq!(history.len(), 2);
+        assert!(history.iter().all(|entry| entry.reason == "restock"));
+
+        request.set_in_transit(true);
+        let resp = handler.handle_transfer(&ctx, request.clone()).unwrap();
+        assert!(!resp.has_destination());
+        let mut receive = proto::ReceiveTransferRequest::new();
+        receive.set_transfer_id(String::from(resp.get_transfer_id()));
+        let resp = handler.handle_receive(&ctx, receive.clone()).unwrap();
+        assert!(!resp.has_source());
+        assert_eq!(resp.get_destination().get_quantity(), 6);
+        assert_eq!(resp.get_quantity(), 3);
+        let status = handler.handle_receive(&ctx, receive).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::NotFound);
+
+        let status = handler.handle_transfer(&ctx, request.clone()).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::FailedPrecondition);
+        assert_eq!(
+            status.details.as_deref(),
+            Some("cannot transfer: the source store only has 2 units of the product")
+        );
+
+        request.set_quantity(0);
+        let status = handler.handle_transfer(&ctx, request.clone()).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::InvalidArgument);
+        request.set_quantity(1);
+        request.set_to(proto::Store::BERLIN_DE);
+        let status = handler.handle_transfer(&ctx, request).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::InvalidArgument);
+    }
+
+    #[test]
+    fn test_handler_history() {
+        let stock = StockImpl::new(crate::tests::log::new());
+        let handler = grpc::Handler::new(stock, crate::tests::log::new());
+        let ctx = Context::new("request-1", trace::Tracer::disabled().start("test", None))
+            .with_actor("alice");
+
+        let mut request = proto::SetItemRequest::new();
+        request.set_store(proto::Store::VENEZA_IT);
+        request.set_name(String::from("Laptop air"));
+        request.set_quantity(8);
+        handler.handle_set(&ctx, request.clone()).unwrap();
+        request.set_quantity(6);
+        request.set_reason(String::from("damaged"));
+        handler.handle_set(&ctx, request).unwrap();
+
+        let mut upserts = Upserts::default();
+        let mut removal = upsert_item("Laptop air", 0);
+        removal.set_remove(true);
+        removal.set_reason(String::from("recall"));
+        handler.handle_upsert(&ctx, &mut upserts, removal);
+        handler.finish_upserts(&ctx, upserts).unwrap();
+
+        let resp = handler
+            .handle_history(&ctx, proto::HistoryRequest::new())
+            .unwrap();
+        assert_eq!(resp.get_request_id(), "request-1");
+        let entries = resp.get_entries();
+        let changes: Vec<(bool, i32, i32, bool, &str)> = entries
+            .iter()
+            .map(|e| {
+                (
+                    e.created,
+                    e.previous_quantity,
+                    e.quantity,
+                    e.removed,
+                    e.get_reason(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (true, 0, 8, false, ""),
+                (false, 8, 6, false, "damaged"),
+                (false, 6, 0, true, "recall"),
+            ]
+        );
+        assert!(entries.iter().all(|e| e.get_actor() == "alice"));
+        assert!(entries[0].timestamp > 0);
+
+        let mut request = proto::HistoryRequest::new();
+        request.set_stores(vec![proto::Store::BERLIN_DE]);
+        assert!(handler
+            .handle_history(&ctx, request)
+            .unwrap()
+            .get_entries()
+            .is_empty());
+
+        let mut request = proto::HistoryRequest::new();
+        request.set_limit(1);
+        let resp = handler.handle_history(&ctx, request).unwrap();
+        assert_eq!(resp.get_entries().len(), 1);
+        assert!(resp.get_entries()[0].removed);
+
+        let mut request = proto::HistoryRequest::new();
+        request.set_since(2);
+        request.set_until(1);
+        let status = handler.handle_history(&ctx, request).unwrap_err();
+        assert_eq!(status.status, grpcio::RpcStatusCode::InvalidArgument);
+    }
+
+    #[test]
+    fn test_request_id() {
+        let mut headers = grpcio::MetadataBuilder::new();
+        headers
+            .add_str(REQUEST_ID_HEADER, "f1c2a3b4-request")
+            .expect("Could not add header");
+        assert_eq!(request_id(&headers.build()), "f1c2a3b4-request");
+
+        let generated = request_id(&grpcio::MetadataBuilder::new().build());
+        assert_eq!(generated.len(), 36);
+        assert_ne!(
+            generated,
+            request_id(&grpcio::MetadataBuilder::new().build())
+        );
 
-        assert_eq!(handler.handle(request), response);
+        let mut headers = grpcio::MetadataBuilder::new();
+        headers
+            .add_str(REQUEST_ID_HEADER, &"a".repeat(MAX_REQUEST_ID_LEN + 1))
+            .expect("Could not add header");
+        assert_eq!(request_id(&headers.build()).len(), 36);
     }
 }
@@ -0,0 +1,231 @@
+use std::{fmt, io, path, str};
+
+use serde::Deserialize;
+
+use super::proto;
+
+/// `FileFormat` is the format of the inventory files read by
+/// `client import` and written by `client export`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileFormat {
+    /// Comma separated values with a header row.
+    Csv,
+    /// A JSON array of objects.
+    Json,
+    /// A YAML sequence of mappings.
+    Yaml,
+}
+
+impl FileFormat {
+    /// Returns the format matching the extension of `path`, if any.
+    pub fn from_path(path: &path::Path) -> Option<Self> {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| ext.parse().ok())
+    }
+}
+
+impl str::FromStr for FileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(FileFormat::Csv),
+            "json" => Ok(FileFormat::Json),
+            "yaml" | "yml" => Ok(FileFormat::Yaml),
+            _ => Err(String::from(
+                "Invalid file format, expected csv, json or yaml",
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FileFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileFormat::Csv => write!(f, "csv"),
+            FileFormat::Json => write!(f, "json"),
+            FileFormat::Yaml => write!(f, "yaml"),
+        }
+    }
+}
+
+/// `Row` is a product of an inventory file and its quantity. The columns
+/// are the ones of the `csv`, `json` and `yaml` client outputs, so an
+/// export can be imported back; `status` is derived from the quantity and
+/// ignored.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Row {
+    /// Product name.
+    pub name: String,
+    /// Product brand.
+    #[serde(default)]
+    pub brand: String,
+    /// Product category.
+    #[serde(default)]
+    pub category: String,
+    /// Quantity to set on the store.
+    pub quantity: u32,
+}
+
+impl Row {
+    /// Returns the `BulkUpsert` item setting the row on `store`.
+    pub fn upsert(&self, store: proto::Store) -> proto::UpsertItem {
+        let mut item = proto::UpsertItem::new();
+        item.set_store(store);
+        item.set_name(self.name.clone());
+        item.set_brand(self.brand.clone());
+        item.set_category(self.category.clone());
+        item.set_quantity(self.quantity as i32);
+        item
+    }
+}
+
+impl From<&proto::Item> for Row {
+    fn from(item: &proto::Item) -> Self {
+        Self {
+            name: item.name.clone(),
+            brand: item.brand.clone(),
+            category: item.category.clone(),
+            quantity: item.quantity.max(0) as u32,
+        }
+    }
+}
+
+/// Reads the rows of an inventory file in `format`. A row that can't be
+/// read, or whose quantity is above the maximum the server holds, is
+/// returned as an error, so the other ones can still be imported; only a
+/// file that can't be read at all fails as a whole.
+pub fn read_rows<R: io::Read>(
+    r: R,
+    format: FileFormat,
+) -> Result<Vec<Result<Row, String>>, String> {
+    let rows: Vec<Result<Row, String>> = match format {
+        FileFormat::Csv => csv::Reader::from_reader(r)
+            .deserialize()
+            .map(|row: Result<Row, csv::Error>| row.map_err(|e| e.to_string()))
+            .collect(),
+        FileFormat::Json => {
+            let values: Vec<serde_json::Value> =
+                serde_json::from_reader(r).map_err(|e| format!("Invalid JSON file: {}", e))?;
+            values
+                .into_iter()
+                .map(|value| serde_json::from_value(value).map_err(|e| e.to_string()))
+                .collect()
+        }
+        FileFormat::Yaml => {
+            let values: Vec<serde_yaml::Value> =
+                serde_yaml::from_reader(r).map_err(|e| format!("Invalid YAML file: {}", e))?;
+            values
+                .into_iter()
+                .map(|value| serde_yaml::from_value(value).map_err(|e| e.to_string()))
+                .collect()
+        }
+    };
+    Ok(rows
+        .into_iter()
+        .map(|row| row.and_then(check_quantity))
+        .collect())
+}
+
+fn check_quantity(row: Row) -> Result<Row, String> {
+    if row.quantity > i32::MAX as u32 {
+        return Err(format!(
+            "quantity {} is above the maximum of {}",
+            row.quantity,
+            i32::MAX
+        ));
+    }
+    Ok(row)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn laptop(quantity: u32) -> Row {
+        Row {
+            name: String::from("Laptop air"),
+            brand: String::from("AbCorp"),
+            category: String::from("computers/laptops"),
+            quantity,
+        }
+    }
+
+    #[test]
+    fn test_file_format() {
+        assert_eq!(
+            FileFormat::from_path(path::Path::new("berlin.CSV")),
+            Some(FileFormat::Csv)
+        );
+        assert_eq!(
+            FileFormat::from_path(path::Path::new("dir.json/berlin")),
+            None
+        );
+        assert_eq!(
+            FileFormat::from_path(path::Path::new("desired.yml")),
+            Some(FileFormat::Yaml)
+        );
+        assert!("xml".parse::<FileFormat>().is_err());
+    }
+
+    #[test]
+    fn test_read_rows_csv() {
+        let file = "\
+name,brand,category,status,quantity
+Laptop air,AbCorp,computers/laptops,UNAVAILABLE,0
+Laptop air,AbCorp,computers/laptops,AVAILABLE,-1
+Laptop air,AbCorp,computers/laptops,AVAILABLE,8
+Laptop air,AbCorp,computers/laptops,AVAILABLE,3000000000
+";
+        let rows = read_rows(file.as_bytes(), FileFormat::Csv).unwrap();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0], Ok(laptop(0)));
+        assert!(rows[1].is_err());
+        assert_eq!(rows[2], Ok(laptop(8)));
+        assert_eq!(
+            rows[3],
+            Err(String::from(
+                "quantity 3000000000 is above the maximum of 2147483647"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_read_rows_json() {
+        let file = r#"[
+            {"name": "Laptop air", "brand": "AbCorp", "category": "computers/laptops", "quantity": 8},
+            {"brand": "AbCorp", "quantity": 1},
+            {"name": "Laptop air", "quantity": 2147483648}
+        ]"#;
+        let rows = read_rows(file.as_bytes(), FileFormat::Json).unwrap();
+        assert_eq!(rows[0], Ok(laptop(8)));
+        assert!(rows[1].is_err());
+        assert!(rows[2].is_err());
+
+        assert!(read_rows("{}".as_bytes(), FileFormat::Json).is_err());
+    }
+
+    #[test]
+    fn test_read_rows_yaml() {
+        let file = "\
+- name: Laptop air
+  brand: AbCorp
+  category: computers/laptops
+  quantity: 8
+- name: Model 3
+  quantity: many
+";
+        let rows = read_rows(file.as_bytes(), FileFormat::Yaml).unwrap();
+        assert_eq!(rows[0], Ok(laptop(8)));
+        assert!(rows[1].is_err());
+    }
+
+    #[test]
+    fn test_row_upsert() {
+        let req = laptop(8).upsert(proto::Store::VENEZA_IT);
+        assert_eq!(req.get_store(), proto::Store::VENEZA_IT);
+        assert_eq!(req.get_name(), "Laptop air");
+        assert_eq!(req.get_quantity(), 8);
+    }
+}
@@ -6,18 +6,137 @@
 //! collections. Also, it implements a gRPC interface for serving client's
 //! requests.
 
-use std::{cmp, collections, hash};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::{cmp, collections, fmt, hash, time};
+
+use protobuf::ProtobufEnum;
 
 #[macro_use]
 extern crate slog;
 
 use slog::Logger;
 
+/// `alerts` evaluates the low-stock thresholds and notifies the alerts
+/// they raise.
+pub mod alerts;
+/// `client_config` holds the client configuration file and its named
+/// contexts.
+pub mod client_config;
+/// `completions` generates the shell completion scripts of the binaries.
+pub mod completions;
+/// `config` holds the layered server configuration.
+pub mod config;
 /// `grpc` holds all the gRPC logic used by the stock service.
 pub mod grpc;
+/// `import` reads the inventory files of the client `import`, `diff` and
+/// `apply` commands.
+pub mod import;
+/// `logging` holds the configurable log drains used by the server.
+pub mod logging;
+/// `output` renders the client results in the formats, columns and templates
+/// of its output options.
+pub mod output;
 /// `proto` is generated during the build. It's the result of the
 /// build script.
 pub mod proto;
+/// `reconcile` compares the desired inventory of a store with the one on
+/// the server.
+pub mod reconcile;
+/// `shell` holds the commands and completion of the interactive client
+/// shell.
+pub mod shell;
+/// `trace` holds the distributed tracing primitives: W3C trace context
+/// propagation, spans and their exporters.
+pub mod trace;
+
+/// Actor recorded for the calls made without a known token.
+pub const ANONYMOUS_ACTOR: &str = "anonymous";
+
+/// Maximum number of entries kept in the [`StockImpl`](struct.StockImpl.html)
+/// history. The oldest ones are dropped first.
+pub const HISTORY_LIMIT: usize = 100_000;
+
+/// `Context` carries the per-call information passed down from the
+/// interface handlers to the [`Stock`](trait.Stock.html) implementation:
+/// the request ID used to correlate logs, the current tracing
+/// [`Span`](trace/struct.Span.html), and the actor and reason recorded in
+/// the history of the changes made by the call.
+pub struct Context {
+    request_id: String,
+    span: trace::Span,
+    actor: String,
+    reason: String,
+}
+
+impl Context {
+    /// Returns a new `Context` given the call request ID and its root
+    /// [`Span`](trace/struct.Span.html). The actor is
+    /// [`ANONYMOUS_ACTOR`](constant.ANONYMOUS_ACTOR.html) and the reason
+    /// is empty.
+    pub fn new(request_id: &str, span: trace::Span) -> Self {
+        Self {
+            request_id: String::from(request_id),
+            span,
+            actor: String::from(ANONYMOUS_ACTOR),
+            reason: String::new(),
+        }
+    }
+
+    /// Sets who makes the call.
+    pub fn with_actor(mut self, actor: &str) -> Self {
+        self.actor = String::from(actor);
+        self
+    }
+
+    /// Sets why the call makes its changes, such as `damaged` or `rental`.
+    pub fn with_reason(mut self, reason: &str) -> Self {
+        self.reason = String::from(reason);
+        self
+    }
+
+    /// Returns who makes the call.
+    pub fn actor(&self) -> &str {
+        &self.actor
+    }
+
+    /// Returns why the call makes its changes.
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// Returns the request ID of the call.
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    /// Returns the current [`Span`](trace/struct.Span.html) of the call.
+    pub fn span(&self) -> &trace::Span {
+        &self.span
+    }
+
+    /// Returns a mutable reference to the current
+    /// [`Span`](trace/struct.Span.html) of the call.
+    pub fn span_mut(&mut self) -> &mut trace::Span {
+        &mut self.span
+    }
+
+    /// Returns a `Context` for the same call, with `name` as a child span
+    /// of the current one.
+    pub fn child(&self, name: &str) -> Context {
+        Self {
+            request_id: self.request_id.clone(),
+            span: self.span.child(name),
+            actor: self.actor.clone(),
+            reason: self.reason.clone(),
+        }
+    }
+
+    /// Returns a child of `log` with the request ID attached as the
+    /// `request_id` key.
+    pub fn logger(&self, log: &Logger) -> Logger {
+        log.new(o!("request_id" => self.request_id.clone()))
+    }
+}
 
 /// `Stock` is the main representation of the crate. This trait is
 /// responsible for holding all the business methods needed while allowing
@@ -28,14 +147,22 @@ pub mod proto;
 ///  # Examples
 ///
 /// ```
-/// # use stock::{proto, Stock};
+/// # use stock::{proto, Context, Product, Stock};
 /// # fn main() {
 /// struct StockMock;
 ///
 /// impl Stock for StockMock {
-///     fn get(&self, _: proto::Store, _: bool) -> Vec<proto::Item> {
+///     fn get(&self, _: &Context, _: proto::Store, _: bool) -> Vec<proto::Item> {
 ///         vec![proto::Item::new()]
 ///     }
+///
+///     fn set(&self, _: &Context, _: proto::Store, _: Product, _: u32) -> Option<u32> {
+///         None
+///     }
+///
+///     fn remove(&self, _: &Context, _: proto::Store, _: &Product) -> Option<u32> {
+///         None
+///     }
 /// }
 /// # }
 /// ```
@@ -43,49 +170,1011 @@ pub trait Stock {
     /// Returns a `Vec` of [`Item`s](proto/stock/struct.Item.html) given a
     /// [Store](proto/stock/enum.Store.html). Also, it receives a boolean
     /// parameter `show_unavailable` that will decide if unavailable items
-    /// must be returned.
-    fn get(&self, store: proto::Store, show_unavaible: bool) -> Vec<proto::Item>;
+    /// must be returned. The call [`Context`](struct.Context.html) is used
+    /// for logging and tracing.
+    fn get(&self, ctx: &Context, store: proto::Store, show_unavaible: bool) -> Vec<proto::Item>;
+
+    /// Returns the [`Item`s](proto/stock/struct.Item.html) `store` held at
+    /// `as_of`, like [`get`](#tymethod.get) does for the current ones, or
+    /// why they can't be known. Every item has the version its product was
+    /// at then. The default implementation keeps no history to answer
+    /// from.
+    fn get_as_of(
+        &self,
+        _ctx: &Context,
+        _store: proto::Store,
+        _show_unavailable: bool,
+        _as_of: time::SystemTime,
+    ) -> Result<Vec<proto::Item>, AsOfError> {
+        Err(AsOfError::Unsupported)
+    }
+
+    /// Sets the `quantity` of `product` in `store`, adding the product if
+    /// it's not there yet. It returns the previous quantity, or `None` if
+    /// the product was added.
+    fn set(
+        &self,
+        ctx: &Context,
+        store: proto::Store,
+        product: Product,
+        quantity: u32,
+    ) -> Option<u32>;
+
+    /// Removes `product` from `store`. It returns the quantity the store
+    /// held, or `None` if the product wasn't there.
+    fn remove(&self, ctx: &Context, store: proto::Store, product: &Product) -> Option<u32>;
+
+    /// Sets every `(store, product, quantity)` of `rows` like
+    /// [`set`](#tymethod.set), or removes the product if the quantity is
+    /// `None`, returning their previous quantities in the same order. The
+    /// default implementation applies them one by one; implementations
+    /// should apply them atomically, so readers see either none or all of
+    /// them.
+    fn set_many(
+        &self,
+        ctx: &Context,
+        rows: Vec<(proto::Store, Product, Option<u32>)>,
+    ) -> Vec<Option<u32>> {
+        rows.into_iter()
+            .map(|(store, product, quantity)| match quantity {
+                Some(quantity) => self.set(ctx, store, product, quantity),
+                None => self.remove(ctx, store, &product),
+            })
+            .collect()
+    }
+
+    /// Applies `updates` like [`set_many`](#method.set_many), unless a
+    /// product is not at the version an update expects: then none is
+    /// applied, and the conflicting updates are returned with their index.
+    /// Versions are checked against the products before any update is
+    /// applied. Otherwise it returns the previous quantity and the new
+    /// version of the product of every update, in the same order.
+    ///
+    /// The default implementation keeps no product versions: updates
+    /// expecting one fail as unsupported, and the others are applied with
+    /// `set_many`, reporting version zero.
+    fn update_many(&self, ctx: &Context, updates: Vec<Update>) -> UpdateResult {
+        let conflicts: Vec<(usize, VersionError)> = updates
+            .iter()
+            .enumerate()
+            .filter(|(_, update)| update.expected_version.is_some())
+            .map(|(i, _)| (i, VersionError::Unsupported))
+            .collect();
+        if !conflicts.is_empty() {
+            return Err(conflicts);
+        }
+        let rows = updates
+            .into_iter()
+            .map(|update| (update.store, update.product, update.quantity))
+            .collect();
+        Ok(self
+            .set_many(ctx, rows)
+            .into_iter()
+            .map(|previous| (previous, 0))
+            .collect())
+    }
+
+    /// Applies `changes` in order, each one seeing the outcome of the
+    /// previous ones, and returns their outcome in the same order. Either
+    /// every change is applied or none is: if any can't be, they are
+    /// returned with their index and why, and nothing changes.
+    ///
+    /// Implementations must apply the changes atomically. The default
+    /// implementation can't, so it fails as unsupported.
+    fn commit(
+        &self,
+        _ctx: &Context,
+        _changes: Vec<Change>,
+    ) -> Result<Vec<Committed>, Vec<(usize, CommitError)>> {
+        Err(vec![(0, CommitError::Unsupported)])
+    }
+
+    /// Returns the version of the `store` inventory, which changes whenever
+    /// its items do. Callers send it back to skip fetching unchanged items.
+    /// The default implementation returns `None`, so items are always
+    /// returned.
+    fn version(&self, _store: proto::Store) -> Option<String> {
+        None
+    }
+
+    /// Returns the stores holding products. The default implementation
+    /// returns every store known by the protocol.
+    fn stores(&self) -> Vec<proto::Store> {
+        proto::Store::values().to_vec()
+    }
+
+    /// Returns the changes matching `filter`, oldest first. The default
+    /// implementation keeps no history.
+    fn history(&self, _filter: &HistoryFilter) -> Vec<HistoryEntry> {
+        vec![]
+    }
+
+    /// Moves the units of `transfer` from its source store to its
+    /// destination atomically. If `in_transit` is set, the units only
+    /// leave the source: they are added to the destination when the
+    /// transfer is received with
+    /// [`receive_transfer`](#method.receive_transfer), given the returned
+    /// ID. Transfers of no units or between a store and itself fail. The
+    /// default implementation can't move stock atomically, so it fails as
+    /// unsupported.
+    fn transfer(
+        &self,
+        _ctx: &Context,
+        _transfer: Transfer,
+        _in_transit: bool,
+    ) -> Result<Transferred, TransferError> {
+        Err(TransferError::Unsupported)
+    }
+
+    /// Adds the units of the in-transit transfer `id` to its destination,
+    /// returning the transfer and the product in the destination after.
+    /// The default implementation keeps no transfers.
+    fn receive_transfer(
+        &self,
+        _ctx: &Context,
+        _id: &str,
+    ) -> Result<(Transfer, proto::Item), TransferError> {
+        Err(TransferError::Unsupported)
+    }
+
+    /// Returns the reorder thresholds set on the products of every store,
+    /// with their current quantities. The default implementation keeps no
+    /// thresholds.
+    fn thresholds(&self) -> Vec<Threshold> {
+        vec![]
+    }
+}
+
+/// `Transfer` moves units of a product from a store to another.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transfer {
+    /// Store the units leave.
+    pub from: proto::Store,
+    /// Store the units are added to.
+    pub to: proto::Store,
+    /// Transferred product.
+    pub product: Product,
+    /// Number of units.
+    pub quantity: u32,
+}
+
+/// `Transferred` is the outcome of a [`Transfer`](struct.Transfer.html).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transferred {
+    /// ID to receive the transfer with, if it's in transit.
+    pub id: Option<String>,
+    /// The product in the source store after the transfer.
+    pub source: proto::Item,
+    /// The product in the destination store after the transfer, `None`
+    /// while it's in transit.
+    pub destination: Option<proto::Item>,
+}
+
+/// `TransferError` describes why a [`Transfer`](struct.Transfer.html)
+/// can't be made or received.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransferError {
+    /// The implementation can't transfer stock.
+    Unsupported,
+    /// The transfer moves no units.
+    Empty,
+    /// The source and destination stores are the same.
+    SameStore,
+    /// The product is not in the source store.
+    Missing,
+    /// The source store holds fewer units than transferred, as given.
+    Insufficient(u32),
+    /// The destination store would hold too many units of the product.
+    OutOfRange,
+    /// No transfer in transit has the given ID.
+    Unknown(String),
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransferError::Unsupported => write!(f, "stock transfers are not supported"),
+            TransferError::Empty => write!(f, "the transfer moves no units"),
+            TransferError::SameStore => {
+                write!(f, "the source and destination stores are the same")
+            }
+            TransferError::Missing => write!(f, "the product is not in the source store"),
+            TransferError::Insufficient(available) => write!(
+                f,
+                "the source store only has {} units of the product",
+                available
+            ),
+            TransferError::OutOfRange => write!(
+                f,
+                "the destination store would hold too many units of the product"
+            ),
+            TransferError::Unknown(id) => write!(f, "no transfer {:?} is in transit", id),
+        }
+    }
+}
+
+/// `Threshold` is the reorder threshold of a product in a store, along
+/// with its current quantity.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Threshold {
+    /// Store of the product.
+    pub store: proto::Store,
+    /// Product the threshold is set on.
+    pub product: Product,
+    /// Quantity the product is low below.
+    pub threshold: u32,
+    /// Current quantity, zero if the product is not in the store.
+    pub quantity: u32,
+}
+
+/// `AsOfError` describes why the items of a store at a point in time can't
+/// be known.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AsOfError {
+    /// The implementation keeps no history.
+    Unsupported,
+    /// The changes made before the given time were dropped from the
+    /// history.
+    Forgotten(time::SystemTime),
+}
+
+impl fmt::Display for AsOfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AsOfError::Unsupported => write!(f, "point-in-time queries are not supported"),
+            AsOfError::Forgotten(time) => {
+                let millis = time
+                    .duration_since(time::UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_millis())
+                    .unwrap_or_default();
+                write!(
+                    f,
+                    "the history before {} Unix milliseconds is not kept",
+                    millis
+                )
+            }
+        }
+    }
+}
+
+/// `Update` sets or removes a product of a store, optionally only if the
+/// product is still at the version the change is based on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Update {
+    /// Store of the product.
+    pub store: proto::Store,
+    /// Product to set or remove.
+    pub product: Product,
+    /// Quantity to set, `None` to remove the product.
+    pub quantity: Option<u32>,
+    /// Version the product must be at, `None` to apply the update
+    /// whatever the version.
+    pub expected_version: Option<u64>,
+}
+
+/// Result of [`Stock::update_many`](trait.Stock.html#method.update_many):
+/// the previous quantity and the new version of the product of every
+/// update, or the conflicting updates with their index.
+pub type UpdateResult = Result<Vec<(Option<u32>, u64)>, Vec<(usize, VersionError)>>;
+
+/// `VersionError` describes why an [`Update`](struct.Update.html)
+/// expecting a product version can't be applied.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VersionError {
+    /// The implementation keeps no product versions.
+    Unsupported,
+    /// The product is not in the store.
+    Missing,
+    /// The product is at another version, the current one is given with
+    /// its quantity.
+    Mismatch(proto::Item),
+}
+
+impl fmt::Display for VersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VersionError::Unsupported => write!(f, "product versions are not supported"),
+            VersionError::Missing => write!(f, "the product is not in the store"),
+            VersionError::Mismatch(item) => write!(
+                f,
+                "the product is at version {} with quantity {}",
+                item.version, item.quantity
+            ),
+        }
+    }
+}
+
+/// `Operation` is what a [`Change`](struct.Change.html) does to a product.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    /// Sets the quantity, adding the product if it's not in the store.
+    Set(u32),
+    /// Adds to the quantity of a product in the store. The result must not
+    /// be negative.
+    Adjust(i64),
+    /// Adds a product not in the store yet with the quantity.
+    Create(u32),
+    /// Removes a product in the store.
+    Delete,
+}
+
+/// `Change` is an [`Operation`](enum.Operation.html) on a product of a
+/// store, committed along with others by
+/// [`Stock::commit`](trait.Stock.html#method.commit).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    /// Store of the product.
+    pub store: proto::Store,
+    /// Product to change.
+    pub product: Product,
+    /// What the change does.
+    pub operation: Operation,
+    /// Version the product must be at, `None` to apply the change whatever
+    /// the version.
+    pub expected_version: Option<u64>,
+}
+
+impl Change {
+    /// Returns the quantity of the product after the change, `None` if
+    /// it's removed, given its current `quantity` and `version`, or why
+    /// the change can't be applied.
+    fn apply(&self, quantity: Option<u32>, version: u64) -> Result<Option<u32>, CommitError> {
+        let current = || {
+            let mut item = self.product.item(quantity.unwrap_or_default());
+            item.version = version as i64;
+            item
+        };
+        if let Some(expected) = self.expected_version {
+            match quantity {
+                None => return Err(CommitError::Missing),
+                Some(_) if version != expected => return Err(CommitError::Mismatch(current())),
+                Some(_) => {}
+            }
+        }
+        match (&self.operation, quantity) {
+            (Operation::Set(quantity), _) | (Operation::Create(quantity), None) => {
+                Ok(Some(*quantity))
+            }
+            (Operation::Create(_), Some(_)) => Err(CommitError::Exists(current())),
+            (Operation::Adjust(_), None) | (Operation::Delete, None) => Err(CommitError::Missing),
+            (Operation::Adjust(delta), Some(quantity)) => {
+                let adjusted = i64::from(quantity) + delta;
+                if adjusted < 0 || adjusted > i64::from(i32::MAX) {
+                    return Err(CommitError::OutOfRange(current()));
+                }
+                Ok(Some(adjusted as u32))
+            }
+            (Operation::Delete, Some(_)) => Ok(None),
+        }
+    }
+}
+
+/// `Committed` is the outcome of a committed
+/// [`Change`](struct.Change.html).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Committed {
+    /// Quantity before the change, `None` if the product was added.
+    pub previous: Option<u32>,
+    /// Quantity after the change, `None` if the product was removed.
+    pub quantity: Option<u32>,
+    /// Version of the product after the change.
+    pub version: u64,
+}
+
+/// `CommitError` describes why a [`Change`](struct.Change.html) can't be
+/// committed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommitError {
+    /// The implementation can't commit changes atomically.
+    Unsupported,
+    /// The product is not in the store.
+    Missing,
+    /// The product to create is already in the store, as given.
+    Exists(proto::Item),
+    /// The product is at another version, the current one is given with
+    /// its quantity.
+    Mismatch(proto::Item),
+    /// The adjustment would leave the quantity of the product, as given,
+    /// negative or too large.
+    OutOfRange(proto::Item),
+}
+
+impl fmt::Display for CommitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommitError::Unsupported => write!(f, "atomic commits are not supported"),
+            CommitError::Missing => write!(f, "the product is not in the store"),
+            CommitError::Exists(item) => write!(
+                f,
+                "the product is already in the store, at version {} with quantity {}",
+                item.version, item.quantity
+            ),
+            CommitError::Mismatch(item) => write!(
+                f,
+                "the product is at version {} with quantity {}",
+                item.version, item.quantity
+            ),
+            CommitError::OutOfRange(item) => write!(
+                f,
+                "the adjustment leaves the quantity out of range, the product is at version {} \
+                 with quantity {}",
+                item.version, item.quantity
+            ),
+        }
+    }
+}
+
+/// `HistoryEntry` records a change of the quantity of a product in a
+/// store.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    /// When the change was made.
+    pub time: time::SystemTime,
+    /// Store of the product.
+    pub store: proto::Store,
+    /// Changed product.
+    pub product: Product,
+    /// Quantity before the change, `None` if the product was added.
+    pub previous: Option<u32>,
+    /// Quantity after the change, `None` if the product was removed.
+    pub quantity: Option<u32>,
+    /// Who made the change.
+    pub actor: String,
+    /// Why the change was made, possibly empty.
+    pub reason: String,
+}
+
+/// `HistoryFilter` selects the [`HistoryEntry`s](struct.HistoryEntry.html)
+/// returned by [`Stock::history`](trait.Stock.html#method.history). Unset
+/// fields match every entry.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HistoryFilter {
+    /// Stores of the changes, every one if empty.
+    pub stores: Vec<proto::Store>,
+    /// Name of the changed product.
+    pub name: Option<String>,
+    /// Earliest time of the changes, inclusive.
+    pub since: Option<time::SystemTime>,
+    /// Latest time of the changes, exclusive.
+    pub until: Option<time::SystemTime>,
+    /// Maximum number of changes, keeping the most recent ones.
+    pub limit: Option<usize>,
+}
+
+impl HistoryFilter {
+    /// Returns whether `entry` matches the filter, regardless of the limit.
+    pub fn matches(&self, entry: &HistoryEntry) -> bool {
+        (self.stores.is_empty() || self.stores.contains(&entry.store))
+            && self.name.iter().all(|name| *name == entry.product.name)
+            && self.since.iter().all(|since| entry.time >= *since)
+            && self.until.iter().all(|until| entry.time < *until)
+    }
 }
 
 /// `StockImpl` is the default implementation for
 /// [`Stock`](trait.Stock.html). It holds a in-memory ephemeral
 /// storage based on rust collection `HashMap`.
+///
+/// Store versions are a counter of changes prefixed by a random epoch, so
+/// versions from a previous process never match the current ones. Every
+/// product of a store also counts its changes, starting at one when it's
+/// first added; the count survives removals, so a product added back
+/// never reuses a version. Every
+/// change is also recorded in a history capped at
+/// [`HISTORY_LIMIT`](constant.HISTORY_LIMIT.html) entries, which past
+/// inventories are reconstructed from. Products added with
+/// [`register`](#method.register) are not recorded, so they are
+/// considered present from the start.
+///
+/// Clones share the same storage, so changes made through the clone held
+/// by a gRPC service are seen by every other one.
 #[derive(Clone)]
 pub struct StockImpl {
-    stores: collections::HashMap<proto::Store, collections::HashMap<Product, u32>>,
+    inventory: Arc<RwLock<Inventory>>,
+    epoch: u32,
     log: Logger,
 }
 
+#[derive(Default)]
+struct Inventory {
+    stores: collections::HashMap<proto::Store, collections::HashMap<Product, u32>>,
+    versions: collections::HashMap<proto::Store, u64>,
+    product_versions: collections::HashMap<proto::Store, collections::HashMap<Product, u64>>,
+    history: collections::VecDeque<HistoryEntry>,
+    // Time of the last change dropped from the history
+    forgotten: Option<time::SystemTime>,
+    // Transfers in transit by ID
+    transfers: collections::HashMap<String, Transfer>,
+    thresholds: collections::HashMap<proto::Store, collections::HashMap<Product, u32>>,
+}
+
+impl Inventory {
+    /// Sets the quantity of `product` in `store` and returns the previous
+    /// one. If it changed, it bumps the store version and records the
+    /// change with the actor and reason of `ctx`.
+    fn put(
+        &mut self,
+        ctx: &Context,
+        store: proto::Store,
+        product: Product,
+        quantity: u32,
+    ) -> Option<u32> {
+        let previous = self
+            .stores
+            .entry(store)
+            .or_default()
+            .insert(product.clone(), quantity);
+        if previous != Some(quantity) {
+            self.record(ctx, store, product, previous, Some(quantity));
+        }
+        previous
+    }
+
+    /// Removes `product` from `store` and returns its quantity. If it was
+    /// there, it bumps the store version and records the change.
+    fn take(&mut self, ctx: &Context, store: proto::Store, product: &Product) -> Option<u32> {
+        let previous = self
+            .stores
+            .get_mut(&store)
+            .and_then(|products| products.remove(product));
+        if previous.is_some() {
+            self.record(ctx, store, product.clone(), previous, None);
+        }
+        previous
+    }
+
+    /// Returns the version of `product` in `store`, zero if it was never
+    /// there.
+    fn product_version(&self, store: proto::Store, product: &Product) -> u64 {
+        self.product_versions
+            .get(&store)
+            .and_then(|products| products.get(product))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the quantity of `product` in `store`, `None` if it's not
+    /// there, and its version.
+    fn line(&self, store: proto::Store, product: &Product) -> (Option<u32>, u64) {
+        let quantity = self
+            .stores
+            .get(&store)
+            .and_then(|products| products.get(product))
+            .copied();
+        (quantity, self.product_version(store, product))
+    }
+
+    /// Returns the quantity the destination of `transfer` holds once it's
+    /// received, or why it can't.
+    fn received(&self, transfer: &Transfer) -> Result<u32, TransferError> {
+        let (quantity, _) = self.line(transfer.to, &transfer.product);
+        let received = u64::from(quantity.unwrap_or_default()) + u64::from(transfer.quantity);
+        if received > i32::MAX as u64 {
+            return Err(TransferError::OutOfRange);
+        }
+        Ok(received as u32)
+    }
+
+    /// Returns the current item of `product` in `store`, if it's there.
+    fn item(&self, store: proto::Store, product: &Product) -> Option<proto::Item> {
+        let quantity = self.stores.get(&store)?.get(product)?;
+        let mut item = product.item(*quantity);
+        item.version = self.product_version(store, product) as i64;
+        Some(item)
+    }
+
+    /// Returns why `update` can't be applied, if the product is not at the
+    /// version it expects.
+    fn check(&self, update: &Update) -> Option<VersionError> {
+        let expected = update.expected_version?;
+        match self.item(update.store, &update.product) {
+            None => Some(VersionError::Missing),
+            Some(item) if item.version as u64 != expected => Some(VersionError::Mismatch(item)),
+            Some(_) => None,
+        }
+    }
+
+    /// Bumps the version of `store` and of `product` in it.
+    fn bump(&mut self, store: proto::Store, product: &Product) {
+        *self.versions.entry(store).or_default() += 1;
+        *self
+            .product_versions
+            .entry(store)
+            .or_default()
+            .entry(product.clone())
+            .or_default() += 1;
+    }
+
+    fn record(
+        &mut self,
+        ctx: &Context,
+        store: proto::Store,
+        product: Product,
+        previous: Option<u32>,
+        quantity: Option<u32>,
+    ) {
+        self.bump(store, &product);
+        if self.history.len() == HISTORY_LIMIT {
+            self.forgotten = self.history.pop_front().map(|entry| entry.time);
+        }
+        self.history.push_back(HistoryEntry {
+            time: time::SystemTime::now(),
+            store,
+            product,
+            previous,
+            quantity,
+            actor: String::from(ctx.actor()),
+            reason: String::from(ctx.reason()),
+        });
+    }
+}
+
 impl Stock for StockImpl {
-    fn get(&self, store: proto::Store, show_unavaible: bool) -> Vec<proto::Item> {
-        trace!(self.log, "Starting get operation on store {:?}", &store);
+    fn get(&self, ctx: &Context, store: proto::Store, show_unavaible: bool) -> Vec<proto::Item> {
+        let mut span = ctx.span().child("StockImpl::get");
+        let log = ctx.logger(&self.log);
+        trace!(log, "Starting get operation on store {:?}", &store);
         let mut answ = vec![];
-        if let Some(products) = self.stores.get(&store) {
+        let inventory = self.read();
+        if let Some(products) = inventory.stores.get(&store) {
             for (product, qt) in products {
                 if *qt <= 0 && !show_unavaible {
                     continue;
                 }
-                let mut item = proto::Item::new();
-                item.name = product.name.clone();
-                item.brand = product.brand.clone();
-                item.category = product.category.clone();
-                item.quantity = *qt as i32;
-                item.availability_description = match *qt {
-                    x if x <= 0 => proto::AvailabilityDescription::UNAVAILABLE,
-                    _ => proto::AvailabilityDescription::AVAILABLE,
-                };
-                trace!(self.log, "Found item {:?}", &item);
+                let mut item = product.item(*qt);
+                item.version = inventory.product_version(store, product) as i64;
+                trace!(log, "Found item {:?}", &item);
                 answ.push(item);
             }
         }
         debug!(
-            self.log,
+            log,
             "Get operation on {:?} returned {} items",
             &store,
             answ.len()
         );
+        span.set_attribute("stock.items", answ.len());
         answ
     }
+
+    fn get_as_of(
+        &self,
+        ctx: &Context,
+        store: proto::Store,
+        show_unavailable: bool,
+        as_of: time::SystemTime,
+    ) -> Result<Vec<proto::Item>, AsOfError> {
+        let mut span = ctx.span().child("StockImpl::get_as_of");
+        let log = ctx.logger(&self.log);
+        let inventory = self.read();
+        if let Some(forgotten) = inventory.forgotten {
+            if as_of < forgotten {
+                return Err(AsOfError::Forgotten(forgotten));
+            }
+        }
+        // Undoes the changes made after `as_of`, latest first, counting them
+        // per product since every change bumped its version once
+        let mut products = inventory.stores.get(&store).cloned().unwrap_or_default();
+        let mut undone_changes: collections::HashMap<&Product, u64> = collections::HashMap::new();
+        let mut undone = 0;
+        for entry in inventory.history.iter().rev() {
+            if entry.time <= as_of {
+                break;
+            }
+            if entry.store != store {
+                continue;
+            }
+            match entry.previous {
+                Some(quantity) => products.insert(entry.product.clone(), quantity),
+                None => products.remove(&entry.product),
+            };
+            *undone_changes.entry(&entry.product).or_default() += 1;
+            undone += 1;
+        }
+        let items: Vec<proto::Item> = products
+            .into_iter()
+            .filter(|(_, quantity)| *quantity > 0 || show_unavailable)
+            .map(|(product, quantity)| {
+                let mut item = product.item(quantity);
+                let version = inventory.product_version(store, &product);
+                let undone = undone_changes.get(&product).copied().unwrap_or_default();
+                item.version = version.saturating_sub(undone) as i64;
+                item
+            })
+            .collect();
+        debug!(
+            log,
+            "Get operation on {:?} as of {:?} returned {} items",
+            &store,
+            as_of,
+            items.len();
+            "undone" => undone,
+        );
+        span.set_attribute("stock.items", items.len());
+        Ok(items)
+    }
+
+    fn set(
+        &self,
+        ctx: &Context,
+        store: proto::Store,
+        product: Product,
+        quantity: u32,
+    ) -> Option<u32> {
+        let _span = ctx.span().child("StockImpl::set");
+        let log = ctx.logger(&self.log);
+        let previous = self.write().put(ctx, store, product.clone(), quantity);
+        debug!(log, "Set {} of '{:?}' on '{:?}'", quantity, &product, store;
+            "previous" => previous,
+        );
+        previous
+    }
+
+    fn remove(&self, ctx: &Context, store: proto::Store, product: &Product) -> Option<u32> {
+        let _span = ctx.span().child("StockImpl::remove");
+        let log = ctx.logger(&self.log);
+        let previous = self.write().take(ctx, store, product);
+        debug!(log, "Removed '{:?}' from '{:?}'", product, store;
+            "previous" => previous,
+        );
+        previous
+    }
+
+    fn set_many(
+        &self,
+        ctx: &Context,
+        rows: Vec<(proto::Store, Product, Option<u32>)>,
+    ) -> Vec<Option<u32>> {
+        let mut span = ctx.span().child("StockImpl::set_many");
+        let log = ctx.logger(&self.log);
+        span.set_attribute("stock.items", rows.len());
+        let count = rows.len();
+        let mut inventory = self.write();
+        let previous = rows
+            .into_iter()
+            .map(|(store, product, quantity)| match quantity {
+                Some(quantity) => inventory.put(ctx, store, product, quantity),
+                None => inventory.take(ctx, store, &product),
+            })
+            .collect();
+        debug!(log, "Set or removed {} products", count);
+        previous
+    }
+
+    fn update_many(&self, ctx: &Context, updates: Vec<Update>) -> UpdateResult {
+        let mut span = ctx.span().child("StockImpl::update_many");
+        let log = ctx.logger(&self.log);
+        span.set_attribute("stock.items", updates.len());
+        let mut inventory = self.write();
+        let conflicts: Vec<(usize, VersionError)> = updates
+            .iter()
+            .enumerate()
+            .filter_map(|(i, update)| inventory.check(update).map(|err| (i, err)))
+            .collect();
+        if !conflicts.is_empty() {
+            debug!(log, "Rejected {} updates", updates.len();
+                "conflicts" => conflicts.len(),
+            );
+            return Err(conflicts);
+        }
+        let count = updates.len();
+        let results = updates
+            .into_iter()
+            .map(|update| {
+                let previous = match update.quantity {
+                    Some(quantity) => {
+                        inventory.put(ctx, update.store, update.product.clone(), quantity)
+                    }
+                    None => inventory.take(ctx, update.store, &update.product),
+                };
+                let version = inventory.product_version(update.store, &update.product);
+                (previous, version)
+            })
+            .collect();
+        debug!(log, "Updated {} products", count);
+        Ok(results)
+    }
+
+    fn transfer(
+        &self,
+        ctx: &Context,
+        transfer: Transfer,
+        in_transit: bool,
+    ) -> Result<Transferred, TransferError> {
+        let mut span = ctx.span().child("StockImpl::transfer");
+        let log = ctx.logger(&self.log);
+        span.set_attribute("stock.items", transfer.quantity as usize);
+        if transfer.quantity == 0 {
+            return Err(TransferError::Empty);
+        }
+        if transfer.from == transfer.to {
+            return Err(TransferError::SameStore);
+        }
+        let mut inventory = self.write();
+        let available = match inventory.line(transfer.from, &transfer.product) {
+            (None, _) => return Err(TransferError::Missing),
+            (Some(available), _) if available < transfer.quantity => {
+                return Err(TransferError::Insufficient(available))
+            }
+            (Some(available), _) => available,
+        };
+        // The destination is checked before the source changes
+        let received = if in_transit {
+            None
+        } else {
+            Some(inventory.received(&transfer)?)
+        };
+
+        inventory.put(
+            ctx,
+            transfer.from,
+            transfer.product.clone(),
+            available - transfer.quantity,
+        );
+        let source = inventory.item(transfer.from, &transfer.product);
+        let id = match received {
+            Some(received) => {
+                inventory.put(ctx, transfer.to, transfer.product.clone(), received);
+                None
+            }
+            None => Some(uuid::Uuid::new_v4().to_string()),
+        };
+        let destination = match &id {
+            Some(_) => None,
+            None => inventory.item(transfer.to, &transfer.product),
+        };
+        debug!(log, "Transferred {} of '{:?}' from '{:?}' to '{:?}'",
+            transfer.quantity, &transfer.product, transfer.from, transfer.to;
+            "transfer_id" => &id,
+        );
+        if let Some(id) = &id {
+            inventory.transfers.insert(id.clone(), transfer);
+        }
+        Ok(Transferred {
+            id,
+            source: source.unwrap_or_default(),
+            destination,
+        })
+    }
+
+    fn receive_transfer(
+        &self,
+        ctx: &Context,
+        id: &str,
+    ) -> Result<(Transfer, proto::Item), TransferError> {
+        let _span = ctx.span().child("StockImpl::receive_transfer");
+        let log = ctx.logger(&self.log);
+        let mut inventory = self.write();
+        let transfer = inventory
+            .transfers
+            .get(id)
+            .cloned()
+            .ok_or_else(|| TransferError::Unknown(String::from(id)))?;
+        let received = inventory.received(&transfer)?;
+        inventory.transfers.remove(id);
+        inventory.put(ctx, transfer.to, transfer.product.clone(), received);
+        debug!(log, "Received {} of '{:?}' on '{:?}'",
+            transfer.quantity, &transfer.product, transfer.to;
+            "transfer_id" => id,
+        );
+        let destination = inventory
+            .item(transfer.to, &transfer.product)
+            .unwrap_or_default();
+        Ok((transfer, destination))
+    }
+
+    fn commit(
+        &self,
+        ctx: &Context,
+        changes: Vec<Change>,
+    ) -> Result<Vec<Committed>, Vec<(usize, CommitError)>> {
+        let mut span = ctx.span().child("StockImpl::commit");
+        let log = ctx.logger(&self.log);
+        span.set_attribute("stock.items", changes.len());
+        let mut inventory = self.write();
+
+        // Every change is checked against the outcome of the previous ones
+        // before any is applied
+        let mut outcomes: collections::HashMap<(proto::Store, &Product), (Option<u32>, u64)> =
+            collections::HashMap::new();
+        let mut quantities = Vec::with_capacity(changes.len());
+        let mut errors = vec![];
+        for (i, change) in changes.iter().enumerate() {
+            let key = (change.store, &change.product);
+            let (quantity, version) = outcomes
+                .get(&key)
+                .copied()
+                .unwrap_or_else(|| inventory.line(change.store, &change.product));
+            match change.apply(quantity, version) {
+                Ok(next) => {
+                    if next != quantity {
+                        outcomes.insert(key, (next, version + 1));
+                    }
+                    quantities.push(next);
+                }
+                Err(err) => errors.push((i, err)),
+            }
+        }
+        if !errors.is_empty() {
+            debug!(log, "Rejected a commit of {} changes", changes.len();
+                "errors" => errors.len(),
+            );
+            return Err(errors);
+        }
+
+        let count = changes.len();
+        let committed = changes
+            .into_iter()
+            .zip(quantities)
+            .map(|(change, next)| {
+                let previous = match next {
+                    Some(next) => inventory.put(ctx, change.store, change.product.clone(), next),
+                    None => inventory.take(ctx, change.store, &change.product),
+                };
+                Committed {
+                    previous,
+                    quantity: next,
+                    version: inventory.product_version(change.store, &change.product),
+                }
+            })
+            .collect();
+        debug!(log, "Committed {} changes", count);
+        Ok(committed)
+    }
+
+    fn history(&self, filter: &HistoryFilter) -> Vec<HistoryEntry> {
+        let inventory = self.read();
+        let mut entries: Vec<HistoryEntry> = inventory
+            .history
+            .iter()
+            .rev()
+            .filter(|entry| filter.matches(entry))
+            .take(filter.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect();
+        entries.reverse();
+        entries
+    }
+
+    fn version(&self, store: proto::Store) -> Option<String> {
+        let version = self
+            .read()
+            .versions
+            .get(&store)
+            .copied()
+            .unwrap_or_default();
+        Some(format!("{:08x}-{}", self.epoch, version))
+    }
+
+    fn stores(&self) -> Vec<proto::Store> {
+        let mut stores: Vec<proto::Store> = self.read().stores.keys().copied().collect();
+        stores.sort_by_key(|store| store.value());
+        stores
+    }
+
+    fn thresholds(&self) -> Vec<Threshold> {
+        let inventory = self.read();
+        let mut thresholds = vec![];
+        for (store, products) in &inventory.thresholds {
+            for (product, threshold) in products {
+                let (quantity, _) = inventory.line(*store, product);
+                thresholds.push(Threshold {
+                    store: *store,
+                    product: product.clone(),
+                    threshold: *threshold,
+                    quantity: quantity.unwrap_or_default(),
+                });
+            }
+        }
+        thresholds.sort_by(|a, b| {
+            (a.store.value(), a.product.name(), a.product.brand()).cmp(&(
+                b.store.value(),
+                b.product.name(),
+                b.product.brand(),
+            ))
+        });
+        thresholds
+    }
 }
 
 impl StockImpl {
@@ -94,7 +1183,8 @@ impl StockImpl {
     /// .
     pub fn new(log: Logger) -> Self {
         Self {
-            stores: collections::HashMap::new(),
+            inventory: Arc::new(RwLock::new(Inventory::default())),
+            epoch: rand::random(),
             log,
         }
     }
@@ -105,7 +1195,9 @@ impl StockImpl {
     ///
     /// [0]: proto/stock/enum.Store.html
     pub fn register(&mut self, pdct: Product, store: proto::Store, qt: u32) {
-        if let Some(products) = self.stores.get_mut(&store) {
+        let mut inventory = self.write();
+        inventory.bump(store, &pdct);
+        if let Some(products) = inventory.stores.get_mut(&store) {
             debug!(self.log, "Store '{:?}' already present on the stock", store);
             trace!(self.log, "Adding {} of '{:?}' to '{:?}'", qt, &pdct, store);
             products.insert(pdct, qt);
@@ -117,9 +1209,58 @@ impl StockImpl {
             let mut products = collections::HashMap::new();
             trace!(self.log, "Adding {} of '{:?}' to '{:?}'", qt, &pdct, store);
             products.insert(pdct, qt);
-            self.stores.insert(store, products);
+            inventory.stores.insert(store, products);
+        }
+    }
+
+    /// Returns whether `product` is in `store`.
+    pub fn contains(&self, store: proto::Store, product: &Product) -> bool {
+        match self.read().stores.get(&store) {
+            Some(products) => products.contains_key(product),
+            None => false,
+        }
+    }
+
+    /// Sets the reorder threshold of `product` in `store`, or clears it if
+    /// `threshold` is `None`, and returns the previous one. The threshold
+    /// is kept when the product is removed from the store, so running out
+    /// of it is still noticed.
+    pub fn set_threshold(
+        &self,
+        store: proto::Store,
+        product: Product,
+        threshold: Option<u32>,
+    ) -> Option<u32> {
+        let mut inventory = self.write();
+        let products = inventory.thresholds.entry(store).or_default();
+        match threshold {
+            Some(threshold) => {
+                trace!(
+                    self.log,
+                    "Setting threshold {} of '{:?}' on '{:?}'",
+                    threshold,
+                    &product,
+                    store
+                );
+                products.insert(product, threshold)
+            }
+            None => products.remove(&product),
         }
     }
+
+    // A panic while holding the lock can't leave the maps half updated, so
+    // poisoning is ignored.
+    fn read(&self) -> RwLockReadGuard<'_, Inventory> {
+        self.inventory
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Inventory> {
+        self.inventory
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
 }
 
 /// `Product` is a simple representation of a product.
@@ -140,6 +1281,36 @@ impl Product {
             category: String::from(category),
         }
     }
+
+    /// Returns the product name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the product brand.
+    pub fn brand(&self) -> &str {
+        &self.brand
+    }
+
+    /// Returns the product category.
+    pub fn category(&self) -> &str {
+        &self.category
+    }
+
+    /// Returns the [`Item`](proto/stock/struct.Item.html) describing
+    /// `quantity` units of the product.
+    pub fn item(&self, quantity: u32) -> proto::Item {
+        let mut item = proto::Item::new();
+        item.name = self.name.clone();
+        item.brand = self.brand.clone();
+        item.category = self.category.clone();
+        item.quantity = quantity as i32;
+        item.availability_description = match quantity {
+            0 => proto::AvailabilityDescription::UNAVAILABLE,
+            _ => proto::AvailabilityDescription::AVAILABLE,
+        };
+        item
+    }
 }
 
 #[cfg(test)]
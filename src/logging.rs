@@ -0,0 +1,243 @@
+use std::sync::{atomic, Arc};
+use std::{fmt, fs, io, path, str};
+
+use serde::{Deserialize, Serialize};
+use slog::{Drain, Level};
+
+/// `Format` defines how log records are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Human readable compact format, the default for terminals.
+    Text,
+    /// One JSON object per line, suitable for log shippers.
+    Json,
+}
+
+impl str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(String::from("Invalid log format, expected text or json")),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Text => write!(f, "text"),
+            Format::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Parses a log level such as `info` or `DEBUG`. It's meant to be used as
+/// a `structopt` parser, as
+/// [slog::Level](https://docs.rs/slog/2.5.2/slog/enum.Level.html)'s own
+/// `FromStr` implementation has no descriptive error.
+pub fn parse_level(s: &str) -> Result<Level, String> {
+    s.parse().map_err(|_| {
+        String::from(
+            "Invalid log level, expected one of trace, debug, info, warn, error or critical",
+        )
+    })
+}
+
+/// `LevelHandle` holds the minimum level of records to be logged. It can
+/// be cloned and changed at runtime, affecting every
+/// [`LevelFilter`](struct.LevelFilter.html) created from it.
+#[derive(Clone, Debug)]
+pub struct LevelHandle {
+    level: Arc<atomic::AtomicUsize>,
+}
+
+impl LevelHandle {
+    /// Returns a new `LevelHandle` set to `level`.
+    pub fn new(level: Level) -> Self {
+        Self {
+            level: Arc::new(atomic::AtomicUsize::new(level.as_usize())),
+        }
+    }
+
+    /// Returns the current level.
+    pub fn get(&self) -> Level {
+        Level::from_usize(self.level.load(atomic::Ordering::Relaxed)).unwrap_or(Level::Info)
+    }
+
+    /// Sets the current level.
+    pub fn set(&self, level: Level) {
+        self.level
+            .store(level.as_usize(), atomic::Ordering::Relaxed);
+    }
+
+    /// Makes logging one level more verbose, up to `Trace`, and returns
+    /// the new level.
+    pub fn increase(&self) -> Level {
+        let level = Level::from_usize(self.get().as_usize() + 1).unwrap_or(Level::Trace);
+        self.set(level);
+        level
+    }
+
+    /// Makes logging one level less verbose, down to `Critical`, and
+    /// returns the new level.
+    pub fn decrease(&self) -> Level {
+        let level = Level::from_usize(self.get().as_usize() - 1).unwrap_or(Level::Critical);
+        self.set(level);
+        level
+    }
+}
+
+/// `LevelFilter` is a
+/// [slog::Drain](https://docs.rs/slog/2.5.2/slog/trait.Drain.html) that
+/// drops records below the level of a
+/// [`LevelHandle`](struct.LevelHandle.html).
+pub struct LevelFilter<D: Drain> {
+    drain: D,
+    level: LevelHandle,
+}
+
+impl<D: Drain> LevelFilter<D> {
+    /// Returns a new `LevelFilter` wrapping `drain`.
+    pub fn new(drain: D, level: LevelHandle) -> Self {
+        Self { drain, level }
+    }
+}
+
+impl<D: Drain> Drain for LevelFilter<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        if self.is_enabled(record.level()) {
+            self.drain.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn is_enabled(&self, level: Level) -> bool {
+        level.is_at_least(self.level.get()) && self.drain.is_enabled(level)
+    }
+}
+
+/// `RootDrain` is the drain returned by [`drain`](fn.drain.html).
+pub type RootDrain = slog::Fuse<LevelFilter<slog::Fuse<slog_async::Async>>>;
+
+/// Returns the root drain for the server logs, writing records in
+/// `format` to `file`, or to the terminal if no file is given. Records are
+/// written asynchronously and filtered by `level`.
+///
+/// Dropping the returned
+/// [slog_async::AsyncGuard](https://docs.rs/slog-async/2.4.0/slog_async/struct.AsyncGuard.html)
+/// flushes the pending records, so it must be kept until the process
+/// exits.
+pub fn drain(
+    format: Format,
+    file: Option<&path::Path>,
+    level: LevelHandle,
+) -> io::Result<(RootDrain, slog_async::AsyncGuard)> {
+    let drain = match (format, file) {
+        (Format::Text, None) => {
+            let decorator = slog_term::TermDecorator::new().build();
+            slog_async::Async::new(slog_term::CompactFormat::new(decorator).build().fuse())
+                .build_with_guard()
+        }
+        (Format::Text, Some(path)) => {
+            let decorator = slog_term::PlainDecorator::new(open(path)?);
+            slog_async::Async::new(slog_term::CompactFormat::new(decorator).build().fuse())
+                .build_with_guard()
+        }
+        (Format::Json, None) => slog_async::Async::new(json(io::stderr())).build_with_guard(),
+        (Format::Json, Some(path)) => slog_async::Async::new(json(open(path)?)).build_with_guard(),
+    };
+    let (drain, guard) = drain;
+    Ok((LevelFilter::new(drain.fuse(), level).fuse(), guard))
+}
+
+fn json<W: io::Write + Send + 'static>(io: W) -> slog::Fuse<slog_json::Json<W>> {
+    slog_json::Json::new(io).add_default_keys().build().fuse()
+}
+
+fn open(path: &path::Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct CountDrain {
+        count: Arc<Mutex<usize>>,
+    }
+
+    impl Drain for CountDrain {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, _: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+            *self.count.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_format_from_str() {
+        assert_eq!("text".parse::<Format>(), Ok(Format::Text));
+        assert_eq!("json".parse::<Format>(), Ok(Format::Json));
+        assert!("yaml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(parse_level("info"), Ok(Level::Info));
+        assert_eq!(parse_level("TRACE"), Ok(Level::Trace));
+        assert_eq!(parse_level("warn"), Ok(Level::Warning));
+        assert!(parse_level("off").is_err());
+        assert!(parse_level("loud").is_err());
+    }
+
+    #[test]
+    fn test_level_handle() {
+        let level = LevelHandle::new(Level::Debug);
+        assert_eq!(level.increase(), Level::Trace);
+        assert_eq!(level.increase(), Level::Trace);
+
+        level.set(Level::Error);
+        assert_eq!(level.decrease(), Level::Critical);
+        assert_eq!(level.decrease(), Level::Critical);
+        assert_eq!(level.get(), Level::Critical);
+    }
+
+    #[test]
+    fn test_level_filter() {
+        let count = Arc::new(Mutex::new(0));
+        let level = LevelHandle::new(Level::Info);
+        let log = slog::Logger::root(
+            LevelFilter::new(
+                CountDrain {
+                    count: count.clone(),
+                },
+                level.clone(),
+            )
+            .fuse(),
+            o!(),
+        );
+
+        debug!(log, "filtered");
+        info!(log, "logged");
+        assert_eq!(*count.lock().unwrap(), 1);
+
+        level.set(Level::Trace);
+        trace!(log, "logged");
+        assert_eq!(*count.lock().unwrap(), 2);
+    }
+}
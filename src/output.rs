@@ -0,0 +1,692 @@
+use std::{fmt, io, str};
+
+use chrono::{SecondsFormat, TimeZone, Utc};
+use prettytable::{format, Cell, Row, Table};
+use serde::{Deserialize, Serialize};
+
+use crate::proto;
+
+/// `Format` defines how the client writes results.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Aligned columns under a title, for humans.
+    Table,
+    /// A JSON array of objects.
+    Json,
+    /// A YAML sequence of mappings.
+    Yaml,
+    /// Comma separated values.
+    Csv,
+    /// Tab separated values.
+    Tsv,
+}
+
+impl str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, Self::Err> {
+        match s {
+            "table" => Ok(Format::Table),
+            "json" => Ok(Format::Json),
+            "yaml" => Ok(Format::Yaml),
+            "csv" => Ok(Format::Csv),
+            "tsv" => Ok(Format::Tsv),
+            _ => Err(String::from(
+                "Invalid output format, expected one of table, json, yaml, csv or tsv",
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Table => write!(f, "table"),
+            Format::Json => write!(f, "json"),
+            Format::Yaml => write!(f, "yaml"),
+            Format::Csv => write!(f, "csv"),
+            Format::Tsv => write!(f, "tsv"),
+        }
+    }
+}
+
+/// `ItemRecord` is the output representation of an
+/// [`Item`](../proto/stock/struct.Item.html). Its field names are part of
+/// the client output and must stay stable.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ItemRecord {
+    /// Product name.
+    pub name: String,
+    /// Product brand.
+    pub brand: String,
+    /// Product category.
+    pub category: String,
+    /// `AVAILABLE` or `UNAVAILABLE`.
+    pub status: String,
+    /// Quantity in stock.
+    pub quantity: i32,
+    /// Version of the product in the store.
+    pub version: i64,
+}
+
+impl From<&proto::Item> for ItemRecord {
+    fn from(item: &proto::Item) -> Self {
+        Self {
+            name: item.name.clone(),
+            brand: item.brand.clone(),
+            category: item.category.clone(),
+            status: format!("{:?}", item.availability_description),
+            quantity: item.quantity,
+            version: item.version,
+        }
+    }
+}
+
+/// `HistoryRecord` is the output representation of a
+/// [`HistoryEntry`](../proto/stock/struct.HistoryEntry.html). Its field
+/// names are part of the client output and must stay stable.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HistoryRecord {
+    /// RFC 3339 UTC time of the change.
+    pub time: String,
+    /// Store of the product.
+    pub store: String,
+    /// Product name.
+    pub name: String,
+    /// Product brand.
+    pub brand: String,
+    /// Product category.
+    pub category: String,
+    /// Quantity before the change, unset if the product was added.
+    pub previous: Option<i32>,
+    /// Quantity after the change, unset if the product was removed.
+    pub quantity: Option<i32>,
+    /// Who made the change.
+    pub actor: String,
+    /// Why the change was made.
+    pub reason: String,
+}
+
+impl HistoryRecord {
+    /// Field names, in order.
+    pub const FIELDS: [&'static str; 9] = [
+        "time", "store", "name", "brand", "category", "previous", "quantity", "actor", "reason",
+    ];
+}
+
+impl From<&proto::HistoryEntry> for HistoryRecord {
+    fn from(entry: &proto::HistoryEntry) -> Self {
+        Self {
+            time: format_time(entry.timestamp),
+            store: format!("{:?}", entry.store),
+            name: entry.name.clone(),
+            brand: entry.brand.clone(),
+            category: entry.category.clone(),
+            previous: Some(entry.previous_quantity).filter(|_| !entry.created),
+            quantity: Some(entry.quantity).filter(|_| !entry.removed),
+            actor: entry.actor.clone(),
+            reason: entry.reason.clone(),
+        }
+    }
+}
+
+/// Returns the RFC 3339 UTC time of `millis` Unix milliseconds, as written
+/// by the client.
+pub fn format_time(millis: i64) -> String {
+    Utc.timestamp_millis(millis)
+        .to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// `Column` is a field of the items output, as selected with the client
+/// `--columns` option.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Column {
+    /// Product name.
+    Name,
+    /// Product brand.
+    Brand,
+    /// Product category.
+    Category,
+    /// Availability status.
+    Status,
+    /// Quantity in stock.
+    Quantity,
+    /// Version of the product, only written when selected.
+    Version,
+}
+
+impl Column {
+    /// Every column.
+    pub const ALL: [Column; 6] = [
+        Column::Name,
+        Column::Brand,
+        Column::Category,
+        Column::Status,
+        Column::Quantity,
+        Column::Version,
+    ];
+
+    /// Columns written when none are selected, in order.
+    pub const DEFAULT: [Column; 5] = [
+        Column::Name,
+        Column::Brand,
+        Column::Category,
+        Column::Status,
+        Column::Quantity,
+    ];
+
+    /// Returns the field name of the column, as used by the structured
+    /// formats, CSV headers and templates.
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Name => "name",
+            Column::Brand => "brand",
+            Column::Category => "category",
+            Column::Status => "status",
+            Column::Quantity => "quantity",
+            Column::Version => "version",
+        }
+    }
+
+    fn header(self) -> &'static str {
+        match self {
+            Column::Name => "PRODUCT",
+            Column::Brand => "BRAND",
+            Column::Category => "CATEGORY",
+            Column::Status => "STATUS",
+            Column::Quantity => "AVAILABLE",
+            Column::Version => "VERSION",
+        }
+    }
+
+    fn value(self, record: &ItemRecord) -> String {
+        match self {
+            Column::Name => record.name.clone(),
+            Column::Brand => record.brand.clone(),
+            Column::Category => record.category.clone(),
+            Column::Status => record.status.clone(),
+            Column::Quantity => record.quantity.to_string(),
+            Column::Version => record.version.to_string(),
+        }
+    }
+}
+
+impl str::FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Column, Self::Err> {
+        Column::ALL
+            .iter()
+            .find(|c| c.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "Invalid column {:?}, expected one of name, brand, category, status, quantity \
+                     or version",
+                    s
+                )
+            })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Field(Column),
+}
+
+/// `Template` renders every item as a line of text, replacing `{{column}}`
+/// placeholders by the item values, e.g. `{{name}}\t{{quantity}}`. The
+/// `\t`, `\n` and `\\` escapes are supported so templates can be written
+/// in single quoted shell arguments.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    fn render(&self, record: &ItemRecord) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Field(column) => column.value(record),
+            })
+            .collect()
+    }
+}
+
+impl str::FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Template, Self::Err> {
+        let mut parts = vec![];
+        let mut rest = s;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                parts.push(Part::Text(unescape(&rest[..start])));
+            }
+            let end = rest[start..]
+                .find("}}")
+                .ok_or_else(|| format!("Invalid template, unclosed {{{{ in {:?}", s))?;
+            parts.push(Part::Field(rest[start + 2..start + end].trim().parse()?));
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(unescape(rest)));
+        }
+        Ok(Template { parts })
+    }
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some('\\') => out.push('\\'),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// `Printer` writes the client results according to its output options.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Printer {
+    /// Output format, ignored if a template is set.
+    pub format: Format,
+    /// Whether to write the title and column headers of the table format
+    /// and the header row of the CSV and TSV formats.
+    pub headers: bool,
+    /// Columns to write, in order.
+    pub columns: Vec<Column>,
+    /// Template rendering every item, overriding the format.
+    pub template: Option<Template>,
+}
+
+impl Default for Printer {
+    fn default() -> Self {
+        Self {
+            format: Format::Table,
+            headers: true,
+            columns: Column::DEFAULT.to_vec(),
+            template: None,
+        }
+    }
+}
+
+struct Selected<'a> {
+    record: &'a ItemRecord,
+    columns: &'a [Column],
+}
+
+impl<'a> Serialize for Selected<'a> {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let mut map = s.serialize_map(Some(self.columns.len()))?;
+        for column in self.columns {
+            match column {
+                Column::Quantity => map.serialize_entry(column.name(), &self.record.quantity)?,
+                Column::Version => map.serialize_entry(column.name(), &self.record.version)?,
+                _ => map.serialize_entry(column.name(), &column.value(self.record))?,
+            }
+        }
+        map.end()
+    }
+}
+
+impl Printer {
+    /// Writes `items` to `w`. The `title` is only written in the table
+    /// format.
+    pub fn write_items<W: io::Write>(
+        &self,
+        w: &mut W,
+        title: &str,
+        items: &[proto::Item],
+    ) -> io::Result<()> {
+        let records: Vec<ItemRecord> = items.iter().map(ItemRecord::from).collect();
+        if let Some(template) = &self.template {
+            for record in &records {
+                writeln!(w, "{}", template.render(record))?;
+            }
+            return Ok(());
+        }
+
+        let selected: Vec<Selected> = records
+            .iter()
+            .map(|record| Selected {
+                record,
+                columns: &self.columns,
+            })
+            .collect();
+        match self.format {
+            Format::Table => {
+                if self.headers {
+                    writeln!(w, "{}", title)?;
+                }
+                let mut table = Table::new();
+                table.set_format(format::FormatBuilder::new().padding(0, 4).build());
+                if self.headers {
+                    table.add_row(Row::new(
+                        self.columns.iter().map(|c| Cell::new(c.header())).collect(),
+                    ));
+                }
+                for record in &records {
+                    table.add_row(Row::new(
+                        self.columns
+                            .iter()
+                            .map(|c| Cell::new(&c.value(record)))
+                            .collect(),
+                    ));
+                }
+                table.print(w).map(|_| ())
+            }
+            Format::Json => {
+                serde_json::to_writer_pretty(&mut *w, &selected)?;
+                writeln!(w)
+            }
+            Format::Yaml => {
+                serde_yaml::to_writer(&mut *w, &selected)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                writeln!(w)
+            }
+            Format::Csv | Format::Tsv => {
+                let delimiter = if self.format == Format::Csv {
+                    b','
+                } else {
+                    b'\t'
+                };
+                let mut writer = csv::WriterBuilder::new()
+                    .delimiter(delimiter)
+                    .from_writer(w);
+                if self.headers {
+                    writer.write_record(self.columns.iter().map(|c| c.name()))?;
+                }
+                for record in &records {
+                    writer.write_record(self.columns.iter().map(|c| c.value(record)))?;
+                }
+                writer.flush()
+            }
+        }
+    }
+
+    /// Writes the history `entries` to `w`. The `title` is only written in
+    /// the table format; columns and templates only apply to items.
+    pub fn write_history<W: io::Write>(
+        &self,
+        w: &mut W,
+        title: &str,
+        entries: &[proto::HistoryEntry],
+    ) -> io::Result<()> {
+        let records: Vec<HistoryRecord> = entries.iter().map(HistoryRecord::from).collect();
+        match self.format {
+            Format::Table => {
+                if self.headers {
+                    writeln!(w, "{}", title)?;
+                }
+                let mut table = Table::new();
+                table.set_format(format::FormatBuilder::new().padding(0, 4).build());
+                if self.headers {
+                    table.add_row(Row::new(
+                        [
+                            "TIME", "STORE", "PRODUCT", "BRAND", "CATEGORY", "FROM", "TO", "ACTOR",
+                            "REASON",
+                        ]
+                        .iter()
+                        .map(|header| Cell::new(header))
+                        .collect(),
+                    ));
+                }
+                let quantity = |quantity: Option<i32>| {
+                    quantity.map_or_else(|| String::from("-"), |q| q.to_string())
+                };
+                for record in &records {
+                    table.add_row(Row::new(
+                        [
+                            record.time.clone(),
+                            record.store.clone(),
+                            record.name.clone(),
+                            record.brand.clone(),
+                            record.category.clone(),
+                            quantity(record.previous),
+                            quantity(record.quantity),
+                            record.actor.clone(),
+                            record.reason.clone(),
+                        ]
+                        .iter()
+                        .map(|value| Cell::new(value))
+                        .collect(),
+                    ));
+                }
+                table.print(w).map(|_| ())
+            }
+            Format::Json => {
+                serde_json::to_writer_pretty(&mut *w, &records)?;
+                writeln!(w)
+            }
+            Format::Yaml => {
+                serde_yaml::to_writer(&mut *w, &records)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                writeln!(w)
+            }
+            Format::Csv | Format::Tsv => {
+                let delimiter = if self.format == Format::Csv {
+                    b','
+                } else {
+                    b'\t'
+                };
+                // Serialized headers are only written along with a record
+                let mut writer = csv::WriterBuilder::new()
+                    .delimiter(delimiter)
+                    .has_headers(false)
+                    .from_writer(w);
+                if self.headers {
+                    writer.write_record(&HistoryRecord::FIELDS)?;
+                }
+                for record in &records {
+                    writer.serialize(record)?;
+                }
+                writer.flush()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items() -> Vec<proto::Item> {
+        let mut item = proto::Item::new();
+        item.name = String::from("Laptop air");
+        item.brand = String::from("AbCorp");
+        item.category = String::from("computers/laptops");
+        item.quantity = 8;
+        item.availability_description = proto::AvailabilityDescription::AVAILABLE;
+        vec![item]
+    }
+
+    fn print(printer: Printer) -> String {
+        let mut out = vec![];
+        printer
+            .write_items(&mut out, "Products available on BERLIN_DE", &items())
+            .expect("Could not write items");
+        String::from_utf8(out).unwrap()
+    }
+
+    fn write(format: Format, headers: bool) -> String {
+        print(Printer {
+            format,
+            headers,
+            ..Printer::default()
+        })
+    }
+
+    #[test]
+    fn test_format_from_str() {
+        assert_eq!("table".parse::<Format>(), Ok(Format::Table));
+        assert_eq!("tsv".parse::<Format>(), Ok(Format::Tsv));
+        assert!("xml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_write_items_table() {
+        let out = write(Format::Table, true);
+        assert!(out.starts_with("Products available on BERLIN_DE\n"));
+        assert!(out.contains("PRODUCT"));
+        assert!(out.contains("Laptop air"));
+
+        let out = write(Format::Table, false);
+        assert!(!out.contains("Products available"));
+        assert!(!out.contains("PRODUCT"));
+        assert!(out.contains("Laptop air"));
+    }
+
+    #[test]
+    fn test_write_items_json() {
+        let out: serde_json::Value = serde_json::from_str(&write(Format::Json, true)).unwrap();
+        assert_eq!(
+            out,
+            serde_json::json!([{
+                "name": "Laptop air",
+                "brand": "AbCorp",
+                "category": "computers/laptops",
+                "status": "AVAILABLE",
+                "quantity": 8
+            }])
+        );
+    }
+
+    #[test]
+    fn test_write_items_yaml() {
+        let out = write(Format::Yaml, true);
+        assert!(out.contains("name: Laptop air"));
+        assert!(out.contains("status: AVAILABLE"));
+        assert!(!out.contains("Products available"));
+    }
+
+    #[test]
+    fn test_write_items_csv() {
+        assert_eq!(
+            write(Format::Csv, true),
+            "name,brand,category,status,quantity\nLaptop air,AbCorp,computers/laptops,AVAILABLE,8\n"
+        );
+        assert_eq!(
+            write(Format::Tsv, false),
+            "Laptop air\tAbCorp\tcomputers/laptops\tAVAILABLE\t8\n"
+        );
+    }
+
+    fn history() -> Vec<proto::HistoryEntry> {
+        let mut entry = proto::HistoryEntry::new();
+        entry.timestamp = 1_600_000_000_123;
+        entry.store = proto::Store::VENEZA_IT;
+        entry.name = String::from("Laptop air");
+        entry.brand = String::from("AbCorp");
+        entry.category = String::from("computers/laptops");
+        entry.quantity = 8;
+        entry.created = true;
+        entry.actor = String::from("alice");
+        entry.reason = String::from("rental");
+        vec![entry]
+    }
+
+    #[test]
+    fn test_write_history() {
+        let write = |format: Format| {
+            let mut out = vec![];
+            Printer {
+                format,
+                ..Printer::default()
+            }
+            .write_history(&mut out, "History of VENEZA_IT", &history())
+            .expect("Could not write history");
+            String::from_utf8(out).unwrap()
+        };
+
+        let out = write(Format::Table);
+        assert!(out.starts_with("History of VENEZA_IT\n"));
+        assert!(out.contains("2020-09-13T12:26:40.123Z"));
+        assert!(out.contains("alice"));
+
+        let out: serde_json::Value = serde_json::from_str(&write(Format::Json)).unwrap();
+        assert_eq!(out[0]["previous"], serde_json::Value::Null);
+        assert_eq!(out[0]["quantity"], 8);
+        assert_eq!(out[0]["store"], "VENEZA_IT");
+
+        assert_eq!(
+            write(Format::Csv),
+            "time,store,name,brand,category,previous,quantity,actor,reason\n\
+             2020-09-13T12:26:40.123Z,VENEZA_IT,Laptop air,AbCorp,computers/laptops,,8,alice,rental\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_column() {
+        assert_eq!("quantity".parse(), Ok(Column::Quantity));
+        assert_eq!("version".parse(), Ok(Column::Version));
+        assert!("price".parse::<Column>().is_err());
+    }
+
+    #[test]
+    fn test_write_items_columns() {
+        let columns = vec![Column::Quantity, Column::Name];
+        let out = print(Printer {
+            columns: columns.clone(),
+            ..Printer::default()
+        });
+        let header = out.lines().nth(1).expect("Could not recover header");
+        assert!(header.starts_with("AVAILABLE"));
+        assert!(!header.contains("BRAND"));
+
+        let out = print(Printer {
+            format: Format::Csv,
+            columns: columns.clone(),
+            ..Printer::default()
+        });
+        assert_eq!(out, "quantity,name\n8,Laptop air\n");
+
+        let out = print(Printer {
+            format: Format::Json,
+            columns,
+            ..Printer::default()
+        });
+        assert_eq!(
+            out.split_whitespace().collect::<String>(),
+            "[{\"quantity\":8,\"name\":\"Laptopair\"}]"
+        );
+    }
+
+    #[test]
+    fn test_template() {
+        let template: Template = "{{name}}\\t{{ quantity }} left\\n".parse().unwrap();
+        let out = print(Printer {
+            template: Some(template),
+            ..Printer::default()
+        });
+        assert_eq!(out, "Laptop air\t8 left\n\n");
+
+        assert!("{{name".parse::<Template>().is_err());
+        assert!("{{price}}".parse::<Template>().is_err());
+        assert_eq!(
+            "C:\\\\{{name}}"
+                .parse::<Template>()
+                .unwrap()
+                .render(&ItemRecord::from(&items()[0])),
+            "C:\\Laptop air"
+        );
+    }
+}
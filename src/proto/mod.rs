@@ -1,26 +1,71 @@
 use std::{str, string};
 
+use protobuf::ProtobufEnum;
+
+pub mod health;
+pub mod health_grpc;
 pub mod stock;
 pub mod stock_grpc;
 
+pub use self::health::HealthCheckRequest;
+pub use self::health::HealthCheckResponse;
+pub use self::health::HealthCheckResponse_ServingStatus as ServingStatus;
+pub use self::health_grpc::create_health as create_health_service;
+pub use self::health_grpc::Health as HealthService;
+pub use self::health_grpc::HealthClient;
+
 pub use self::stock::AvailabilityDescription;
+pub use self::stock::Batch;
+pub use self::stock::BatchResult;
+pub use self::stock::BulkUpsertSummary;
+pub use self::stock::HistoryEntry;
+pub use self::stock::HistoryRequest;
+pub use self::stock::HistoryResponse;
 pub use self::stock::Item;
+pub use self::stock::Operation;
+pub use self::stock::OperationKind;
+pub use self::stock::OperationResult;
+pub use self::stock::ReceiveTransferRequest;
+pub use self::stock::SetItemRequest;
+pub use self::stock::SetItemResponse;
 pub use self::stock::StockRequest;
 pub use self::stock::StockResponse;
 pub use self::stock::Store;
+pub use self::stock::StoresRequest;
+pub use self::stock::StoresResponse;
+pub use self::stock::TransferRequest;
+pub use self::stock::TransferResponse;
+pub use self::stock::UpsertFailure;
+pub use self::stock::UpsertItem;
 pub use self::stock_grpc::create_stock as create_stock_service;
 pub use self::stock_grpc::Stock as StockService;
 pub use self::stock_grpc::StockClient;
 
+/// Returns the names of every store known by the protocol, such as
+/// `BERLIN_DE`.
+pub fn store_names() -> Vec<String> {
+    Store::values()
+        .iter()
+        .map(|store| format!("{:?}", store))
+        .collect()
+}
+
 impl str::FromStr for Store {
     type Err = String;
 
+    /// Parses a store name, ignoring case.
     fn from_str(s: &str) -> Result<Store, Self::Err> {
-        match s {
-            "BERLIN_DE" => Ok(Store::BERLIN_DE),
-            "VENEZA_IT" => Ok(Store::VENEZA_IT),
-            _ => Err(String::from("Invalid store")),
-        }
+        Store::values()
+            .iter()
+            .find(|store| format!("{:?}", store).eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "Invalid store {:?}, expected one of {}",
+                    s,
+                    store_names().join(", ")
+                )
+            })
     }
 }
 
@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::import::Row;
+use super::proto;
+
+/// `Change` is a difference between the desired inventory of a store and
+/// the one on the server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// A product the server doesn't hold yet.
+    Add(Row),
+    /// A product whose quantity differs, along with the server quantity.
+    Update {
+        /// Desired product and quantity.
+        row: Row,
+        /// Quantity on the server.
+        from: u32,
+    },
+    /// A product on the server missing from the desired inventory.
+    Remove(Row),
+}
+
+impl Change {
+    /// Returns the product and quantity the change is about.
+    pub fn row(&self) -> &Row {
+        match self {
+            Change::Add(row) | Change::Update { row, .. } | Change::Remove(row) => row,
+        }
+    }
+
+    /// Returns whether applying the change loses data. Only removals do,
+    /// quantities can always be set back.
+    pub fn is_destructive(&self) -> bool {
+        matches!(self, Change::Remove(_))
+    }
+
+    /// Returns the `BulkUpsert` item applying the change on `store`.
+    pub fn upsert(&self, store: proto::Store) -> proto::UpsertItem {
+        let mut item = self.row().upsert(store);
+        item.set_remove(self.is_destructive());
+        item
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let row = self.row();
+        let product = format!("{} ({}, {})", row.name, row.brand, row.category);
+        match self {
+            Change::Add(_) => write!(f, "+ {}: {}", product, row.quantity),
+            Change::Update { from, .. } => write!(
+                f,
+                "~ {}: {} -> {} ({:+})",
+                product,
+                from,
+                row.quantity,
+                i64::from(row.quantity) - i64::from(*from)
+            ),
+            Change::Remove(_) => write!(f, "- {}: {}", product, row.quantity),
+        }
+    }
+}
+
+type Key = (String, String, String);
+
+fn key(row: &Row) -> Key {
+    (row.name.clone(), row.brand.clone(), row.category.clone())
+}
+
+/// Returns the changes turning the `current` items of a store into the
+/// `desired` rows, ordered by product. Products are identified by name,
+/// brand and category; if a product is listed more than once, its last
+/// row wins.
+pub fn diff(current: &[proto::Item], desired: &[Row]) -> Vec<Change> {
+    let current: BTreeMap<Key, Row> = current
+        .iter()
+        .map(Row::from)
+        .map(|row| (key(&row), row))
+        .collect();
+    let desired: BTreeMap<Key, &Row> = desired.iter().map(|row| (key(row), row)).collect();
+
+    let mut changes: Vec<Change> = desired
+        .iter()
+        .filter_map(|(key, row)| match current.get(key) {
+            None => Some(Change::Add((*row).clone())),
+            Some(existing) if existing.quantity != row.quantity => Some(Change::Update {
+                row: (*row).clone(),
+                from: existing.quantity,
+            }),
+            Some(_) => None,
+        })
+        .chain(
+            current
+                .iter()
+                .filter(|(key, _)| !desired.contains_key(*key))
+                .map(|(_, row)| Change::Remove(row.clone())),
+        )
+        .collect();
+    changes.sort_by_key(|change| key(change.row()));
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(name: &str, quantity: u32) -> Row {
+        Row {
+            name: String::from(name),
+            brand: String::from("AbCorp"),
+            category: String::from("computers/laptops"),
+            quantity,
+        }
+    }
+
+    fn item(name: &str, quantity: i32) -> proto::Item {
+        let mut item = proto::Item::new();
+        item.set_name(String::from(name));
+        item.set_brand(String::from("AbCorp"));
+        item.set_category(String::from("computers/laptops"));
+        item.set_quantity(quantity);
+        item
+    }
+
+    #[test]
+    fn test_diff() {
+        let current = vec![
+            item("Laptop air", 8),
+            item("Laptop pro", 2),
+            item("Laptop old", 0),
+        ];
+        let desired = vec![
+            row("Laptop pro", 2),
+            row("Laptop air", 3),
+            row("Laptop air", 5),
+            row("Laptop new", 1),
+        ];
+        assert_eq!(
+            diff(&current, &desired),
+            vec![
+                Change::Update {
+                    row: row("Laptop air", 5),
+                    from: 8
+                },
+                Change::Add(row("Laptop new", 1)),
+                Change::Remove(row("Laptop old", 0)),
+            ]
+        );
+        assert!(diff(&current[..1], &[row("Laptop air", 8)]).is_empty());
+    }
+
+    #[test]
+    fn test_display_change() {
+        assert_eq!(
+            Change::Add(row("Laptop air", 8)).to_string(),
+            "+ Laptop air (AbCorp, computers/laptops): 8"
+        );
+        assert_eq!(
+            Change::Update {
+                row: row("Laptop air", 5),
+                from: 8
+            }
+            .to_string(),
+            "~ Laptop air (AbCorp, computers/laptops): 8 -> 5 (-3)"
+        );
+        assert_eq!(
+            Change::Remove(row("Laptop air", 0)).to_string(),
+            "- Laptop air (AbCorp, computers/laptops): 0"
+        );
+    }
+
+    #[test]
+    fn test_change_upsert() {
+        let item = Change::Remove(row("Laptop air", 2)).upsert(proto::Store::VENEZA_IT);
+        assert!(item.get_remove());
+        assert_eq!(item.get_store(), proto::Store::VENEZA_IT);
+        assert!(!Change::Add(row("Laptop air", 2))
+            .upsert(proto::Store::VENEZA_IT)
+            .get_remove());
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::{path, str, time};
+
+use linefeed::{Completion, Prompter, Terminal};
+
+use super::client_config::ClientConfig;
+use super::grpc::client;
+use super::proto;
+
+/// Shell command names, in the order `help` lists them.
+pub const COMMANDS: [&str; 7] = ["list", "use", "show", "category", "watch", "help", "exit"];
+
+/// Help text of the shell.
+pub const HELP: &str = "\
+list [STORE] [--show-unavailable]  lists the products available in the store
+use STORE                          makes STORE the default store of the session
+show NAME                          shows the product called NAME
+category CATEGORY                  lists the products of CATEGORY and its subcategories
+watch [SECONDS]                    shows the store inventory full screen, refreshing it
+help                               prints this help
+exit                               leaves the shell";
+
+/// `Command` is a line typed in the client shell.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Lists the products of the given store, or the session one.
+    List {
+        /// Store to list, the session store if unset.
+        store: Option<proto::Store>,
+        /// Whether to list unavailable products too.
+        show_unavailable: bool,
+    },
+    /// Switches the session store.
+    Use(proto::Store),
+    /// Shows a product by name.
+    Show(String),
+    /// Lists the products of a category.
+    Category(String),
+    /// Shows the store inventory full screen, refreshing it on the given
+    /// interval.
+    Watch(Option<time::Duration>),
+    /// Prints the help.
+    Help,
+    /// Leaves the shell.
+    Exit,
+}
+
+impl Command {
+    /// Returns whether `item` is part of the command result. Only `show`
+    /// and `category` filter the store items.
+    pub fn matches(&self, item: &proto::Item) -> bool {
+        match self {
+            Command::Show(name) => item.name.eq_ignore_ascii_case(name),
+            Command::Category(category) => {
+                item.category == *category || item.category.starts_with(&format!("{}/", category))
+            }
+            _ => true,
+        }
+    }
+}
+
+impl str::FromStr for Command {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Command, Self::Err> {
+        let line = line.trim();
+        let (name, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        let args: Vec<&str> = rest.split_whitespace().collect();
+        match (name, args.as_slice()) {
+            ("list", args) => {
+                let mut store = None;
+                let mut show_unavailable = false;
+                for arg in args {
+                    match *arg {
+                        "--show-unavailable" => show_unavailable = true,
+                        arg if store.is_none() => store = Some(arg.parse()?),
+                        arg => return Err(format!("Unexpected argument {:?}", arg)),
+                    }
+                }
+                Ok(Command::List {
+                    store,
+                    show_unavailable,
+                })
+            }
+            ("use", [store]) => Ok(Command::Use(store.parse()?)),
+            ("use", _) => Err(String::from("Usage: use STORE")),
+            ("show", []) => Err(String::from("Usage: show NAME")),
+            ("show", _) => Ok(Command::Show(String::from(rest))),
+            ("category", [category]) => Ok(Command::Category(String::from(*category))),
+            ("category", _) => Err(String::from("Usage: category CATEGORY")),
+            ("watch", []) => Ok(Command::Watch(None)),
+            ("watch", [seconds]) => Ok(Command::Watch(Some(client::parse_seconds(seconds)?))),
+            ("watch", _) => Err(String::from("Usage: watch [SECONDS]")),
+            ("help", _) => Ok(Command::Help),
+            ("exit", _) | ("quit", _) => Ok(Command::Exit),
+            (name, _) => Err(format!(
+                "Unknown command {:?}, type help for the available ones",
+                name
+            )),
+        }
+    }
+}
+
+/// `Catalog` holds the product names and categories fetched from the
+/// server, used to complete the shell commands.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Catalog {
+    names: BTreeSet<String>,
+    categories: BTreeSet<String>,
+}
+
+impl Catalog {
+    /// Replaces the catalog with the names and categories of `items`.
+    /// Categories are completed at every level, so `computers/laptops`
+    /// also adds `computers`.
+    pub fn update(&mut self, items: &[proto::Item]) {
+        self.names = items.iter().map(|item| item.name.clone()).collect();
+        self.categories.clear();
+        for item in items {
+            for (i, _) in item.category.match_indices('/') {
+                self.categories.insert(String::from(&item.category[..i]));
+            }
+            self.categories.insert(item.category.clone());
+        }
+    }
+}
+
+/// Returns where the word completed at `end` of `line` starts. Product
+/// names may have spaces, so the argument of `show` is a single word.
+pub fn word_start(line: &str, end: usize) -> usize {
+    let prefix = &line[..end];
+    let command_start = prefix.len() - prefix.trim_start().len();
+    let command_end = prefix[command_start..]
+        .find(char::is_whitespace)
+        .map(|i| command_start + i);
+    match command_end {
+        None => command_start,
+        Some(i) if &prefix[command_start..i] == "show" => {
+            prefix.len() - prefix[i..].trim_start().len()
+        }
+        Some(_) => prefix
+            .rfind(char::is_whitespace)
+            .map(|i| i + 1)
+            .unwrap_or(0),
+    }
+}
+
+/// Returns the completions of the word between `start` and `end` of
+/// `line`: command names first, then stores, product names or categories
+/// depending on the command.
+pub fn complete(catalog: &Catalog, line: &str, start: usize, end: usize) -> Vec<String> {
+    let word = &line[start..end];
+    let before = line[..start].trim();
+    let candidates: Vec<String> = if before.is_empty() {
+        COMMANDS.iter().map(|c| String::from(*c)).collect()
+    } else {
+        match before.split_whitespace().next() {
+            Some("list") => proto::store_names()
+                .into_iter()
+                .chain(Some(String::from("--show-unavailable")))
+                .collect(),
+            Some("use") => proto::store_names(),
+            Some("show") => catalog.names.iter().cloned().collect(),
+            Some("category") => catalog.categories.iter().cloned().collect(),
+            _ => vec![],
+        }
+    };
+    let lowercase = word.to_lowercase();
+    candidates
+        .into_iter()
+        .filter(|c| c.to_lowercase().starts_with(&lowercase))
+        .collect()
+}
+
+/// Returns the shell history file, next to the client configuration file.
+pub fn history_path() -> Option<path::PathBuf> {
+    ClientConfig::default_path().and_then(|path| path.parent().map(|dir| dir.join("history")))
+}
+
+/// `Completer` completes the shell commands with the shared
+/// [`Catalog`](struct.Catalog.html), which the shell refreshes as it
+/// fetches items.
+pub struct Completer {
+    catalog: Arc<Mutex<Catalog>>,
+}
+
+impl Completer {
+    /// Returns a `Completer` reading `catalog`.
+    pub fn new(catalog: Arc<Mutex<Catalog>>) -> Self {
+        Self { catalog }
+    }
+}
+
+impl<T: Terminal> linefeed::Completer<T> for Completer {
+    fn complete(
+        &self,
+        _word: &str,
+        prompter: &Prompter<T>,
+        start: usize,
+        end: usize,
+    ) -> Option<Vec<Completion>> {
+        let catalog = self.catalog.lock().ok()?;
+        Some(
+            complete(&catalog, prompter.buffer(), start, end)
+                .into_iter()
+                .map(Completion::simple)
+                .collect(),
+        )
+    }
+
+    fn word_start(&self, line: &str, end: usize, _prompter: &Prompter<T>) -> usize {
+        word_start(line, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(name: &str, category: &str) -> proto::Item {
+        let mut item = proto::Item::new();
+        item.set_name(String::from(name));
+        item.set_category(String::from(category));
+        item
+    }
+
+    fn catalog() -> Catalog {
+        let mut catalog = Catalog::default();
+        catalog.update(&[
+            item("Laptop air", "computers/laptops"),
+            item("Laptop UltraPower", "computers/desktop-computers"),
+        ]);
+        catalog
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            "list VENEZA_IT --show-unavailable".parse(),
+            Ok(Command::List {
+                store: Some(proto::Store::VENEZA_IT),
+                show_unavailable: true
+            })
+        );
+        assert_eq!(
+            "  use BERLIN_DE ".parse(),
+            Ok(Command::Use(proto::Store::BERLIN_DE))
+        );
+        assert_eq!(
+            "show Laptop  air".parse(),
+            Ok(Command::Show(String::from("Laptop  air")))
+        );
+        assert_eq!(
+            "watch 5".parse(),
+            Ok(Command::Watch(Some(time::Duration::from_secs(5))))
+        );
+        assert_eq!("quit".parse(), Ok(Command::Exit));
+
+        for line in &["list PARIS_FR", "use", "show", "watch soon", "buy"] {
+            assert!(line.parse::<Command>().is_err(), "{:?} was accepted", line);
+        }
+    }
+
+    #[test]
+    fn test_command_matches() {
+        let laptop = item("Laptop air", "computers/laptops");
+        assert!(Command::Show(String::from("laptop AIR")).matches(&laptop));
+        assert!(!Command::Show(String::from("Laptop")).matches(&laptop));
+        assert!(Command::Category(String::from("computers")).matches(&laptop));
+        assert!(!Command::Category(String::from("computer")).matches(&laptop));
+        assert!(Command::Help.matches(&laptop));
+    }
+
+    #[test]
+    fn test_word_start() {
+        assert_eq!(word_start("  li", 4), 2);
+        assert_eq!(word_start("list VEN", 8), 5);
+        assert_eq!(word_start("show Laptop a", 13), 5);
+        assert_eq!(word_start("category comp", 13), 9);
+    }
+
+    #[test]
+    fn test_complete() {
+        let catalog = catalog();
+        let complete =
+            |line: &str| complete(&catalog, line, word_start(line, line.len()), line.len());
+
+        assert_eq!(complete("ca"), vec!["category"]);
+        assert_eq!(complete("use v"), vec!["VENEZA_IT"]);
+        assert_eq!(complete("list --"), vec!["--show-unavailable"]);
+        assert_eq!(complete("show laptop a"), vec!["Laptop air"]);
+        assert_eq!(
+            complete("category computers"),
+            vec![
+                "computers",
+                "computers/desktop-computers",
+                "computers/laptops"
+            ]
+        );
+        assert!(complete("help me").is_empty());
+    }
+}
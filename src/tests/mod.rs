@@ -2,6 +2,10 @@ use super::*;
 
 pub mod log;
 
+fn ctx() -> Context {
+    Context::new("test", trace::Tracer::disabled().start("test", None))
+}
+
 #[test]
 fn stock_register_product_on_store() {
     let mut stock = StockImpl::new(log::new());
@@ -12,7 +16,8 @@ fn stock_register_product_on_store() {
         8,
     );
 
-    let store = stock
+    let inventory = stock.read();
+    let store = inventory
         .stores
         .get(&proto::Store::BERLIN_DE)
         .expect("Could not recover store");
@@ -47,7 +52,8 @@ fn stock_register_more_than_one_product_on_store() {
         4,
     );
 
-    let store = stock
+    let inventory = stock.read();
+    let store = inventory
         .stores
         .get(&proto::Store::BERLIN_DE)
         .expect("Could not recover store");
@@ -93,8 +99,8 @@ fn stock_return_product_by_store() {
         3,
     );
 
-    assert_eq!(stock.get(proto::Store::BERLIN_DE, false).len(), 2);
-    let berlin = stock.get(proto::Store::VENEZA_IT, false);
+    assert_eq!(stock.get(&ctx(), proto::Store::BERLIN_DE, false).len(), 2);
+    let berlin = stock.get(&ctx(), proto::Store::VENEZA_IT, false);
     assert_eq!(berlin.len(), 1);
 
     for item in berlin {
@@ -146,8 +152,8 @@ fn stock_return_product_by_store_not_show_unavailable() {
         0,
     );
 
-    assert_eq!(stock.get(proto::Store::BERLIN_DE, false).len(), 2);
-    let berlin = stock.get(proto::Store::VENEZA_IT, false);
+    assert_eq!(stock.get(&ctx(), proto::Store::BERLIN_DE, false).len(), 2);
+    let berlin = stock.get(&ctx(), proto::Store::VENEZA_IT, false);
     assert_eq!(berlin.len(), 1);
 
     for item in berlin {
@@ -190,8 +196,8 @@ fn stock_return_product_by_store_show_unavailable() {
         0,
     );
 
-    assert_eq!(stock.get(proto::Store::BERLIN_DE, true).len(), 2);
-    let berlin = stock.get(proto::Store::VENEZA_IT, true);
+    assert_eq!(stock.get(&ctx(), proto::Store::BERLIN_DE, true).len(), 2);
+    let berlin = stock.get(&ctx(), proto::Store::VENEZA_IT, true);
     assert_eq!(berlin.len(), 1);
 
     for item in berlin {
@@ -225,8 +231,8 @@ fn stock_does_not_return_unavailable_items() {
         0,
     );
 
-    assert_eq!(stock.get(proto::Store::BERLIN_DE, false).len(), 0);
-    assert_eq!(stock.get(proto::Store::VENEZA_IT, false).len(), 0);
+    assert_eq!(stock.get(&ctx(), proto::Store::BERLIN_DE, false).len(), 0);
+    assert_eq!(stock.get(&ctx(), proto::Store::VENEZA_IT, false).len(), 0);
 }
 
 #[test]
@@ -248,8 +254,8 @@ fn stock_return_empty_store() {
         4,
     );
 
-    assert_eq!(stock.get(proto::Store::BERLIN_DE, true).len(), 2);
-    assert_eq!(stock.get(proto::Store::VENEZA_IT, true).len(), 0);
+    assert_eq!(stock.get(&ctx(), proto::Store::BERLIN_DE, true).len(), 2);
+    assert_eq!(stock.get(&ctx(), proto::Store::VENEZA_IT, true).len(), 0);
 }
 
 #[test]
@@ -276,6 +282,553 @@ fn stock_return_empty_store_with_unavailable() {
         0,
     );
 
-    assert_eq!(stock.get(proto::Store::BERLIN_DE, false).len(), 2);
-    assert_eq!(stock.get(proto::Store::VENEZA_IT, false).len(), 0);
+    assert_eq!(stock.get(&ctx(), proto::Store::BERLIN_DE, false).len(), 2);
+    assert_eq!(stock.get(&ctx(), proto::Store::VENEZA_IT, false).len(), 0);
+}
+
+#[test]
+fn stock_version_changes_on_register() {
+    let mut stock = StockImpl::new(log::new());
+    let berlin = stock.version(proto::Store::BERLIN_DE);
+    assert!(berlin.is_some());
+    assert_eq!(stock.version(proto::Store::BERLIN_DE), berlin);
+
+    stock.register(
+        Product::new("Laptop air", "AbCorp", "computers/laptops"),
+        proto::Store::BERLIN_DE,
+        8,
+    );
+    assert_ne!(stock.version(proto::Store::BERLIN_DE), berlin);
+    assert_ne!(
+        stock.version(proto::Store::BERLIN_DE),
+        stock.version(proto::Store::VENEZA_IT)
+    );
+
+    let other = StockImpl::new(log::new());
+    assert_ne!(
+        other.version(proto::Store::VENEZA_IT),
+        stock.version(proto::Store::VENEZA_IT)
+    );
+}
+
+#[test]
+fn stock_set_product_on_store() {
+    let stock = StockImpl::new(log::new());
+    let laptop = Product::new("Laptop air", "AbCorp", "computers/laptops");
+    let version = stock.version(proto::Store::BERLIN_DE);
+
+    assert_eq!(
+        stock.set(&ctx(), proto::Store::BERLIN_DE, laptop.clone(), 8),
+        None
+    );
+    let added = stock.version(proto::Store::BERLIN_DE);
+    assert_ne!(added, version);
+
+    assert_eq!(
+        stock.set(&ctx(), proto::Store::BERLIN_DE, laptop.clone(), 8),
+        Some(8)
+    );
+    assert_eq!(stock.version(proto::Store::BERLIN_DE), added);
+
+    assert_eq!(
+        stock.set(&ctx(), proto::Store::BERLIN_DE, laptop, 0),
+        Some(8)
+    );
+    assert_ne!(stock.version(proto::Store::BERLIN_DE), added);
+
+    let items = stock.get(&ctx(), proto::Store::BERLIN_DE, true);
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].quantity, 0);
+    assert!(stock.get(&ctx(), proto::Store::BERLIN_DE, false).is_empty());
+}
+
+#[test]
+fn stock_set_many_products() {
+    let stock = StockImpl::new(log::new());
+    let laptop = Product::new("Laptop air", "AbCorp", "computers/laptops");
+    let model = Product::new("Model 3", "Tesla", "cars");
+
+    let previous = stock.set_many(
+        &ctx(),
+        vec![
+            (proto::Store::BERLIN_DE, laptop.clone(), Some(8)),
+            (proto::Store::VENEZA_IT, model.clone(), Some(0)),
+            (proto::Store::BERLIN_DE, laptop, Some(4)),
+        ],
+    );
+    assert_eq!(previous, vec![None, None, Some(8)]);
+    assert_eq!(
+        stock.get(&ctx(), proto::Store::BERLIN_DE, false)[0].quantity,
+        4
+    );
+    assert_eq!(stock.get(&ctx(), proto::Store::VENEZA_IT, true).len(), 1);
+
+    let version = stock.version(proto::Store::VENEZA_IT);
+    let previous = stock.set_many(
+        &ctx(),
+        vec![
+            (proto::Store::VENEZA_IT, model.clone(), None),
+            (proto::Store::VENEZA_IT, model, None),
+        ],
+    );
+    assert_eq!(previous, vec![Some(0), None]);
+    assert!(stock.get(&ctx(), proto::Store::VENEZA_IT, true).is_empty());
+    assert_ne!(stock.version(proto::Store::VENEZA_IT), version);
+}
+
+#[test]
+fn stock_update_many_with_versions() {
+    let mut stock = StockImpl::new(log::new());
+    let laptop = Product::new("Laptop air", "AbCorp", "computers/laptops");
+    let model = Product::new("Model 3", "Tesla", "cars");
+    stock.register(laptop.clone(), proto::Store::BERLIN_DE, 8);
+    assert_eq!(
+        stock.get(&ctx(), proto::Store::BERLIN_DE, true)[0].version,
+        1
+    );
+
+    let update = |product: &Product, quantity, expected_version| Update {
+        store: proto::Store::BERLIN_DE,
+        product: product.clone(),
+        quantity,
+        expected_version,
+    };
+    assert_eq!(
+        stock.update_many(
+            &ctx(),
+            vec![
+                update(&laptop, Some(6), Some(1)),
+                update(&model, Some(2), None),
+            ]
+        ),
+        Ok(vec![(Some(8), 2), (None, 1)])
+    );
+
+    // Versions are checked before any update is applied
+    let version = stock.version(proto::Store::BERLIN_DE);
+    let conflicts = stock
+        .update_many(
+            &ctx(),
+            vec![
+                update(&model, None, Some(1)),
+                update(&laptop, Some(5), Some(1)),
+                update(&Product::new("Laptop pro", "", ""), Some(1), Some(1)),
+            ],
+        )
+        .unwrap_err();
+    let mut current = laptop.item(6);
+    current.version = 2;
+    assert_eq!(
+        conflicts,
+        vec![
+            (1, VersionError::Mismatch(current)),
+            (2, VersionError::Missing)
+        ]
+    );
+    assert_eq!(stock.version(proto::Store::BERLIN_DE), version);
+    assert_eq!(stock.get(&ctx(), proto::Store::BERLIN_DE, true).len(), 2);
+
+    // Removed products don't reuse their versions
+    assert_eq!(
+        stock.update_many(&ctx(), vec![update(&model, None, Some(1))]),
+        Ok(vec![(Some(2), 2)])
+    );
+    assert_eq!(
+        stock.update_many(&ctx(), vec![update(&model, Some(1), None)]),
+        Ok(vec![(None, 3)])
+    );
+
+    // Setting the same quantity changes nothing
+    assert_eq!(
+        stock.update_many(&ctx(), vec![update(&laptop, Some(6), Some(2))]),
+        Ok(vec![(Some(6), 2)])
+    );
+}
+
+#[test]
+fn stock_commit_changes() {
+    let mut stock = StockImpl::new(log::new());
+    let laptop = Product::new("Laptop air", "AbCorp", "computers/laptops");
+    let damaged = Product::new("Laptop air (damaged)", "AbCorp", "computers/laptops");
+    let model = Product::new("Model 3", "Tesla", "cars");
+    stock.register(laptop.clone(), proto::Store::BERLIN_DE, 8);
+    stock.register(damaged.clone(), proto::Store::BERLIN_DE, 2);
+
+    let change = |product: &Product, operation, expected_version| Change {
+        store: proto::Store::BERLIN_DE,
+        product: product.clone(),
+        operation,
+        expected_version,
+    };
+    let committed = |previous, quantity, version| Committed {
+        previous,
+        quantity,
+        version,
+    };
+    assert_eq!(
+        stock.commit(
+            &ctx(),
+            vec![
+                change(&laptop, Operation::Adjust(-1), Some(1)),
+                change(&damaged, Operation::Adjust(1), None),
+            ]
+        ),
+        Ok(vec![
+            committed(Some(8), Some(7), 2),
+            committed(Some(2), Some(3), 2)
+        ])
+    );
+
+    // Nothing is applied if any change can't be
+    let version = stock.version(proto::Store::BERLIN_DE);
+    let errors = stock
+        .commit(
+            &ctx(),
+            vec![
+                change(&model, Operation::Set(1), None),
+                change(&laptop, Operation::Adjust(-10), None),
+                change(&damaged, Operation::Create(1), None),
+                change(&Product::new("Laptop pro", "", ""), Operation::Delete, None),
+                change(&laptop, Operation::Set(1), Some(1)),
+            ],
+        )
+        .unwrap_err();
+    let item = |product: &Product, quantity, version| {
+        let mut item = product.item(quantity);
+        item.version = version;
+        item
+    };
+    assert_eq!(
+        errors,
+        vec![
+            (1, CommitError::OutOfRange(item(&laptop, 7, 2))),
+            (2, CommitError::Exists(item(&damaged, 3, 2))),
+            (3, CommitError::Missing),
+            (4, CommitError::Mismatch(item(&laptop, 7, 2))),
+        ]
+    );
+    assert_eq!(stock.version(proto::Store::BERLIN_DE), version);
+    assert_eq!(stock.get(&ctx(), proto::Store::BERLIN_DE, true).len(), 2);
+
+    // Changes see the outcome of the previous ones
+    let start = stock.history(&HistoryFilter::default()).len();
+    assert_eq!(
+        stock.commit(
+            &ctx(),
+            vec![
+                change(&model, Operation::Create(1), None),
+                change(&model, Operation::Adjust(2), None),
+                change(&model, Operation::Set(3), Some(2)),
+                change(&model, Operation::Delete, None),
+            ]
+        ),
+        Ok(vec![
+            committed(None, Some(1), 1),
+            committed(Some(1), Some(3), 2),
+            committed(Some(3), Some(3), 2),
+            committed(Some(3), None, 3),
+        ])
+    );
+    assert_eq!(stock.get(&ctx(), proto::Store::BERLIN_DE, true).len(), 2);
+    assert_eq!(stock.history(&HistoryFilter::default()).len(), start + 3);
+}
+
+#[test]
+fn stock_transfer_between_stores() {
+    let mut stock = StockImpl::new(log::new());
+    let laptop = Product::new("Laptop air", "AbCorp", "computers/laptops");
+    stock.register(laptop.clone(), proto::Store::BERLIN_DE, 8);
+
+    let transfer = |quantity| Transfer {
+        from: proto::Store::BERLIN_DE,
+        to: proto::Store::VENEZA_IT,
+        product: laptop.clone(),
+        quantity,
+    };
+    let transferred = stock.transfer(&ctx(), transfer(3), false).unwrap();
+    assert_eq!(transferred.id, None);
+    assert_eq!(transferred.source.quantity, 5);
+    assert_eq!(transferred.source.version, 2);
+    assert_eq!(transferred.destination.map(|item| item.quantity), Some(3));
+
+    // Nothing changes if the source doesn't hold enough units
+    assert_eq!(
+        stock.transfer(&ctx(), transfer(6), false),
+        Err(TransferError::Insufficient(5))
+    );
+    let mut missing = transfer(1);
+    missing.product = Product::new("Model 3", "Tesla", "cars");
+    assert_eq!(
+        stock.transfer(&ctx(), missing, false),
+        Err(TransferError::Missing)
+    );
+    stock.set(
+        &ctx(),
+        proto::Store::VENEZA_IT,
+        laptop.clone(),
+        i32::MAX as u32,
+    );
+    assert_eq!(
+        stock.transfer(&ctx(), transfer(1), false),
+        Err(TransferError::OutOfRange)
+    );
+    assert_eq!(
+        stock.get(&ctx(), proto::Store::BERLIN_DE, true)[0].quantity,
+        5
+    );
+    stock.set(&ctx(), proto::Store::VENEZA_IT, laptop.clone(), 3);
+
+    // Transfers of no units or within a store are rejected
+    let history = stock.history(&HistoryFilter::default()).len();
+    assert_eq!(
+        stock.transfer(&ctx(), transfer(0), false),
+        Err(TransferError::Empty)
+    );
+    assert_eq!(
+        stock.transfer(&ctx(), transfer(0), true),
+        Err(TransferError::Empty)
+    );
+    let mut same_store = transfer(1);
+    same_store.to = proto::Store::BERLIN_DE;
+    assert_eq!(
+        stock.transfer(&ctx(), same_store.clone(), false),
+        Err(TransferError::SameStore)
+    );
+    assert_eq!(
+        stock.transfer(&ctx(), same_store, true),
+        Err(TransferError::SameStore)
+    );
+    assert_eq!(stock.history(&HistoryFilter::default()).len(), history);
+
+    // Units in transit only leave the source until received
+    let transferred = stock.transfer(&ctx(), transfer(2), true).unwrap();
+    assert_eq!(transferred.source.quantity, 3);
+    assert_eq!(transferred.destination, None);
+    let id = transferred.id.unwrap();
+    assert_eq!(
+        stock.get(&ctx(), proto::Store::VENEZA_IT, true)[0].quantity,
+        3
+    );
+    let (received, destination) = stock.receive_transfer(&ctx(), &id).unwrap();
+    assert_eq!(received, transfer(2));
+    assert_eq!(destination.quantity, 5);
+    assert_eq!(
+        stock.receive_transfer(&ctx(), &id),
+        Err(TransferError::Unknown(id))
+    );
+}
+
+#[test]
+fn stock_history_of_changes() {
+    let mut stock = StockImpl::new(log::new());
+    let laptop = Product::new("Laptop air", "AbCorp", "computers/laptops");
+    let model = Product::new("Model 3", "Tesla", "cars");
+    stock.register(laptop.clone(), proto::Store::BERLIN_DE, 8);
+    assert!(stock.history(&HistoryFilter::default()).is_empty());
+
+    let start = time::SystemTime::now();
+    let rental = ctx().with_actor("alice").with_reason("rental");
+    stock.set(&rental, proto::Store::BERLIN_DE, laptop.clone(), 8);
+    stock.set(&rental, proto::Store::BERLIN_DE, laptop.clone(), 7);
+    stock.set(&ctx(), proto::Store::VENEZA_IT, model.clone(), 2);
+    stock.remove(&ctx(), proto::Store::BERLIN_DE, &laptop);
+    stock.remove(&ctx(), proto::Store::BERLIN_DE, &laptop);
+
+    let history = stock.history(&HistoryFilter::default());
+    assert_eq!(history.len(), 3);
+    assert_eq!(history[0].product, laptop);
+    assert_eq!(
+        (history[0].previous, history[0].quantity),
+        (Some(8), Some(7))
+    );
+    assert_eq!(history[0].actor, "alice");
+    assert_eq!(history[0].reason, "rental");
+    assert_eq!((history[1].previous, history[1].quantity), (None, Some(2)));
+    assert_eq!(history[1].actor, ANONYMOUS_ACTOR);
+    assert_eq!((history[2].previous, history[2].quantity), (Some(7), None));
+    assert!(history.iter().all(|entry| entry.time >= start));
+
+    let filter = HistoryFilter {
+        stores: vec![proto::Store::BERLIN_DE],
+        limit: Some(1),
+        ..HistoryFilter::default()
+    };
+    assert_eq!(stock.history(&filter), history[2..].to_vec());
+    let filter = HistoryFilter {
+        name: Some(String::from("Model 3")),
+        ..HistoryFilter::default()
+    };
+    assert_eq!(stock.history(&filter), history[1..2].to_vec());
+    let filter = HistoryFilter {
+        until: Some(start),
+        ..HistoryFilter::default()
+    };
+    assert!(stock.history(&filter).is_empty());
+}
+
+#[test]
+fn stock_get_as_of() {
+    let mut stock = StockImpl::new(log::new());
+    let laptop = Product::new("Laptop air", "AbCorp", "computers/laptops");
+    let model = Product::new("Model 3", "Tesla", "cars");
+    stock.register(laptop.clone(), proto::Store::BERLIN_DE, 8);
+
+    let start = time::SystemTime::now();
+    std::thread::sleep(time::Duration::from_millis(2));
+    stock.set(&ctx(), proto::Store::BERLIN_DE, model.clone(), 2);
+    stock.set(&ctx(), proto::Store::BERLIN_DE, laptop.clone(), 0);
+    std::thread::sleep(time::Duration::from_millis(2));
+    let middle = time::SystemTime::now();
+    std::thread::sleep(time::Duration::from_millis(2));
+    stock.remove(&ctx(), proto::Store::BERLIN_DE, &model);
+    stock.set(&ctx(), proto::Store::VENEZA_IT, laptop, 1);
+
+    let quantities = |as_of, show_unavailable| {
+        let mut items: Vec<(String, i32)> = stock
+            .get_as_of(&ctx(), proto::Store::BERLIN_DE, show_unavailable, as_of)
+            .unwrap()
+            .into_iter()
+            .map(|item| (item.name, item.quantity))
+            .collect();
+        items.sort();
+        items
+    };
+    let versions = |as_of| {
+        let mut items: Vec<(String, i64)> = stock
+            .get_as_of(&ctx(), proto::Store::BERLIN_DE, true, as_of)
+            .unwrap()
+            .into_iter()
+            .map(|item| (item.name, item.version))
+            .collect();
+        items.sort();
+        items
+    };
+    assert_eq!(
+        quantities(start, false),
+        vec![(String::from("Laptop air"), 8)]
+    );
+    assert_eq!(
+        quantities(middle, false),
+        vec![(String::from("Model 3"), 2)]
+    );
+    assert_eq!(
+        quantities(middle, true),
+        vec![
+            (String::from("Laptop air"), 0),
+            (String::from("Model 3"), 2)
+        ]
+    );
+    assert_eq!(
+        quantities(time::SystemTime::now(), true),
+        vec![(String::from("Laptop air"), 0)]
+    );
+    assert_eq!(versions(start), vec![(String::from("Laptop air"), 1)]);
+    assert_eq!(
+        versions(middle),
+        vec![
+            (String::from("Laptop air"), 2),
+            (String::from("Model 3"), 1)
+        ]
+    );
+
+    for quantity in 0..HISTORY_LIMIT as u32 {
+        stock.set(&ctx(), proto::Store::VENEZA_IT, model.clone(), quantity);
+    }
+    assert!(matches!(
+        stock.get_as_of(&ctx(), proto::Store::BERLIN_DE, true, middle),
+        Err(AsOfError::Forgotten(_))
+    ));
+}
+
+#[test]
+fn stock_thresholds() {
+    let mut stock = StockImpl::new(log::new());
+    let laptop = Product::new("Laptop air", "AbCorp", "computers/laptops");
+    let watch = Product::new(
+        "SantosDumond watch Active2",
+        "EvilCorp",
+        "wearables/smartwatches",
+    );
+    stock.register(laptop.clone(), proto::Store::BERLIN_DE, 8);
+    stock.register(watch.clone(), proto::Store::VENEZA_IT, 3);
+
+    assert_eq!(stock.thresholds(), vec![]);
+    let version = stock.version(proto::Store::BERLIN_DE);
+    assert_eq!(
+        stock.set_threshold(proto::Store::VENEZA_IT, watch.clone(), Some(2)),
+        None
+    );
+    assert_eq!(
+        stock.set_threshold(proto::Store::BERLIN_DE, laptop.clone(), Some(10)),
+        None
+    );
+    assert_eq!(
+        stock.set_threshold(proto::Store::BERLIN_DE, laptop.clone(), Some(5)),
+        Some(10)
+    );
+    // Setting a threshold is not a change of the stock
+    assert_eq!(stock.version(proto::Store::BERLIN_DE), version);
+    assert!(stock.history(&HistoryFilter::default()).is_empty());
+
+    stock.remove(&ctx(), proto::Store::BERLIN_DE, &laptop);
+    assert_eq!(
+        stock.thresholds(),
+        vec![
+            Threshold {
+                store: proto::Store::BERLIN_DE,
+                product: laptop.clone(),
+                threshold: 5,
+                quantity: 0,
+            },
+            Threshold {
+                store: proto::Store::VENEZA_IT,
+                product: watch.clone(),
+                threshold: 2,
+                quantity: 3,
+            },
+        ]
+    );
+
+    assert_eq!(
+        stock.set_threshold(proto::Store::VENEZA_IT, watch, None),
+        Some(2)
+    );
+    assert_eq!(stock.thresholds().len(), 1);
+}
+
+#[test]
+fn stock_stores_holding_products() {
+    let mut stock = StockImpl::new(log::new());
+    assert!(stock.stores().is_empty());
+
+    stock.register(
+        Product::new("Laptop air", "AbCorp", "computers/laptops"),
+        proto::Store::VENEZA_IT,
+        8,
+    );
+    stock.register(
+        Product::new(
+            "Laptop UltraPower",
+            "GNUFoundation",
+            "computers/desktop-computers",
+        ),
+        proto::Store::BERLIN_DE,
+        0,
+    );
+    assert_eq!(
+        stock.stores(),
+        vec![proto::Store::BERLIN_DE, proto::Store::VENEZA_IT]
+    );
+}
+
+#[test]
+fn store_from_str() {
+    assert_eq!("VENEZA_IT".parse(), Ok(proto::Store::VENEZA_IT));
+    assert_eq!("berlin_de".parse(), Ok(proto::Store::BERLIN_DE));
+    assert_eq!(
+        "PARIS_FR".parse::<proto::Store>(),
+        Err(String::from(
+            "Invalid store \"PARIS_FR\", expected one of BERLIN_DE, VENEZA_IT"
+        ))
+    );
 }
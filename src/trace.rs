@@ -0,0 +1,525 @@
+use std::io::Write;
+use std::{collections, fmt, fs, io, path, sync, thread, time};
+
+use rand::Rng;
+use serde::Serialize;
+use serde_json::json;
+
+/// Metadata key used by the [W3C Trace Context][0] specification to
+/// propagate the caller span.
+///
+/// [0]: https://www.w3.org/TR/trace-context/
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+const SAMPLED_FLAG: u8 = 0x01;
+
+/// `SpanContext` identifies a span inside a trace. It's the part of a
+/// span that crosses process boundaries through the `traceparent`
+/// metadata.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpanContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    flags: u8,
+}
+
+impl SpanContext {
+    /// Returns a sampled `SpanContext` for a new trace, with random trace
+    /// and span IDs.
+    pub fn generate() -> Self {
+        let mut rng = rand::thread_rng();
+        let mut trace_id = [0u8; 16];
+        while trace_id == [0u8; 16] {
+            rng.fill(&mut trace_id);
+        }
+        Self {
+            trace_id,
+            span_id: random_span_id(),
+            flags: SAMPLED_FLAG,
+        }
+    }
+
+    /// Parses a `traceparent` value such as
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    /// Returns `None` if the value does not follow the specification, in
+    /// which case callers are expected to start a new trace.
+    pub fn from_traceparent(value: &str) -> Option<Self> {
+        let parts: Vec<&str> = value.trim().split('-').collect();
+        if parts.len() < 4 {
+            return None;
+        }
+        let version = decode_hex(parts[0], 1)?[0];
+        // Version 0xff is forbidden and version 00 has exactly 4 fields.
+        if version == 0xff || (version == 0 && parts.len() != 4) {
+            return None;
+        }
+
+        let mut trace_id = [0u8; 16];
+        trace_id.copy_from_slice(&decode_hex(parts[1], 16)?);
+        let mut span_id = [0u8; 8];
+        span_id.copy_from_slice(&decode_hex(parts[2], 8)?);
+        let flags = decode_hex(parts[3], 1)?[0];
+
+        if trace_id == [0u8; 16] || span_id == [0u8; 8] {
+            return None;
+        }
+        Some(Self {
+            trace_id,
+            span_id,
+            flags,
+        })
+    }
+
+    /// Returns the `traceparent` representation of this context.
+    pub fn to_traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            self.trace_id(),
+            self.span_id(),
+            self.flags
+        )
+    }
+
+    /// Returns the lowercase hex encoded trace ID.
+    pub fn trace_id(&self) -> String {
+        encode_hex(&self.trace_id)
+    }
+
+    /// Returns the lowercase hex encoded span ID.
+    pub fn span_id(&self) -> String {
+        encode_hex(&self.span_id)
+    }
+
+    /// Returns whether the caller asked for this trace to be recorded.
+    pub fn is_sampled(&self) -> bool {
+        self.flags & SAMPLED_FLAG == SAMPLED_FLAG
+    }
+
+    fn child(&self) -> Self {
+        Self {
+            trace_id: self.trace_id,
+            span_id: random_span_id(),
+            flags: self.flags,
+        }
+    }
+}
+
+impl fmt::Display for SpanContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_traceparent())
+    }
+}
+
+/// `SpanKind` tells whether a span serves a call received by the process
+/// or an operation inside it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpanKind {
+    /// An operation inside the process, such as `StockImpl::get`.
+    Internal,
+    /// A call received by the process.
+    Server,
+}
+
+impl SpanKind {
+    // Values of the OTLP `SpanKind` enum.
+    fn otlp(self) -> u8 {
+        match self {
+            SpanKind::Internal => 1,
+            SpanKind::Server => 2,
+        }
+    }
+}
+
+/// `SpanData` is the finished representation of a
+/// [`Span`](struct.Span.html), handed to an
+/// [`Exporter`](trait.Exporter.html).
+#[derive(Clone, Debug, Serialize)]
+pub struct SpanData {
+    /// Operation name, such as `Handler::handle`.
+    pub name: String,
+    /// Whether the span serves a call or an operation inside it.
+    pub kind: SpanKind,
+    /// Hex encoded trace ID.
+    pub trace_id: String,
+    /// Hex encoded span ID.
+    pub span_id: String,
+    /// Hex encoded span ID of the parent, if any.
+    pub parent_span_id: Option<String>,
+    /// Start time in nanoseconds since the UNIX epoch.
+    pub start_time_unix_nano: u64,
+    /// End time in nanoseconds since the UNIX epoch.
+    pub end_time_unix_nano: u64,
+    /// Key/value pairs describing the operation.
+    pub attributes: collections::BTreeMap<String, String>,
+}
+
+/// `Exporter` sends finished spans somewhere they can be inspected.
+pub trait Exporter: Send + Sync {
+    /// Exports a finished span. Implementations must not block the caller
+    /// for long, as spans are finished while serving requests.
+    fn export(&self, span: SpanData);
+}
+
+/// `FileExporter` appends finished spans as JSON lines to a file. It's
+/// meant for tests and local debugging.
+pub struct FileExporter {
+    file: sync::Mutex<fs::File>,
+}
+
+impl FileExporter {
+    /// Returns a new `FileExporter` appending to the file on `path`. The
+    /// file is created if it does not exist.
+    pub fn new<P: AsRef<path::Path>>(path: P) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self {
+            file: sync::Mutex::new(file),
+        })
+    }
+}
+
+impl Exporter for FileExporter {
+    fn export(&self, span: SpanData) {
+        if let Ok(line) = serde_json::to_string(&span) {
+            if let Ok(mut file) = self.file.lock() {
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+    }
+}
+
+/// `OtlpExporter` sends spans to an [OpenTelemetry][0] collector using
+/// OTLP over HTTP with JSON encoding, e.g. to
+/// `http://127.0.0.1:4318/v1/traces`.
+///
+/// Spans are sent in batches by a background thread so requests are
+/// never blocked by the collector. Export failures are silently dropped,
+/// and so are spans finished while
+/// [`OTLP_QUEUE_SIZE`](constant.OTLP_QUEUE_SIZE.html) of them are waiting
+/// to be sent.
+///
+/// [0]: https://opentelemetry.io/docs/collector/
+pub struct OtlpExporter {
+    sender: sync::Mutex<sync::mpsc::SyncSender<SpanData>>,
+}
+
+/// Maximum number of spans waiting to be sent by an
+/// [`OtlpExporter`](struct.OtlpExporter.html).
+pub const OTLP_QUEUE_SIZE: usize = 4096;
+
+const OTLP_MAX_BATCH: usize = 512;
+
+const OTLP_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+impl OtlpExporter {
+    /// Returns a new `OtlpExporter` given the collector traces endpoint
+    /// and the `service.name` reported to it.
+    pub fn new(endpoint: &str, service_name: &str) -> Self {
+        let (sender, receiver) = sync::mpsc::sync_channel::<SpanData>(OTLP_QUEUE_SIZE);
+        let endpoint = String::from(endpoint);
+        let service_name = String::from(service_name);
+        thread::spawn(move || {
+            while let Ok(span) = receiver.recv() {
+                let mut batch = vec![span];
+                while batch.len() < OTLP_MAX_BATCH {
+                    match receiver.try_recv() {
+                        Ok(span) => batch.push(span),
+                        Err(_) => break,
+                    }
+                }
+                let body = otlp_body(&service_name, &batch).to_string();
+                let _ = ureq::post(&endpoint)
+                    .timeout(OTLP_TIMEOUT)
+                    .set("Content-Type", "application/json")
+                    .send_string(&body);
+            }
+        });
+        Self {
+            sender: sync::Mutex::new(sender),
+        }
+    }
+}
+
+impl Exporter for OtlpExporter {
+    fn export(&self, span: SpanData) {
+        if let Ok(sender) = self.sender.lock() {
+            // Drops the span if the queue is full.
+            let _ = sender.try_send(span);
+        }
+    }
+}
+
+fn otlp_body(service_name: &str, spans: &[SpanData]) -> serde_json::Value {
+    let spans: Vec<serde_json::Value> = spans
+        .iter()
+        .map(|span| {
+            let attributes: Vec<serde_json::Value> = span
+                .attributes
+                .iter()
+                .map(|(k, v)| json!({"key": k, "value": {"stringValue": v}}))
+                .collect();
+            json!({
+                "traceId": span.trace_id,
+                "spanId": span.span_id,
+                "parentSpanId": span.parent_span_id.clone().unwrap_or_default(),
+                "name": span.name,
+                "kind": span.kind.otlp(),
+                "startTimeUnixNano": span.start_time_unix_nano.to_string(),
+                "endTimeUnixNano": span.end_time_unix_nano.to_string(),
+                "attributes": attributes,
+            })
+        })
+        .collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    {"key": "service.name", "value": {"stringValue": service_name}}
+                ]
+            },
+            "scopeSpans": [{
+                "scope": {"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")},
+                "spans": spans,
+            }]
+        }]
+    })
+}
+
+/// `Tracer` creates [`Span`s](struct.Span.html) and hands them to an
+/// [`Exporter`](trait.Exporter.html) once they finish.
+#[derive(Clone)]
+pub struct Tracer {
+    exporter: Option<sync::Arc<dyn Exporter>>,
+}
+
+impl Tracer {
+    /// Returns a new `Tracer` exporting spans with `exporter`.
+    pub fn new<E: Exporter + 'static>(exporter: E) -> Self {
+        Self {
+            exporter: Some(sync::Arc::new(exporter)),
+        }
+    }
+
+    /// Returns a `Tracer` that propagates context but never exports spans.
+    pub fn disabled() -> Self {
+        Self { exporter: None }
+    }
+
+    /// Starts a new server span for a call received by the process, with
+    /// `parent` the context propagated by the caller. If `parent` is
+    /// `None`, a new trace is started.
+    pub fn start(&self, name: &str, parent: Option<SpanContext>) -> Span {
+        self.start_kind(name, parent, SpanKind::Server)
+    }
+
+    fn start_kind(&self, name: &str, parent: Option<SpanContext>, kind: SpanKind) -> Span {
+        let (context, parent_span_id) = match parent {
+            Some(parent) => (parent.child(), Some(parent.span_id())),
+            None => (SpanContext::generate(), None),
+        };
+        Span {
+            tracer: self.clone(),
+            name: String::from(name),
+            kind,
+            context,
+            parent_span_id,
+            start: time::SystemTime::now(),
+            attributes: collections::BTreeMap::new(),
+        }
+    }
+}
+
+/// `Span` represents a single operation inside a trace. The span is
+/// finished and exported when dropped.
+pub struct Span {
+    tracer: Tracer,
+    name: String,
+    kind: SpanKind,
+    context: SpanContext,
+    parent_span_id: Option<String>,
+    start: time::SystemTime,
+    attributes: collections::BTreeMap<String, String>,
+}
+
+impl Span {
+    /// Starts an internal child span of this one, sharing its trace ID.
+    pub fn child(&self, name: &str) -> Span {
+        self.tracer
+            .start_kind(name, Some(self.context), SpanKind::Internal)
+    }
+
+    /// Returns the [`SpanContext`](struct.SpanContext.html) of this span.
+    pub fn context(&self) -> SpanContext {
+        self.context
+    }
+
+    /// Adds a key/value attribute to the span, replacing any previous
+    /// value for the same key.
+    pub fn set_attribute<V: ToString>(&mut self, key: &str, value: V) {
+        self.attributes.insert(String::from(key), value.to_string());
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let exporter = match &self.tracer.exporter {
+            Some(exporter) if self.context.is_sampled() => exporter,
+            _ => return,
+        };
+        exporter.export(SpanData {
+            name: self.name.clone(),
+            kind: self.kind,
+            trace_id: self.context.trace_id(),
+            span_id: self.context.span_id(),
+            parent_span_id: self.parent_span_id.take(),
+            start_time_unix_nano: unix_nanos(self.start),
+            end_time_unix_nano: unix_nanos(time::SystemTime::now()),
+            attributes: std::mem::take(&mut self.attributes),
+        });
+    }
+}
+
+fn random_span_id() -> [u8; 8] {
+    let mut rng = rand::thread_rng();
+    let mut span_id = [0u8; 8];
+    while span_id == [0u8; 8] {
+        rng.fill(&mut span_id);
+    }
+    span_id
+}
+
+fn unix_nanos(t: time::SystemTime) -> u64 {
+    t.duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str, len: usize) -> Option<Vec<u8>> {
+    if s.len() != len * 2
+        || !s
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    {
+        return None;
+    }
+    (0..len)
+        .map(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    struct CollectExporter {
+        spans: sync::Arc<sync::Mutex<Vec<SpanData>>>,
+    }
+
+    impl Exporter for CollectExporter {
+        fn export(&self, span: SpanData) {
+            self.spans.lock().unwrap().push(span);
+        }
+    }
+
+    #[test]
+    fn test_traceparent_roundtrip() {
+        let ctx = SpanContext::from_traceparent(TRACEPARENT).expect("Could not parse traceparent");
+        assert_eq!(ctx.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(ctx.span_id(), "00f067aa0ba902b7");
+        assert!(ctx.is_sampled());
+        assert_eq!(ctx.to_traceparent(), TRACEPARENT);
+    }
+
+    #[test]
+    fn test_traceparent_invalid() {
+        for value in &[
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e47-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+        ] {
+            assert_eq!(SpanContext::from_traceparent(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_span_export() {
+        let spans = sync::Arc::new(sync::Mutex::new(vec![]));
+        let tracer = Tracer::new(CollectExporter {
+            spans: spans.clone(),
+        });
+        let parent = SpanContext::from_traceparent(TRACEPARENT);
+
+        {
+            let mut span = tracer.start("parent", parent);
+            span.set_attribute("store", "BERLIN_DE");
+            let _child = span.child("child");
+        }
+
+        let spans = spans.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].name, "child");
+        assert_eq!(spans[1].name, "parent");
+        assert_eq!(spans[0].kind, SpanKind::Internal);
+        assert_eq!(spans[1].kind, SpanKind::Server);
+        assert_eq!(spans[0].trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(spans[1].trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(
+            spans[1].parent_span_id,
+            Some(String::from("00f067aa0ba902b7"))
+        );
+        assert_eq!(spans[0].parent_span_id, Some(spans[1].span_id.clone()));
+        assert_eq!(
+            spans[1].attributes.get("store"),
+            Some(&String::from("BERLIN_DE"))
+        );
+    }
+
+    #[test]
+    fn test_span_not_sampled() {
+        let spans = sync::Arc::new(sync::Mutex::new(vec![]));
+        let tracer = Tracer::new(CollectExporter {
+            spans: spans.clone(),
+        });
+        let parent = SpanContext::from_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00",
+        );
+
+        drop(tracer.start("parent", parent));
+
+        assert_eq!(spans.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_otlp_body_span_kinds() {
+        let spans = sync::Arc::new(sync::Mutex::new(vec![]));
+        let tracer = Tracer::new(CollectExporter {
+            spans: spans.clone(),
+        });
+        drop(tracer.start("parent", None).child("child"));
+
+        let body = otlp_body("stock", &spans.lock().unwrap());
+        let kinds: Vec<&serde_json::Value> = body["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|span| &span["kind"])
+            .collect();
+        // SPAN_KIND_INTERNAL for the child, SPAN_KIND_SERVER for the parent
+        assert_eq!(kinds, vec![&json!(1), &json!(2)]);
+    }
+}
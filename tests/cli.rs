@@ -1,3 +1,4 @@
+use predicates::prelude::*;
 use predicates::str::contains;
 use std::process::Command;
 use std::sync::mpsc;
@@ -5,6 +6,117 @@ use std::time;
 
 use assert_cmd::prelude::*;
 
+/// `ServerGuard` is a `server` process listening on a free local port. It's
+/// killed when dropped.
+struct ServerGuard {
+    process: std::process::Child,
+    port: u16,
+}
+
+impl ServerGuard {
+    /// Starts `server` listening on a free port, besides any address it
+    /// sets, and waits until the port accepts connections.
+    fn start(server: Command) -> Self {
+        Self::start_on(server, free_port())
+    }
+
+    /// Starts `server` listening on `port`, like
+    /// [`start`](#method.start) does on a free one.
+    fn start_on(mut server: Command, port: u16) -> Self {
+        let process = server
+            .args(&["--listen", &format!("127.0.0.1:{}", port)])
+            .spawn()
+            .unwrap();
+        let mut guard = Self { process, port };
+
+        let deadline = time::Instant::now() + time::Duration::from_secs(10);
+        while std::net::TcpStream::connect(("127.0.0.1", port)).is_err() {
+            if let Some(status) = guard.process.try_wait().unwrap() {
+                panic!("server exited with {} before accepting connections", status);
+            }
+            assert!(
+                time::Instant::now() < deadline,
+                "server did not accept connections on port {}",
+                port
+            );
+            std::thread::sleep(time::Duration::from_millis(50));
+        }
+        guard
+    }
+
+    /// Returns the port the server listens on.
+    fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Returns the `--target` of the server.
+    fn target(&self) -> String {
+        format!("127.0.0.1:{}", self.port)
+    }
+
+    /// Returns a `client` command connecting to the server.
+    fn client(&self) -> Command {
+        let mut client = Command::cargo_bin("client").unwrap();
+        client.args(&["--target", &self.target()]);
+        client
+    }
+
+    /// Sends SIGTERM to the server.
+    fn terminate(&self) {
+        Command::new("kill")
+            .args(&["-TERM", &self.process.id().to_string()])
+            .assert()
+            .success();
+    }
+
+    /// Waits for the server to exit and returns its exit code.
+    fn wait(&mut self) -> Option<i32> {
+        self.process.wait().unwrap().code()
+    }
+}
+
+impl Drop for ServerGuard {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Returns a local port nothing listens on.
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap()
+        .port()
+}
+
+/// Returns the first line of the file on `path` containing `pattern`,
+/// waiting for the file to get it for up to 10 seconds.
+fn wait_for_line(path: &std::path::Path, pattern: &str) -> String {
+    let deadline = time::Instant::now() + time::Duration::from_secs(10);
+    loop {
+        let content = std::fs::read_to_string(path).unwrap_or_default();
+        if let Some(line) = content.lines().find(|l| l.contains(pattern)) {
+            return String::from(line);
+        }
+        assert!(
+            time::Instant::now() < deadline,
+            "{} has no line with {:?}:\n{}",
+            path.display(),
+            pattern,
+            content
+        );
+        std::thread::sleep(time::Duration::from_millis(50));
+    }
+}
+
+/// Starts `server` with `args`, see [`ServerGuard::start`].
+fn start_server(args: &[&str]) -> ServerGuard {
+    let mut server = Command::cargo_bin("server").unwrap();
+    server.args(args);
+    ServerGuard::start(server)
+}
+
 #[test]
 fn client_cli_connect_server() {
     let (sender, receiver) = mpsc::sync_channel(0);
@@ -52,6 +164,63 @@ fn client_cli_connect_server() {
         .success()
         .stdout(contains("UNAVAILABLE"));
 
+    // `client` with --output json prints the items with no title
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["list", "--output", "json"])
+        .assert()
+        .success()
+        .stdout(contains("\"name\": \"Laptop air\""))
+        .stdout(contains("Products available").not());
+
+    // `client` with --output csv --no-headers prints only the rows
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["list", "-o", "csv", "--no-headers"])
+        .assert()
+        .success()
+        .stdout(contains("Laptop air,AbCorp,computers/laptops,AVAILABLE,8"))
+        .stdout(contains("name,brand").not());
+
+    // `client` with --columns selects and orders the columns
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["list", "-o", "csv", "--columns", "quantity,name"])
+        .assert()
+        .success()
+        .stdout(contains("quantity,name\n8,Laptop air\n"));
+
+    // `client` with --template renders every item with it
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["list", "--template", "{{name}}\\t{{quantity}}"])
+        .assert()
+        .success()
+        .stdout(contains("Laptop air\t8\n"))
+        .stdout(contains("Products available").not());
+
+    // `client` rejects unknown columns
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["list", "--columns", "name,price"])
+        .assert()
+        .failure();
+
+    // `client` rejects unknown output formats
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["list", "--output", "xml"])
+        .assert()
+        .failure();
+
+    // `client` prints the stores of the server for shell completion
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["complete-stores"])
+        .assert()
+        .success()
+        .stdout("BERLIN_DE\nVENEZA_IT\n");
+
     // kill server
     sender.send(()).unwrap();
     handle.join().unwrap();
@@ -59,7 +228,10 @@ fn client_cli_connect_server() {
     // Start server again with different port
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("server").unwrap();
-    let mut server_process = server.args(&["--addr", "127.0.0.1:8080"]).spawn().unwrap();
+    let mut server_process = server
+        .args(&["--listen", "127.0.0.1:8080"])
+        .spawn()
+        .unwrap();
     let handle = std::thread::spawn(move || {
         let _ = receiver.recv();
         server_process.kill().expect("server died before killed");
@@ -67,17 +239,18 @@ fn client_cli_connect_server() {
     // Sleep a bit so server can start
     std::thread::sleep(time::Duration::from_secs(1));
 
-    // `client` default port is not 8080
+    // `client` default port is not 8080, it exits with 3 as the server is
+    // unavailable
     Command::cargo_bin("client")
         .unwrap()
-        .args(&["list"])
+        .args(&["list", "--retries", "1", "--connect-timeout", "1"])
         .assert()
-        .failure();
+        .code(3);
 
     // `client` successfuly changes port
     Command::cargo_bin("client")
         .unwrap()
-        .args(&["list", "--port", "8080"])
+        .args(&["list", "--target", "127.0.0.1:8080"])
         .assert()
         .success();
 
@@ -85,3 +258,775 @@ fn client_cli_connect_server() {
     sender.send(()).unwrap();
     handle.join().unwrap();
 }
+
+#[test]
+fn cli_completions() {
+    // `client` completes --store with the stores of the server
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["completions", "bash"])
+        .assert()
+        .success()
+        .stdout(contains("complete -F _client_stores"))
+        .stdout(contains("client complete-stores"));
+
+    // `server` completions are static
+    Command::cargo_bin("server")
+        .unwrap()
+        .args(&["completions", "zsh"])
+        .assert()
+        .success()
+        .stdout(contains("#compdef server"));
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["completions", "tcsh"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn client_cli_connect_unix_socket() {
+    let socket = std::env::temp_dir().join(format!("stock-{}.sock", std::process::id()));
+    let unix_target = format!("unix:{}", socket.display());
+
+    let server = start_server(&["--listen", &unix_target]);
+
+    // `client` reaches the server through both addresses
+    for target in &[
+        unix_target.clone(),
+        server.target(),
+        format!("dns:///localhost:{}", server.port()),
+    ] {
+        Command::cargo_bin("client")
+            .unwrap()
+            .args(&["list", "--target", target.as_str()])
+            .assert()
+            .success()
+            .stdout(contains("Products available on BERLIN_DE"));
+    }
+
+    // `client` rejects targets it does not understand
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["list", "--target", &format!("http://{}", server.target())])
+        .assert()
+        .failure();
+
+    // kill server
+    drop(server);
+    let _ = std::fs::remove_file(&socket);
+}
+
+#[test]
+fn client_cli_retries_until_server_starts() {
+    let port = free_port();
+    let client = Command::cargo_bin("client")
+        .unwrap()
+        .args(&["--target", &format!("127.0.0.1:{}", port)])
+        .args(&["--retries", "20", "list"])
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // The first attempts are refused, the call succeeds once the server is
+    // up without waiting for its connect timeout between reconnections
+    std::thread::sleep(time::Duration::from_millis(500));
+    let _server = ServerGuard::start_on(Command::cargo_bin("server").unwrap(), port);
+    let output = client.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Laptop air"));
+}
+
+#[test]
+fn client_cli_contexts() {
+    let server = start_server(&[]);
+
+    let config = std::env::temp_dir().join(format!("stock-client-{}.toml", std::process::id()));
+    std::fs::write(
+        &config,
+        format!(
+            r#"
+current_context = "down"
+
+[contexts.down]
+target = "127.0.0.1:8081"
+
+[contexts.local]
+target = "{}"
+store = "VENEZA_IT"
+output = "csv"
+"#,
+            server.target()
+        ),
+    )
+    .unwrap();
+    let config = config.to_str().unwrap();
+
+    // `client` lists the contexts, marking the current one
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["--config", config, "context", "list"])
+        .assert()
+        .success()
+        .stdout(contains("* down\n  local\n"));
+
+    // `client` connects to the target of the current context
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["--config", config, "list", "--retries", "0"])
+        .args(&["--connect-timeout", "1"])
+        .assert()
+        .code(3);
+
+    // `client` switches contexts
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["--config", config, "context", "use", "local"])
+        .assert()
+        .success();
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["--config", config, "context", "show"])
+        .assert()
+        .success()
+        .stdout(contains(format!("target = \"{}\"", server.target())));
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["--config", config, "list"])
+        .assert()
+        .success()
+        .stdout(contains("name,brand,category,status,quantity"));
+
+    // Environment variables override the context, and flags override both
+    Command::cargo_bin("client")
+        .unwrap()
+        .env("STOCK_OUTPUT", "json")
+        .args(&["--config", config, "list"])
+        .assert()
+        .success()
+        .stdout(contains("\"name\""));
+    Command::cargo_bin("client")
+        .unwrap()
+        .env("STOCK_OUTPUT", "json")
+        .args(&["--config", config, "list", "-o", "table"])
+        .assert()
+        .success()
+        .stdout(contains("Products available on VENEZA_IT"));
+
+    // `client` rejects unknown contexts
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["--config", config, "context", "use", "staging"])
+        .assert()
+        .failure();
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["--config", config, "--context", "staging", "list"])
+        .assert()
+        .failure();
+
+    std::fs::remove_file(config).unwrap();
+}
+
+#[test]
+fn client_cli_import_export() {
+    let file = std::env::temp_dir().join(format!("stock-berlin-{}.csv", std::process::id()));
+
+    let server = start_server(&[]);
+
+    // `client` exports unavailable items too
+    let output = server.client().args(&["export"]).output().unwrap();
+    assert!(output.status.success());
+    let export = String::from_utf8(output.stdout).unwrap();
+    assert!(export.starts_with("name,brand,category,status,quantity\n"));
+    assert!(export.contains("Model 3,Tesla,cars,UNAVAILABLE,0\n"));
+
+    // The export of a store can be imported into another one
+    std::fs::write(&file, &export).unwrap();
+    server
+        .client()
+        .args(&["--store", "VENEZA_IT", "import"])
+        .arg(&file)
+        .assert()
+        .success()
+        .stdout(contains(
+            "Imported 3 rows into VENEZA_IT: 2 created, 1 updated, 0 failed",
+        ));
+    server
+        .client()
+        .args(&["--store", "VENEZA_IT"])
+        .args(&["list", "-o", "csv"])
+        .assert()
+        .success()
+        .stdout(contains("Laptop air,AbCorp,computers/laptops,AVAILABLE,8"));
+
+    // Rows failing are reported, the other ones are imported
+    std::fs::write(
+        &file,
+        r#"[{"name": "Model 3", "quantity": -1}, {"name": "Model S", "quantity": 2}]"#,
+    )
+    .unwrap();
+    server
+        .client()
+        .args(&["import", "--format", "json"])
+        .arg(&file)
+        .assert()
+        .code(2)
+        .stdout(contains("row 1: failed"))
+        .stdout(contains(
+            "2 rows into BERLIN_DE: 1 created, 0 updated, 1 failed",
+        ));
+
+    // With --all-or-nothing, a row rejected by the server fails the import
+    std::fs::write(
+        &file,
+        r#"[{"name": "Model X", "quantity": 2}, {"name": " ", "quantity": 1}]"#,
+    )
+    .unwrap();
+    server
+        .client()
+        .args(&["import", "--format", "json"])
+        .arg("--all-or-nothing")
+        .arg(&file)
+        .assert()
+        .code(2)
+        .stdout(contains("row 2: failed: name must not be empty"))
+        .stdout(contains("Imported no rows into BERLIN_DE: 1 failed"));
+    server
+        .client()
+        .args(&["list"])
+        .assert()
+        .success()
+        .stdout(contains("Model S"))
+        .stdout(contains("Model X").not());
+
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn client_cli_diff_apply() {
+    let file = std::env::temp_dir().join(format!("stock-desired-{}.yaml", std::process::id()));
+    std::fs::write(
+        &file,
+        r#"
+- name: Laptop air
+  brand: AbCorp
+  category: computers/laptops
+  quantity: 5
+- name: Model 3
+  brand: Tesla
+  category: cars
+  quantity: 0
+- name: Model S
+  brand: Tesla
+  category: cars
+  quantity: 2
+"#,
+    )
+    .unwrap();
+
+    let server = start_server(&[]);
+
+    let client = || server.client();
+
+    // `client diff` prints the changes against the server
+    client()
+        .arg("diff")
+        .arg(&file)
+        .arg("--exit-code")
+        .assert()
+        .code(1)
+        .stdout(contains(
+            "- Laptop UltraPower (GNUFoundation, computers/desktop-computers): 4\n\
+             ~ Laptop air (AbCorp, computers/laptops): 8 -> 5 (-3)\n\
+             + Model S (Tesla, cars): 2\n",
+        ))
+        .stdout(contains("1 to remove"));
+
+    // `client apply` keeps products missing from the file unless --prune is set
+    client()
+        .arg("apply")
+        .arg(&file)
+        .assert()
+        .success()
+        .stdout(contains("Keeping 1 products"))
+        .stdout(contains(
+            "Applied to BERLIN_DE: 1 added, 1 updated, 0 removed",
+        ));
+
+    // Removals are only shown without --force, and --dry-run applies nothing
+    client()
+        .args(&["apply", "--prune"])
+        .arg(&file)
+        .assert()
+        .success()
+        .stdout(contains("- Laptop UltraPower"))
+        .stdout(contains("Skipped 1 removals"));
+    client()
+        .args(&["apply", "--prune", "--force", "--dry-run"])
+        .arg(&file)
+        .assert()
+        .success()
+        .stdout(contains("Dry run"));
+    client()
+        .args(&["list"])
+        .assert()
+        .success()
+        .stdout(contains("Laptop UltraPower"));
+
+    client()
+        .args(&["apply", "--prune", "--force"])
+        .arg(&file)
+        .assert()
+        .success()
+        .stdout(contains(
+            "Applied to BERLIN_DE: 0 added, 0 updated, 1 removed",
+        ));
+    client()
+        .args(&["diff", "--exit-code"])
+        .arg(&file)
+        .assert()
+        .success()
+        .stdout(contains("BERLIN_DE matches"));
+
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn client_cli_history() {
+    let file = std::env::temp_dir().join(format!("stock-delivery-{}.csv", std::process::id()));
+    std::fs::write(
+        &file,
+        "name,brand,category,quantity\nLaptop air,AbCorp,computers/laptops,10\n",
+    )
+    .unwrap();
+
+    let mut server = Command::cargo_bin("server").unwrap();
+    server.env("STOCK_AUTH_ACTORS", "alice:s3cr3t");
+    let server = ServerGuard::start(server);
+
+    let client = || server.client();
+
+    // Seeded products have no history
+    client()
+        .args(&["history", "--all-stores", "-o", "csv", "--no-headers"])
+        .assert()
+        .success()
+        .stdout("");
+
+    client()
+        .env("STOCK_TOKEN", "s3cr3t")
+        .args(&["import", "--reason", "delivery"])
+        .arg(&file)
+        .assert()
+        .success();
+    client()
+        .args(&["import", "--store", "VENEZA_IT"])
+        .arg(&file)
+        .assert()
+        .success();
+
+    client()
+        .args(&["history", "-o", "csv", "--since", "1h"])
+        .assert()
+        .success()
+        .stdout(contains(
+            ",BERLIN_DE,Laptop air,AbCorp,computers/laptops,8,10,alice,delivery\n",
+        ))
+        .stdout(contains("VENEZA_IT").not());
+    client()
+        .args(&["history", "--all-stores", "--limit", "1", "-o", "csv"])
+        .assert()
+        .success()
+        .stdout(contains(
+            ",VENEZA_IT,Laptop air,AbCorp,computers/laptops,,10,anonymous,\n",
+        ))
+        .stdout(contains("alice").not());
+    client()
+        .args(&["history", "--until", "2000-01-01T00:00:00Z", "-o", "csv"])
+        .assert()
+        .success()
+        .stdout("time,store,name,brand,category,previous,quantity,actor,reason\n");
+
+    // The seeded quantity is reconstructed from the history
+    client()
+        .args(&["list", "--as-of", "1h", "-o", "csv"])
+        .assert()
+        .success()
+        .stdout(contains(
+            "Laptop air,AbCorp,computers/laptops,AVAILABLE,8\n",
+        ));
+    client()
+        .args(&["list", "-o", "csv"])
+        .assert()
+        .success()
+        .stdout(contains(
+            "Laptop air,AbCorp,computers/laptops,AVAILABLE,10\n",
+        ));
+
+    client()
+        .args(&["history", "--since", "yesterday"])
+        .assert()
+        .failure()
+        .stderr(contains("Invalid time"));
+
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn client_cli_set_with_version() {
+    let server = start_server(&[]);
+
+    let client = || server.client();
+    let laptop = ["--brand", "AbCorp", "--category", "computers/laptops"];
+
+    client()
+        .args(&["list", "--columns", "name,version", "-o", "csv"])
+        .assert()
+        .success()
+        .stdout(contains("Laptop air,1\n"));
+    client()
+        .args(&["set", "Laptop air", "6", "--if-version", "1"])
+        .args(&laptop)
+        .assert()
+        .success()
+        .stdout("Set 'Laptop air' on BERLIN_DE from 8 to 6 (version 2)\n");
+
+    // A change based on the previous version is rejected
+    client()
+        .args(&["set", "Laptop air", "7", "--if-version", "1"])
+        .args(&laptop)
+        .assert()
+        .code(6)
+        .stderr(contains("the product is at version 2 with quantity 6"));
+    client()
+        .args(&["set", "Laptop new", "1", "--if-version", "1"])
+        .args(&laptop)
+        .assert()
+        .code(6)
+        .stderr(contains("the product is not in the store"));
+    client()
+        .args(&["set", "Laptop air", "2147483648"])
+        .args(&laptop)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid quantity \"2147483648\""));
+
+    client()
+        .args(&["set", "Laptop new", "1"])
+        .args(&laptop)
+        .assert()
+        .success()
+        .stdout("Added 'Laptop new' to BERLIN_DE with quantity 1 (version 1)\n");
+}
+
+#[test]
+fn client_cli_transfer() {
+    let server = start_server(&[]);
+
+    let client = || server.client();
+    let transfer = |qty: &str| {
+        let mut client = client();
+        client.args(&[
+            "transfer",
+            "--from",
+            "BERLIN_DE",
+            "--to",
+            "VENEZA_IT",
+            "--product",
+            "Laptop air",
+            "--brand",
+            "AbCorp",
+            "--category",
+            "computers/laptops",
+            "--qty",
+            qty,
+        ]);
+        client
+    };
+
+    transfer("3")
+        .assert()
+        .success()
+        .stdout("Moved 3 of 'Laptop air' from BERLIN_DE (5 left) to VENEZA_IT (3 now)\n");
+    transfer("6")
+        .assert()
+        .code(6)
+        .stderr(contains("the source store only has 5 units of the product"));
+    transfer("4294967296")
+        .assert()
+        .failure()
+        .stderr(contains("Invalid quantity \"4294967296\""));
+
+    let output = transfer("2").arg("--in-transit").output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with(
+        "Moved 2 of 'Laptop air' from BERLIN_DE (3 left), in transit to VENEZA_IT as "
+    ));
+    let id = stdout.trim_end().rsplit(' ').next().unwrap();
+    client()
+        .args(&["--store", "VENEZA_IT", "list", "-o", "csv", "--no-headers"])
+        .assert()
+        .success()
+        .stdout(contains(
+            "Laptop air,AbCorp,computers/laptops,AVAILABLE,3\n",
+        ));
+    client()
+        .args(&["receive", id])
+        .assert()
+        .success()
+        .stdout("Received 2 of 'Laptop air' from BERLIN_DE to VENEZA_IT (5 now)\n");
+    client()
+        .args(&["receive", id])
+        .assert()
+        .code(2)
+        .stderr(contains("is in transit"));
+}
+
+#[test]
+fn client_cli_propagates_trace_context() {
+    let trace_file = std::env::temp_dir().join(format!("stock-traces-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&trace_file);
+
+    let server = start_server(&["--trace-file", trace_file.to_str().unwrap()]);
+
+    // `client` with --verbose prints the trace ID
+    let output = server
+        .client()
+        .args(&["list", "--verbose"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let trace_id = stderr
+        .lines()
+        .find(|l| l.starts_with("trace id: "))
+        .expect("client did not print the trace id")
+        .trim_start_matches("trace id: ")
+        .to_string();
+    assert_eq!(trace_id.len(), 32);
+    assert!(stderr.lines().any(|l| l.starts_with("request id: ")));
+
+    // server spans share the client trace ID, the call one is exported last
+    wait_for_line(&trace_file, "generated.Stock/ListItems");
+    drop(server);
+    let spans = std::fs::read_to_string(&trace_file).unwrap();
+    for name in &[
+        "generated.Stock/ListItems",
+        "Handler::handle",
+        "StockImpl::get",
+    ] {
+        assert!(
+            spans
+                .lines()
+                .any(|l| l.contains(name) && l.contains(&trace_id)),
+            "missing span {}",
+            name
+        );
+    }
+    let _ = std::fs::remove_file(&trace_file);
+}
+
+#[test]
+fn server_cli_logs_json_to_file() {
+    let log_file = std::env::temp_dir().join(format!("stock-logs-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&log_file);
+
+    let mut server = Command::cargo_bin("server").unwrap();
+    server
+        .args(&["--log-format", "json"])
+        .env("STOCK_LOG_FILE", &log_file);
+    let server = ServerGuard::start(server);
+
+    let line = wait_for_line(
+        &log_file,
+        &format!("\"msg\":\"listening on {}\"", server.target()),
+    );
+    assert!(line.starts_with('{'));
+    drop(server);
+    let _ = std::fs::remove_file(&log_file);
+
+    // `server` rejects unknown log formats
+    Command::cargo_bin("server")
+        .unwrap()
+        .args(&["--log-format", "yaml"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn server_cli_alerts_to_file() {
+    let alerts_file =
+        std::env::temp_dir().join(format!("stock-alerts-{}.json", std::process::id()));
+    let config_file =
+        std::env::temp_dir().join(format!("stock-alerts-{}.toml", std::process::id()));
+    let _ = std::fs::remove_file(&alerts_file);
+    std::fs::write(
+        &config_file,
+        "[[alerts.thresholds]]\nstore = \"BERLIN_DE\"\nname = \"Laptop UltraPower\"\n\
+         brand = \"GNUFoundation\"\ncategory = \"computers/desktop-computers\"\nthreshold = 3\n",
+    )
+    .unwrap();
+
+    let mut server = Command::cargo_bin("server").unwrap();
+    server
+        .arg("--config")
+        .arg(&config_file)
+        .args(&["--alerts-interval", "1"])
+        .env("STOCK_ALERTS_FILE", &alerts_file);
+    let server = ServerGuard::start(server);
+
+    // Laptop air has a threshold of 5 on BERLIN_DE in the fake data, and
+    // Laptop UltraPower one of 3 from the configuration file
+    server
+        .client()
+        .args(&["set", "Laptop air", "2"])
+        .args(&["--brand", "AbCorp", "--category", "computers/laptops"])
+        .assert()
+        .success();
+    server
+        .client()
+        .args(&["set", "Laptop UltraPower", "1"])
+        .args(&["--brand", "GNUFoundation"])
+        .args(&["--category", "computers/desktop-computers"])
+        .assert()
+        .success();
+
+    for (name, fields) in &[
+        ("Laptop air", ["\"quantity\":2", "\"threshold\":5"]),
+        ("Laptop UltraPower", ["\"quantity\":1", "\"threshold\":3"]),
+    ] {
+        let line = wait_for_line(&alerts_file, &format!("\"name\":\"{}\"", name));
+        for field in ["\"kind\":\"low\"", "\"store\":\"BERLIN_DE\""]
+            .iter()
+            .chain(fields)
+        {
+            assert!(line.contains(field), "missing {} in {}", field, line);
+        }
+    }
+    drop(server);
+
+    // Each product is alerted once
+    let alerts = std::fs::read_to_string(&alerts_file).unwrap();
+    assert_eq!(alerts.lines().count(), 2, "{}", alerts);
+    let _ = std::fs::remove_file(&alerts_file);
+    let _ = std::fs::remove_file(&config_file);
+
+    // `server` rejects webhook URLs without a scheme
+    Command::cargo_bin("server")
+        .unwrap()
+        .args(&[
+            "--alerts-webhook-url",
+            "hooks.example.com",
+            "config",
+            "check",
+        ])
+        .assert()
+        .failure()
+        .stderr(contains("alerts.webhook_url"));
+}
+
+#[test]
+fn server_cli_config() {
+    let config_file =
+        std::env::temp_dir().join(format!("stock-config-{}.toml", std::process::id()));
+    std::fs::write(
+        &config_file,
+        "listen = [\"0.0.0.0:7070\"]\n\n[log]\nlevel = \"warn\"\nformat = \"json\"\n",
+    )
+    .unwrap();
+
+    // `server config check` validates the configuration file
+    Command::cargo_bin("server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config_file)
+        .args(&["config", "check"])
+        .assert()
+        .success()
+        .stdout(contains("Configuration OK"));
+
+    // environment variables override the file and flags override both
+    Command::cargo_bin("server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config_file)
+        .args(&["--listen", "127.0.0.1:7071", "config", "print"])
+        .env("STOCK_LISTEN", "127.0.0.1:7072")
+        .env("STOCK_LOG_LEVEL", "debug")
+        .env("STOCK_AUTH_ACTORS", "alice:s3cr3t")
+        .assert()
+        .success()
+        .stdout(contains("listen = [\"127.0.0.1:7071\"]"))
+        .stdout(contains("level = \"debug\""))
+        .stdout(contains("format = \"json\""))
+        .stdout(contains("token = \"REDACTED\""))
+        .stdout(contains("s3cr3t").not());
+
+    // invalid values are reported with their key
+    std::fs::write(&config_file, "[log]\nformat = \"yaml\"\n").unwrap();
+    Command::cargo_bin("server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config_file)
+        .args(&["config", "check"])
+        .assert()
+        .failure()
+        .stderr(contains("format"));
+
+    // the webhook client has no TLS, so https URLs are rejected at startup
+    Command::cargo_bin("server")
+        .unwrap()
+        .args(&["--alerts-webhook-url", "https://hooks.example.com/stock"])
+        .args(&["config", "check"])
+        .assert()
+        .failure()
+        .stderr(contains("https is not supported"));
+
+    let _ = std::fs::remove_file(&config_file);
+}
+
+#[test]
+fn server_cli_graceful_shutdown() {
+    let mut server = start_server(&["--shutdown-timeout", "5", "--shutdown-drain-delay", "0"]);
+
+    server.terminate();
+
+    // With no in-flight calls the server exits cleanly
+    assert_eq!(server.wait(), Some(0));
+}
+
+#[test]
+fn server_cli_forced_shutdown() {
+    let mut server = start_server(&["--shutdown-drain-delay", "30"]);
+
+    let env = std::sync::Arc::new(grpcio::EnvBuilder::new().build());
+    let channel = grpcio::ChannelBuilder::new(env).connect(&server.target());
+    let health = stock::proto::HealthClient::new(channel);
+    let check = || {
+        health
+            .check_opt(
+                &stock::proto::HealthCheckRequest::new(),
+                grpcio::CallOption::default().timeout(time::Duration::from_secs(5)),
+            )
+            .unwrap()
+            .get_status()
+    };
+    assert_eq!(check(), stock::proto::ServingStatus::SERVING);
+
+    server.terminate();
+    std::thread::sleep(time::Duration::from_millis(500));
+
+    // Health checks are still answered during the drain delay
+    assert_eq!(check(), stock::proto::ServingStatus::NOT_SERVING);
+
+    // A second signal cancels the shutdown wait
+    server.terminate();
+    assert_eq!(server.wait(), Some(3));
+}